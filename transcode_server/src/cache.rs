@@ -0,0 +1,151 @@
+/*
+ * cache.rs
+ *
+ * Outputs of finished jobs, keyed by the blake3 hash of the source and the
+ * settings it was transcoded with. A source submitted again with the same
+ * settings gets the stored outputs of the earlier job instead of being
+ * downloaded and transcoded again. The outputs are only remembered in
+ * memory, so none are reused after the server restarts.
+ */
+
+use crate::source::encrypted_blob_cid;
+use crate::storage::StorageBackend;
+use crate::transcode::{Artifact, TranscodeRequest};
+use crate::video_title;
+use once_cell::sync::Lazy;
+use prost::Message;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+
+static RESULTS: Lazy<Mutex<HashMap<String, Vec<Artifact>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The key of the outputs of transcoding the source with `source_hash` as
+// `request` asks, into the storage backend named `storage`
+pub fn cache_key(source_hash: &blake3::Hash, storage: &str, request: &TranscodeRequest) -> String {
+    // The source is identified by its hash alone, and the storage by its
+    // resolved name, so neither may differ between equivalent requests. The
+    // title is in the reused metadata document, so it counts, whether given or
+    // taken from the URL. Publishing changes none of the outputs.
+    let mut settings = request.clone();
+    settings.title = video_title(request);
+    settings.url.clear();
+    settings.source_hash.clear();
+    settings.storage_backend.clear();
+    settings.registry_name.clear();
+    settings.tenant.clear();
+    settings.publish_plaintext = false;
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(source_hash.as_bytes());
+    hasher.update(&[storage.len() as u8]);
+    hasher.update(storage.as_bytes());
+    hasher.update(&settings.encode_to_vec());
+
+    hasher.finalize().to_hex().to_string()
+}
+
+// The outputs stored under `key`, as long as `storage` still holds all of them
+pub async fn lookup(key: &str, storage: &dyn StorageBackend) -> Option<Vec<Artifact>> {
+    let artifacts = RESULTS.lock().await.get(key).cloned()?;
+
+    for artifact in &artifacts {
+        let stored = match encrypted_blob_cid(&artifact.cid) {
+            Ok(blob_cid) => matches!(storage.stat(&blob_cid).await, Ok(Some(_))),
+            Err(_) => false,
+        };
+
+        if !stored {
+            println!(
+                "Cached {} output {} is no longer stored",
+                artifact.name, artifact.cid
+            );
+            RESULTS.lock().await.remove(key);
+            return None;
+        }
+    }
+
+    Some(artifacts)
+}
+
+pub async fn insert(key: &str, artifacts: Vec<Artifact>) {
+    RESULTS.lock().await.insert(key.to_string(), artifacts);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcode::RenditionOptions;
+
+    fn request() -> TranscodeRequest {
+        TranscodeRequest {
+            url: "https://example.com/video.mp4".to_string(),
+            renditions: vec![RenditionOptions::default()],
+            chunked: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cache_keys_are_stable() {
+        let hash = blake3::hash(b"source");
+
+        assert_eq!(
+            cache_key(&hash, "s5", &request()),
+            cache_key(&hash, "s5", &request())
+        );
+    }
+
+    #[test]
+    fn cache_keys_ignore_where_the_source_came_from_and_publishing() {
+        let hash = blake3::hash(b"source");
+        let key = cache_key(&hash, "s5", &request());
+
+        let equivalent = TranscodeRequest {
+            url: "s5://uJh8".to_string(),
+            source_hash: hash.to_hex().to_string(),
+            storage_backend: "s5".to_string(),
+            title: "video".to_string(),
+            registry_name: "video".to_string(),
+            tenant: "acme".to_string(),
+            publish_plaintext: true,
+            ..request()
+        };
+
+        assert_eq!(cache_key(&hash, "s5", &equivalent), key);
+    }
+
+    #[test]
+    fn cache_keys_differ_by_source_storage_and_settings() {
+        let hash = blake3::hash(b"source");
+        let key = cache_key(&hash, "s5", &request());
+
+        assert_ne!(cache_key(&blake3::hash(b"other"), "s5", &request()), key);
+        assert_ne!(cache_key(&hash, "s3", &request()), key);
+
+        let unchunked = TranscodeRequest {
+            chunked: false,
+            ..request()
+        };
+        assert_ne!(cache_key(&hash, "s5", &unchunked), key);
+    }
+
+    #[test]
+    fn cache_keys_differ_by_title() {
+        let hash = blake3::hash(b"source");
+        let key = cache_key(&hash, "s5", &request());
+
+        let titled = TranscodeRequest {
+            title: "Title".to_string(),
+            ..request()
+        };
+        assert_ne!(cache_key(&hash, "s5", &titled), key);
+
+        // Without a title, the file name in the URL is the title
+        let renamed = TranscodeRequest {
+            url: "https://example.com/other.mp4".to_string(),
+            ..request()
+        };
+        assert_ne!(cache_key(&hash, "s5", &renamed), key);
+    }
+}
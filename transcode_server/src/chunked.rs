@@ -0,0 +1,329 @@
+use crate::encode::{audio_args, video_args, ColourHandling, Rendition};
+use dotenv::var;
+use once_cell::sync::Lazy;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+// Minimum change between frames, from 0 to 1, that counts as a scene cut
+const SCENE_THRESHOLD: f64 = 0.4;
+
+// Scene cuts closer together than this are merged so chunks stay long enough
+// for the encoder's rate control to settle
+const MIN_CHUNK_SECONDS: f64 = 10.0;
+
+// CPU slots shared by every chunk encode on this server. Each chunk encode runs
+// single-threaded in one slot. Set CPU_SLOTS to override the number of cores;
+// there is always at least one slot, so chunk encodes can't wait forever.
+static CPU_SLOTS: Lazy<Arc<Semaphore>> = Lazy::new(|| {
+    let slots = var("CPU_SLOTS")
+        .ok()
+        .and_then(|slots| slots.parse().ok())
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        });
+
+    Arc::new(Semaphore::new(slots.max(1)))
+});
+
+// Splits the video of the source at scene cuts into chunks and returns their
+// paths. The chunks only depend on the source, so they are split once and
+// encoded for every rendition.
+pub async fn split_source(
+    file_path: &str,
+    work_prefix: &str,
+) -> Result<Vec<String>, anyhow::Error> {
+    let split_times = detect_scene_cuts(file_path).await?;
+    println!("{} split points: {:?}", split_times.len(), &split_times);
+
+    split_at_keyframes(file_path, work_prefix, &split_times).await
+}
+
+// Encodes the rendition by encoding the chunks of the source in parallel and
+// losslessly concatenating the results. Audio is encoded once from the source
+// while concatenating.
+pub async fn encode_rendition_chunked(
+    file_path: &str,
+    output_path: &str,
+    work_prefix: &str,
+    rendition: &Rendition,
+    colour: ColourHandling<'_>,
+    crf: Option<u32>,
+    chunks: &[String],
+) -> Result<(), anyhow::Error> {
+    let mut handles = Vec::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let encoded = encoded_chunk_path(work_prefix, index);
+        let args = [
+            vec![String::from("-i"), chunk.clone()],
+            video_args(rendition, false, colour, crf),
+            ["-threads", "1", "-an", "-y", encoded.as_str()]
+                .map(String::from)
+                .to_vec(),
+        ]
+        .concat();
+
+        let slots = Arc::clone(&CPU_SLOTS);
+        handles.push(tokio::spawn(async move {
+            let _permit = slots.acquire_owned().await?;
+            let output = Command::new("ffmpeg").args(&args).output().await?;
+
+            if !output.status.success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to encode chunk {}: {}",
+                    encoded,
+                    String::from_utf8_lossy(&output.stderr)
+                ));
+            }
+
+            Ok(())
+        }));
+    }
+
+    for handle in handles {
+        handle.await??;
+    }
+
+    concat_chunks(file_path, output_path, work_prefix, rendition, chunks.len()).await
+}
+
+// Returns the timestamps, in seconds, of scene cuts at least MIN_CHUNK_SECONDS
+// apart. Detection runs on a downscaled copy of the video to keep it cheap.
+async fn detect_scene_cuts(file_path: &str) -> Result<Vec<f64>, anyhow::Error> {
+    let output = Command::new("ffmpeg")
+        .args([
+            "-i",
+            file_path,
+            "-map",
+            "0:v:0",
+            "-an",
+            "-vf",
+            format!(
+                "scale=480:-2,select='gt(scene,{})',showinfo",
+                SCENE_THRESHOLD
+            )
+            .as_str(),
+            "-f",
+            "null",
+            "-",
+        ])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Scene detection failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(scene_cuts(&String::from_utf8_lossy(&output.stderr)))
+}
+
+// Reads the scene cut timestamps from the showinfo lines in ffmpeg's log,
+// dropping cuts less than MIN_CHUNK_SECONDS after the previous one
+fn scene_cuts(log: &str) -> Vec<f64> {
+    let mut cuts = Vec::new();
+    let mut last_cut = 0.0;
+    for line in log.lines() {
+        let pts_time = line
+            .split_once("pts_time:")
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .and_then(|time| time.parse::<f64>().ok());
+
+        if let Some(time) = pts_time {
+            if time - last_cut >= MIN_CHUNK_SECONDS {
+                cuts.push(time);
+                last_cut = time;
+            }
+        }
+    }
+
+    cuts
+}
+
+// Stream-copies the video into chunks. The segment muxer cuts at the first
+// keyframe at or after each split time, so no frames are re-encoded.
+async fn split_at_keyframes(
+    file_path: &str,
+    work_prefix: &str,
+    split_times: &[f64],
+) -> Result<Vec<String>, anyhow::Error> {
+    let segment_list = format!("{}_chunks.txt", work_prefix);
+    let mut args = vec![
+        String::from("-i"),
+        file_path.to_string(),
+        String::from("-map"),
+        String::from("0:v:0"),
+        String::from("-c"),
+        String::from("copy"),
+        String::from("-f"),
+        String::from("segment"),
+        String::from("-reset_timestamps"),
+        String::from("1"),
+        String::from("-segment_list"),
+        segment_list.clone(),
+    ];
+    if !split_times.is_empty() {
+        let times: Vec<String> = split_times.iter().map(|time| time.to_string()).collect();
+        args.extend([String::from("-segment_times"), times.join(",")]);
+    } else {
+        // A single chunk holding the whole video
+        args.extend([String::from("-segment_time"), String::from("1000000")]);
+    }
+    args.extend([
+        String::from("-y"),
+        format!("{}_chunk_%05d.mkv", work_prefix),
+    ]);
+
+    let output = Command::new("ffmpeg").args(&args).output().await?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to split source into chunks: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let names = tokio::fs::read_to_string(&segment_list).await?;
+
+    Ok(chunk_paths(&segment_list, &names))
+}
+
+// Resolves the chunk names in a segment list, which are relative to the
+// directory of the list itself
+fn chunk_paths(segment_list: &str, names: &str) -> Vec<String> {
+    let directory = Path::new(segment_list)
+        .parent()
+        .unwrap_or_else(|| Path::new("."));
+
+    names
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|name| directory.join(name.trim()).to_string_lossy().to_string())
+        .collect()
+}
+
+// Joins the `chunk_count` encoded chunks with the concat demuxer, copying the
+// AV1 stream, and adds the source's default audio track
+async fn concat_chunks(
+    file_path: &str,
+    output_path: &str,
+    work_prefix: &str,
+    rendition: &Rendition,
+    chunk_count: usize,
+) -> Result<(), anyhow::Error> {
+    let concat_list = format!("{}_concat.txt", work_prefix);
+    let entries: Vec<String> = (0..chunk_count)
+        .map(|index| {
+            let encoded = encoded_chunk_path(work_prefix, index);
+            let name = Path::new(&encoded)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or(encoded);
+            format!("file '{}'", name.replace('\'', "'\\''"))
+        })
+        .collect();
+    tokio::fs::write(&concat_list, entries.join("\n")).await?;
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args([
+        "-f",
+        "concat",
+        "-safe",
+        "0",
+        "-i",
+        concat_list.as_str(),
+        "-i",
+        file_path,
+        "-map",
+        "0:v:0",
+        "-map",
+        "1:a:0?",
+        "-c:v",
+        "copy",
+    ])
+    .args(audio_args(rendition, false))
+    .args(["-y", output_path]);
+
+    let output = cmd.output().await?;
+    println!("{:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to concatenate {} chunks: {}",
+            rendition.resolution,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
+
+// Where the rendition with `work_prefix` stores its encode of the chunk at
+// `index`, next to the concat list that refers to it by name
+fn encoded_chunk_path(work_prefix: &str, index: usize) -> String {
+    format!("{}_chunk_{:05}_av1.mkv", work_prefix, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn showinfo(pts_time: &str) -> String {
+        format!(
+            "[Parsed_showinfo_2 @ 0x5581] n:   3 pts: 123 pts_time:{} duration:1 fmt:yuv420p",
+            pts_time
+        )
+    }
+
+    #[test]
+    fn scene_cuts_are_read_from_showinfo_lines() {
+        let log = [
+            String::from("Input #0, matroska,webm, from 'source.mkv':"),
+            showinfo("12.5"),
+            String::from("frame=  120 fps=0.0 q=-0.0 size=N/A time=00:00:12.50"),
+            showinfo("30.04"),
+        ]
+        .join("\n");
+
+        assert_eq!(scene_cuts(&log), [12.5, 30.04]);
+    }
+
+    #[test]
+    fn scene_cuts_closer_than_the_minimum_chunk_are_merged() {
+        let log = ["4", "10", "15", "19.9", "20", "45", "not a time"]
+            .map(showinfo)
+            .join("\n");
+
+        assert_eq!(scene_cuts(&log), [10.0, 20.0, 45.0]);
+        assert!(scene_cuts("").is_empty());
+    }
+
+    #[test]
+    fn chunk_paths_are_relative_to_the_segment_list() {
+        let names = "job_chunk_00000.mkv\n job_chunk_00001.mkv \n\n";
+
+        assert_eq!(
+            chunk_paths("./temp/to/transcode/job_chunks.txt", names),
+            [
+                "./temp/to/transcode/job_chunk_00000.mkv",
+                "./temp/to/transcode/job_chunk_00001.mkv"
+            ]
+        );
+        assert_eq!(
+            chunk_paths("job_chunks.txt", names)[0],
+            "job_chunk_00000.mkv"
+        );
+    }
+
+    #[test]
+    fn encoded_chunks_are_numbered_next_to_the_work_prefix() {
+        assert_eq!(
+            encoded_chunk_path("./temp/to/transcode/job_1080p", 7),
+            "./temp/to/transcode/job_1080p_chunk_00007_av1.mkv"
+        );
+    }
+}
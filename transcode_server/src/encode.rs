@@ -0,0 +1,244 @@
+use crate::probe::HdrInfo;
+use crate::transcode::{HdrMode, TranscodeRequest};
+use tokio::process::Command;
+
+// Converts PQ or HLG to linear light, tone-maps to BT.709 and reduces to 8-bit
+pub const TONE_MAP_FILTER: &str = "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,\
+tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p";
+
+// An output rung produced for every job
+#[derive(Debug)]
+pub struct Rendition {
+    pub resolution: &'static str,
+    gpu_video_bitrate: &'static str,
+    gpu_audio_bitrate: &'static str,
+    gpu_scale: &'static str,
+    cpu_size: &'static str,
+}
+
+pub const RENDITIONS: [Rendition; 2] = [
+    Rendition {
+        resolution: "2160p",
+        gpu_video_bitrate: "15M",
+        gpu_audio_bitrate: "192k",
+        gpu_scale: "scale=3840:2160",
+        cpu_size: "hd1080",
+    },
+    Rendition {
+        resolution: "1080p",
+        gpu_video_bitrate: "5M",
+        gpu_audio_bitrate: "96k",
+        gpu_scale: "scale=1920:1080",
+        cpu_size: "hd720",
+    },
+];
+
+// How the colour of an HDR source is handled for one rendition
+#[derive(Debug, Clone, Copy)]
+pub enum ColourHandling<'a> {
+    Sdr,
+    Preserve(&'a HdrInfo),
+    ToneMap,
+}
+
+impl<'a> ColourHandling<'a> {
+    // Resolves the HDR mode requested for `rendition`. With `HDR_AUTO` the top
+    // rung keeps HDR and the lower rungs are tone-mapped to SDR.
+    pub fn for_rendition(
+        request: &TranscodeRequest,
+        rendition: &Rendition,
+        hdr: Option<&'a HdrInfo>,
+    ) -> Self {
+        let hdr = match hdr {
+            Some(hdr) => hdr,
+            None => return ColourHandling::Sdr,
+        };
+
+        let hdr_mode = request
+            .renditions
+            .iter()
+            .find(|options| options.resolution == rendition.resolution)
+            .map(|options| options.hdr_mode())
+            .unwrap_or(HdrMode::HdrAuto);
+
+        match hdr_mode {
+            HdrMode::HdrPreserve => ColourHandling::Preserve(hdr),
+            HdrMode::HdrToneMap => ColourHandling::ToneMap,
+            HdrMode::HdrAuto if rendition.resolution == RENDITIONS[0].resolution => {
+                ColourHandling::Preserve(hdr)
+            }
+            HdrMode::HdrAuto => ColourHandling::ToneMap,
+        }
+    }
+
+    // The source HDR metadata carried into the rendition, if any
+    pub fn hdr(&self) -> Option<&'a HdrInfo> {
+        match self {
+            ColourHandling::Preserve(hdr) => Some(hdr),
+            _ => None,
+        }
+    }
+
+    // "sdr", "hdr10" or "hlg"
+    pub fn dynamic_range(&self) -> &'static str {
+        match self {
+            ColourHandling::Preserve(hdr) => hdr.transfer.dynamic_range(),
+            _ => "sdr",
+        }
+    }
+}
+
+// The ffmpeg video encoder arguments for a rendition. `crf` overrides the
+// rendition's rate control with a constant quality level, which av1_nvenc
+// takes as `-cq`. HDR renditions are encoded on the CPU with SVT-AV1 rather
+// than libaom-av1, which can't be given the static HDR metadata.
+pub fn video_args(
+    rendition: &Rendition,
+    is_gpu: bool,
+    colour: ColourHandling,
+    crf: Option<u32>,
+) -> Vec<String> {
+    let crf = crf.map(|crf| crf.to_string());
+    let mut args: Vec<String> = match (is_gpu, &crf) {
+        (true, None) => vec!["-c:v", "av1_nvenc", "-b:v", rendition.gpu_video_bitrate],
+        (true, Some(crf)) => vec![
+            "-c:v",
+            "av1_nvenc",
+            "-rc",
+            "vbr",
+            "-cq",
+            crf.as_str(),
+            "-b:v",
+            "0",
+        ],
+        (false, crf) if colour.hdr().is_some() => vec![
+            "-c:v",
+            "libsvtav1",
+            "-preset",
+            "8",
+            "-crf",
+            crf.as_deref().unwrap_or("30"),
+        ],
+        (false, crf) => vec![
+            "-c:v",
+            "libaom-av1", // use libaom-av1 encoder for AV1
+            "-cpu-used",
+            "4", // set encoding speed to 4 (range 0-8, lower is slower)
+            "-b:v",
+            "0", // use constant quality mode
+            "-crf",
+            crf.as_deref().unwrap_or("30"), // set quality level (range 0-63, lower is better)
+        ],
+    }
+    .into_iter()
+    .map(String::from)
+    .collect();
+
+    let mut filters = Vec::new();
+    match colour {
+        ColourHandling::Sdr => (),
+        ColourHandling::Preserve(hdr) => {
+            // AV1 main profile carries HDR at 10-bit with the source's signalling
+            let pix_fmt = if is_gpu { "p010le" } else { "yuv420p10le" };
+            let primaries = if hdr.bt2020 { "bt2020" } else { "bt709" };
+            let colorspace = if hdr.bt2020 { "bt2020nc" } else { "bt709" };
+            args.extend(
+                [
+                    "-pix_fmt",
+                    pix_fmt,
+                    "-color_primaries",
+                    primaries,
+                    "-color_trc",
+                    hdr.transfer.color_trc(),
+                    "-colorspace",
+                    colorspace,
+                    "-color_range",
+                    "tv",
+                ]
+                .map(String::from),
+            );
+
+            // av1_nvenc writes the mastering display and content light level
+            // it finds in the side data of the frames, which ffmpeg carries
+            // over from the decoder. SVT-AV1 is given them as parameters.
+            if !is_gpu {
+                let mut params = vec![String::from("enable-hdr=1")];
+                if let Some(mastering_display) = &hdr.mastering_display {
+                    params.push(format!("mastering-display={}", mastering_display.svt_av1()));
+                }
+                if let Some(content_light_level) = &hdr.content_light_level {
+                    params.push(format!("content-light={}", content_light_level));
+                }
+                args.extend([String::from("-svtav1-params"), params.join(":")]);
+            }
+        }
+        ColourHandling::ToneMap => {
+            filters.push(TONE_MAP_FILTER);
+            args.extend(
+                [
+                    "-color_primaries",
+                    "bt709",
+                    "-color_trc",
+                    "bt709",
+                    "-colorspace",
+                    "bt709",
+                ]
+                .map(String::from),
+            );
+        }
+    }
+
+    if is_gpu {
+        filters.push(rendition.gpu_scale);
+    } else {
+        args.extend(["-s", rendition.cpu_size].map(String::from));
+    }
+
+    if !filters.is_empty() {
+        args.extend([String::from("-vf"), filters.join(",")]);
+    }
+
+    args
+}
+
+// The ffmpeg audio encoder arguments for a rendition's default audio track
+pub fn audio_args(rendition: &Rendition, is_gpu: bool) -> Vec<String> {
+    let audio_bitrate = if is_gpu {
+        rendition.gpu_audio_bitrate
+    } else {
+        "128k"
+    };
+
+    ["-c:a", "libopus", "-b:a", audio_bitrate, "-ac", "2"]
+        .map(String::from)
+        .to_vec()
+}
+
+// Encodes `file_path` to `output_path` with the rendition's settings
+pub async fn encode_rendition(
+    file_path: &str,
+    output_path: &str,
+    rendition: &Rendition,
+    is_gpu: bool,
+    colour: ColourHandling<'_>,
+    crf: Option<u32>,
+) -> Result<(), anyhow::Error> {
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args(["-i", file_path])
+        .args(video_args(rendition, is_gpu, colour, crf))
+        .args(audio_args(rendition, is_gpu))
+        .args(["-y", output_path]);
+
+    let output = cmd.output().await?;
+    println!("{:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffmpeg failed to encode {} rendition: {}",
+            rendition.resolution,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,448 @@
+/*
+ * ingest.rs
+ *
+ * Sources clients upload directly, instead of hosting them somewhere the
+ * server can download them from.
+ *
+ * The tus endpoint turns each finished upload into a transcode job of its
+ * own, and adds the job ID to the upload's metadata as `job_id`, for the
+ * client to read with a HEAD request. Upload metadata may carry the job
+ * options: is_gpu, all_audio_tracks, extract_subtitles, measure_quality,
+ * chunked ("true" or "false"), target_vmaf, storage_backend, source_hash
+ * and title, which defaults to the filename most tus clients send. Uploads
+ * need the token of a tenant in TENANT_TOKENS as a bearer token, but aren't
+ * bound to the tenant, so they can't be published to the registry.
+ *
+ * Sources streamed with the UploadAndTranscode RPC are written to the job's
+ * working directory, hashed as they arrive.
+ *
+ * Either kind of source is deleted once its job has ended.
+ */
+
+use crate::registry::authenticated_tenant;
+use crate::s5::hash_blake3_file;
+use crate::source::DownloadOptions;
+use crate::transcode::{upload_chunk::Payload, TranscodeRequest, UploadChunk};
+use crate::{job, TranscodeServiceHandler};
+use anyhow::anyhow;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Handle;
+use tokio::task;
+use tokio_stream::{Stream, StreamExt};
+use tonic::Status;
+use tus_client::{Client, FinishedUpload, TusServer};
+
+// Where sources uploaded over tus are stored
+const INGEST_DIR: &str = "./temp/ingest";
+
+// Where sources streamed over gRPC are written, in a directory per job
+const JOBS_DIR: &str = "./temp/jobs";
+
+// Path of each uploaded source, by the URL its job refers to it by
+static UPLOADS: Lazy<Mutex<HashMap<String, PathBuf>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The tus server of the ingest endpoint, once it is serving
+static INGEST_SERVER: OnceCell<Arc<TusServer>> = OnceCell::new();
+
+// The file uploaded as `upload_url`, if any
+pub fn uploaded_file(upload_url: &str) -> Option<PathBuf> {
+    UPLOADS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(upload_url)
+        .cloned()
+}
+
+// Makes the file at `path` the source of jobs whose URL is `upload_url`
+pub fn add_upload(upload_url: &str, path: PathBuf) {
+    UPLOADS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(upload_url.to_string(), path);
+}
+
+// Deletes the source uploaded as `upload_url` once its job has ended: the
+// job's working directory for a streamed source, or the tus upload
+pub async fn remove_upload(upload_url: &str) {
+    let path = match UPLOADS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(upload_url)
+    {
+        Some(path) => path,
+        None => return,
+    };
+
+    let result = match (path.parent(), INGEST_SERVER.get()) {
+        (Some(dir), _) if dir.starts_with(JOBS_DIR) => fs::remove_dir_all(dir).await,
+        (_, Some(server)) => {
+            // Terminating the upload also removes what the tus server keeps
+            // about it
+            let server = Arc::clone(server);
+            let upload_url = upload_url.to_string();
+            match task::spawn_blocking(move || Client::new(server).delete(&upload_url)).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(std::io::Error::other(e.to_string())),
+                Err(e) => Err(std::io::Error::other(e)),
+            }
+        }
+        (_, None) => fs::remove_file(&path).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("Failed to remove the source {}: {}", path.display(), e);
+    }
+}
+
+// Writes the source data following the header of an UploadAndTranscode call
+// to the working directory of `job_id`, returning its path and blake3 hash
+pub async fn receive_source(
+    stream: &mut (impl Stream<Item = Result<UploadChunk, Status>> + Unpin),
+    job_id: &str,
+    options: &DownloadOptions,
+) -> Result<(PathBuf, blake3::Hash), anyhow::Error> {
+    let dir = PathBuf::from(JOBS_DIR).join(job_id);
+    fs::create_dir_all(&dir).await?;
+    let path = dir.join("source");
+
+    match write_source(stream, &path, options).await {
+        Ok(hash) => Ok((path, hash)),
+        Err(e) => {
+            let _ = fs::remove_dir_all(&dir).await;
+            Err(e)
+        }
+    }
+}
+
+async fn write_source(
+    stream: &mut (impl Stream<Item = Result<UploadChunk, Status>> + Unpin),
+    path: &Path,
+    options: &DownloadOptions,
+) -> Result<blake3::Hash, anyhow::Error> {
+    let mut file = File::create(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut received: u64 = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let data = match chunk?.payload {
+            Some(Payload::Data(data)) => data,
+            _ => return Err(anyhow!("Expected source data after the header")),
+        };
+
+        received += data.len() as u64;
+        if received > options.max_size() {
+            return Err(anyhow!(
+                "Source is larger than the limit of {} bytes",
+                options.max_size()
+            ));
+        }
+
+        hasher.update(&data);
+        file.write_all(&data).await?;
+    }
+    file.flush().await?;
+
+    if received == 0 {
+        return Err(anyhow!("No source data was sent"));
+    }
+
+    let hash = hasher.finalize();
+    if let Some(expected_hash) = options.expected_hash() {
+        if hash != expected_hash {
+            return Err(anyhow!(
+                "Source hash {} does not match the expected {}",
+                hash.to_hex(),
+                expected_hash.to_hex()
+            ));
+        }
+    }
+
+    Ok(hash)
+}
+
+// Serves the ingest endpoint on `addr`, with upload URLs under `base_url`,
+// queueing a transcode job with `handler` for every finished upload
+pub async fn serve(
+    addr: SocketAddr,
+    base_url: String,
+    handler: TranscodeServiceHandler,
+) -> Result<(), anyhow::Error> {
+    let server = Arc::new(ingest_server(INGEST_DIR, base_url, handler)?);
+    let _ = INGEST_SERVER.set(Arc::clone(&server));
+
+    println!("Ingest endpoint listening on {}", addr);
+    server.serve(addr).await?;
+
+    Ok(())
+}
+
+// The tus server storing uploads in `dir`, for callers with a tenant token.
+// Uploads over the source size limit are refused.
+fn ingest_server(
+    dir: impl Into<PathBuf>,
+    base_url: String,
+    handler: TranscodeServiceHandler,
+) -> Result<TusServer, anyhow::Error> {
+    // Uploads are handled on blocking threads, which reach back into the
+    // runtime to register jobs
+    let runtime = Handle::current();
+    let max_size = DownloadOptions::from_env("")?.max_size();
+
+    let server = TusServer::new(dir, base_url)?
+        .with_max_size(max_size as usize)
+        .with_authorization(|headers| {
+            bearer_token(headers)
+                .and_then(authenticated_tenant)
+                .is_some()
+        })
+        .with_on_finish(move |upload| {
+            let request = transcode_request(upload);
+            let job_id = runtime.block_on(job::create_job());
+            println!("Upload {} queued as job {}", upload.upload_url, job_id);
+
+            add_upload(&upload.upload_url, upload.path.clone());
+
+            let handler = handler.clone();
+            let task_job_id = job_id.clone();
+            let path = upload.path.to_string_lossy().to_string();
+            runtime.spawn(async move {
+                // Hashed here rather than while the tus server holds its lock
+                let source_hash = match task::spawn_blocking(move || hash_blake3_file(path)).await {
+                    Ok(Ok(hash)) => Some(hash),
+                    _ => {
+                        eprintln!("Failed to hash the source of job {}", task_job_id);
+                        None
+                    }
+                };
+
+                if let Err(e) = handler
+                    .queue(&task_job_id, request, source_hash, None)
+                    .await
+                {
+                    eprintln!("Failed to queue job {}: {}", task_job_id, e.message());
+                }
+            });
+
+            HashMap::from([("job_id".to_string(), job_id)])
+        });
+
+    Ok(server)
+}
+
+// The token of an `Authorization: Bearer` header
+fn bearer_token(headers: &HashMap<String, String>) -> Option<&str> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))?
+        .1
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+// The request of the job created for `upload`, with options from its metadata
+fn transcode_request(upload: &FinishedUpload) -> TranscodeRequest {
+    let flag = |key: &str| {
+        upload
+            .metadata
+            .get(key)
+            .is_some_and(|value| value == "true")
+    };
+    let text = |key: &str| upload.metadata.get(key).cloned().unwrap_or_default();
+
+    TranscodeRequest {
+        url: upload.upload_url.clone(),
+        is_gpu: flag("is_gpu"),
+        all_audio_tracks: flag("all_audio_tracks"),
+        extract_subtitles: flag("extract_subtitles"),
+        measure_quality: flag("measure_quality"),
+        chunked: flag("chunked"),
+        target_vmaf: text("target_vmaf").parse().unwrap_or_default(),
+        storage_backend: text("storage_backend"),
+        source_hash: text("source_hash"),
+        registry_name: text("registry_name"),
+        title: match text("title") {
+            title if title.is_empty() => text("filename"),
+            title => title,
+        },
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::JobStatus;
+    use crate::TranscodeTask;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    const BASE_URL: &str = "http://localhost:1080/files";
+
+    fn handler() -> (TranscodeServiceHandler, mpsc::Receiver<TranscodeTask>) {
+        let (sender, receiver) = mpsc::channel(10);
+        let handler = TranscodeServiceHandler {
+            transcode_task_sender: Some(Arc::new(tokio::sync::Mutex::new(sender))),
+        };
+        (handler, receiver)
+    }
+
+    async fn next_task(receiver: &mut mpsc::Receiver<TranscodeTask>) -> TranscodeTask {
+        timeout(Duration::from_secs(10), receiver.recv())
+            .await
+            .expect("No job was queued")
+            .unwrap()
+    }
+
+    fn header(request: TranscodeRequest) -> UploadChunk {
+        UploadChunk {
+            payload: Some(Payload::Header(request)),
+        }
+    }
+
+    fn data(data: &[u8]) -> UploadChunk {
+        UploadChunk {
+            payload: Some(Payload::Data(data.to_vec())),
+        }
+    }
+
+    fn stream(chunks: Vec<UploadChunk>) -> impl Stream<Item = Result<UploadChunk, Status>> + Unpin {
+        tokio_stream::iter(chunks.into_iter().map(Ok))
+    }
+
+    #[test]
+    fn bearer_tokens_are_read_from_the_authorization_header() {
+        let headers =
+            |value: &str| HashMap::from([("Authorization".to_string(), value.to_string())]);
+
+        assert_eq!(bearer_token(&headers("Bearer secret")), Some("secret"));
+        assert_eq!(bearer_token(&headers("Basic c2VjcmV0")), None);
+        assert_eq!(bearer_token(&HashMap::new()), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn finished_uploads_become_jobs_until_they_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("clip.mp4");
+        std::fs::write(&source, vec![7; 100_000]).unwrap();
+        let (handler, mut receiver) = handler();
+        let server = Arc::new(
+            ingest_server(dir.path().join("ingest"), BASE_URL.to_string(), handler).unwrap(),
+        );
+        let _ = INGEST_SERVER.set(Arc::clone(&server));
+
+        let client_server = Arc::clone(&server);
+        let (upload_url, job_id) = task::spawn_blocking(move || {
+            let client = Client::new(client_server);
+            let metadata = HashMap::from([
+                ("filename".to_string(), "clip.mp4".to_string()),
+                ("chunked".to_string(), "true".to_string()),
+            ]);
+            let upload_url = client
+                .create_with_metadata(BASE_URL, &source, metadata)
+                .unwrap();
+            client.upload(&upload_url, &source).unwrap();
+
+            let metadata = client.get_info(&upload_url).unwrap().metadata.unwrap();
+            (upload_url, metadata["job_id"].clone())
+        })
+        .await
+        .unwrap();
+
+        let (task_job_id, request) = next_task(&mut receiver).await;
+        assert_eq!(task_job_id, job_id);
+        assert_eq!(request.url, upload_url);
+        assert_eq!(request.title, "clip.mp4");
+        assert!(request.chunked);
+        assert_eq!(
+            job::get_job(&job_id).await.unwrap().status,
+            JobStatus::Queued
+        );
+
+        let path = uploaded_file(&upload_url).unwrap();
+        assert_eq!(Some(path.clone()), server.file_path(&upload_url));
+
+        remove_upload(&upload_url).await;
+
+        assert_eq!(uploaded_file(&upload_url), None);
+        assert!(!path.exists());
+        let info_server = Arc::clone(&server);
+        let info = task::spawn_blocking(move || Client::new(info_server).get_info(&upload_url))
+            .await
+            .unwrap();
+        assert!(info.is_err());
+    }
+
+    #[tokio::test]
+    async fn streamed_sources_become_jobs_until_they_end() {
+        let (handler, mut receiver) = handler();
+        let chunks = vec![
+            header(TranscodeRequest {
+                title: "Streamed".to_string(),
+                ..Default::default()
+            }),
+            data(&[1; 1000]),
+            data(&[2; 500]),
+        ];
+        let source = [[1; 1000].as_slice(), [2; 500].as_slice()].concat();
+
+        let response = handler
+            .receive_and_queue(stream(chunks), None)
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(
+            response.source_hash,
+            blake3::hash(&source).to_hex().as_str()
+        );
+        let (job_id, request) = next_task(&mut receiver).await;
+        assert_eq!(job_id, response.job_id);
+        assert_eq!(request.url, format!("upload://{}", job_id));
+        assert_eq!(request.title, "Streamed");
+        assert_eq!(request.source_hash, response.source_hash);
+
+        let path = uploaded_file(&request.url).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), source);
+
+        remove_upload(&request.url).await;
+
+        assert_eq!(uploaded_file(&request.url), None);
+        assert!(!path.parent().unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn streamed_sources_need_a_header_data_and_a_tenant_to_publish() {
+        let (handler, mut receiver) = handler();
+
+        let status = handler
+            .receive_and_queue(stream(vec![data(&[1; 10])]), None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let status = handler
+            .receive_and_queue(stream(vec![header(TranscodeRequest::default())]), None)
+            .await
+            .unwrap_err();
+        assert!(status.message().contains("No source data"), "{}", status);
+
+        let publishing = TranscodeRequest {
+            registry_name: "video".to_string(),
+            ..Default::default()
+        };
+        let status = handler
+            .receive_and_queue(stream(vec![header(publishing), data(&[1; 10])]), None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::transcode::{Artifact, GetJobResultResponse};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+// The state and outputs of a single transcode request
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub status: JobStatus,
+    pub message: String,
+    pub artifacts: Vec<Artifact>,
+    // Registry key and revision the job's media metadata was published under
+    pub registry_key: String,
+    pub registry_revision: u64,
+}
+
+static JOBS: Lazy<Mutex<HashMap<String, Job>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Registers a new queued job and returns its ID
+pub async fn create_job() -> String {
+    let job_id = uuid::Uuid::new_v4().to_string();
+
+    JOBS.lock().await.insert(
+        job_id.clone(),
+        Job {
+            status: JobStatus::Queued,
+            message: String::new(),
+            artifacts: Vec::new(),
+            registry_key: String::new(),
+            registry_revision: 0,
+        },
+    );
+
+    job_id
+}
+
+pub async fn set_status(job_id: &str, status: JobStatus, message: &str) {
+    if let Some(job) = JOBS.lock().await.get_mut(job_id) {
+        job.status = status;
+        job.message = message.to_string();
+    }
+}
+
+pub async fn add_artifact(job_id: &str, artifact: Artifact) {
+    if let Some(job) = JOBS.lock().await.get_mut(job_id) {
+        job.artifacts.push(artifact);
+    }
+}
+
+pub async fn set_published(job_id: &str, registry_key: &str, registry_revision: u64) {
+    if let Some(job) = JOBS.lock().await.get_mut(job_id) {
+        job.registry_key = registry_key.to_string();
+        job.registry_revision = registry_revision;
+    }
+}
+
+pub async fn get_job(job_id: &str) -> Option<Job> {
+    JOBS.lock().await.get(job_id).cloned()
+}
+
+impl From<Job> for GetJobResultResponse {
+    fn from(job: Job) -> Self {
+        let metadata_cid = job
+            .artifacts
+            .iter()
+            .find(|artifact| artifact.kind == "metadata")
+            .map(|artifact| artifact.cid.clone())
+            .unwrap_or_default();
+
+        GetJobResultResponse {
+            status_code: 200,
+            status: job.status.as_str().to_string(),
+            message: job.message,
+            artifacts: job.artifacts,
+            metadata_cid,
+            registry_key: job.registry_key,
+            registry_revision: job.registry_revision,
+        }
+    }
+}
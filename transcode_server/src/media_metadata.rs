@@ -0,0 +1,84 @@
+/*
+ * media_metadata.rs
+ *
+ * The media metadata document of a finished job, which ties its renditions,
+ * audio tracks, subtitles and thumbnail together. It follows the JSON form of
+ * S5's media metadata ("type": "media"), with the outputs grouped by media
+ * type in `mediaTypes`. The document is stored encrypted like the outputs, and
+ * its CID is the handle of the whole video.
+ */
+
+use crate::transcode::Artifact;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+// Describes `artifacts` as the video `title`, `duration` seconds long if known
+pub fn media_metadata(title: &str, duration: Option<f64>, artifacts: &[Artifact]) -> Value {
+    let mut media_types: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+    for artifact in artifacts {
+        media_types
+            .entry(artifact.kind.as_str())
+            .or_default()
+            .push(media_format(artifact));
+    }
+
+    let mut details = Map::new();
+    if let Some(duration) = duration {
+        details.insert("duration".to_string(), json!(duration));
+    }
+
+    json!({
+        "type": "media",
+        "name": title,
+        "details": details,
+        "parents": [],
+        "mediaTypes": media_types,
+        "links": {},
+        "extraMetadata": {},
+    })
+}
+
+// The entry of one output in `mediaTypes`, leaving out what is unknown
+fn media_format(artifact: &Artifact) -> Value {
+    let mut format = Map::new();
+    let mut set = |key: &str, value: Value| {
+        format.insert(key.to_string(), value);
+    };
+
+    set("cid", json!(artifact.cid));
+
+    match artifact.kind.as_str() {
+        "video" => {
+            set("ext", json!("mp4"));
+            set("container", json!("mp4"));
+            set("vcodec", json!(artifact.codec));
+            // Every rendition carries a stereo Opus mix of the first audio stream
+            set("acodec", json!("opus"));
+            set("dynamicRange", json!(artifact.dynamic_range));
+        }
+        "audio" => {
+            set("ext", json!("mp4"));
+            set("container", json!("mp4"));
+            set("acodec", json!(artifact.codec));
+        }
+        "subtitle" => set("ext", json!("vtt")),
+        "thumbnail" => set("ext", json!("jpg")),
+        _ => (),
+    }
+
+    if !artifact.language.is_empty() && artifact.language != "und" {
+        set("languages", json!([artifact.language]));
+    }
+    if artifact.width > 0 && artifact.height > 0 {
+        set("width", json!(artifact.width));
+        set("height", json!(artifact.height));
+    }
+    if artifact.bitrate > 0 {
+        set("bitrate", json!(artifact.bitrate));
+    }
+    if artifact.channels > 0 {
+        set("audioChannels", json!(artifact.channels));
+    }
+
+    Value::Object(format)
+}
@@ -0,0 +1,258 @@
+use crate::encode::{video_args, ColourHandling, Rendition};
+use crate::probe::probe_duration;
+use crate::quality::measure_quality;
+use std::future::Future;
+use tokio::process::Command;
+
+// CRF values tried by the probe encodes, from best quality to smallest file
+const CANDIDATE_CRFS: [u32; 6] = [18, 24, 30, 36, 42, 48];
+
+// Number of segments sampled from the source and the length of each in seconds
+const SAMPLE_COUNT: usize = 3;
+const SAMPLE_SECONDS: f64 = 5.0;
+
+// The CRF chosen for a rendition and the mean VMAF its probe encodes reached
+#[derive(Debug, Clone, Copy)]
+pub struct CrfChoice {
+    pub crf: u32,
+    pub vmaf: f64,
+}
+
+// Picks the highest CRF whose probe encodes reach `target_vmaf` on average. Short
+// segments spread over the source are cut losslessly, encoded at candidate CRFs
+// with the rendition's settings and scored against the cut. If no candidate
+// reaches the target, the lowest CRF is used.
+pub async fn select_crf(
+    file_path: &str,
+    work_prefix: &str,
+    rendition: &Rendition,
+    is_gpu: bool,
+    colour: ColourHandling<'_>,
+    target_vmaf: f64,
+) -> Result<CrfChoice, anyhow::Error> {
+    let samples = cut_samples(file_path, work_prefix).await?;
+
+    bisect_crf(&CANDIDATE_CRFS, target_vmaf, |crf| {
+        let samples = &samples;
+        async move {
+            let vmaf = probe_encode(samples, work_prefix, rendition, is_gpu, colour, crf).await?;
+            println!(
+                "{} probe encode crf {}: vmaf {:.2}",
+                rendition.resolution, crf, vmaf
+            );
+            Ok(vmaf)
+        }
+    })
+    .await
+}
+
+// Bisects `candidates`, ordered from best quality to smallest file, for the
+// highest CRF that `score` puts at or above `target_vmaf`. Quality only drops
+// as CRF rises, so each score rules out half of the remaining candidates. If
+// none reaches the target, the lowest CRF is returned with its score.
+async fn bisect_crf<F, Fut>(
+    candidates: &[u32],
+    target_vmaf: f64,
+    mut score: F,
+) -> Result<CrfChoice, anyhow::Error>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<f64, anyhow::Error>>,
+{
+    let mut passed: Option<CrfChoice> = None;
+    let mut failed: Option<CrfChoice> = None;
+    let (mut low, mut high) = (0, candidates.len());
+    while low < high {
+        let middle = (low + high) / 2;
+        let crf = candidates[middle];
+        let vmaf = score(crf).await?;
+
+        if vmaf >= target_vmaf {
+            passed = Some(CrfChoice { crf, vmaf });
+            low = middle + 1;
+        } else {
+            failed = Some(CrfChoice { crf, vmaf });
+            high = middle;
+        }
+    }
+
+    // When nothing passed, the last candidate to fail is the lowest CRF
+    passed
+        .or(failed)
+        .ok_or_else(|| anyhow::anyhow!("No candidate CRF values"))
+}
+
+// Encodes every sample at `crf` and returns their mean VMAF
+async fn probe_encode(
+    samples: &[String],
+    work_prefix: &str,
+    rendition: &Rendition,
+    is_gpu: bool,
+    colour: ColourHandling<'_>,
+    crf: u32,
+) -> Result<f64, anyhow::Error> {
+    let mut total_vmaf = 0.0;
+    for (i, sample) in samples.iter().enumerate() {
+        let encoded = format!("{}_sample{}_crf{}.mp4", work_prefix, i, crf);
+        let output = Command::new("ffmpeg")
+            .args(["-i", sample.as_str()])
+            .args(video_args(rendition, is_gpu, colour, Some(crf)))
+            .args(["-an", "-y", encoded.as_str()])
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Probe encode at crf {} failed: {}",
+                crf,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        let log_path = format!("{}_sample{}_crf{}_vmaf.json", work_prefix, i, crf);
+        total_vmaf += measure_quality(sample, &encoded, &log_path).await?.vmaf;
+    }
+
+    Ok(total_vmaf / samples.len() as f64)
+}
+
+// Cuts SAMPLE_COUNT segments evenly spread over the source to lossless FFV1, or
+// the whole source if it is too short to sample
+async fn cut_samples(file_path: &str, work_prefix: &str) -> Result<Vec<String>, anyhow::Error> {
+    let duration = probe_duration(file_path).await?;
+
+    let mut samples = Vec::new();
+    for (i, (start, length)) in sample_segments(duration).iter().enumerate() {
+        let sample = format!("{}_sample{}.mkv", work_prefix, i);
+        let output = Command::new("ffmpeg")
+            .args([
+                "-ss",
+                start.to_string().as_str(),
+                "-i",
+                file_path,
+                "-t",
+                length.to_string().as_str(),
+                "-map",
+                "0:v:0",
+                "-c:v",
+                "ffv1",
+                "-an",
+                "-y",
+                sample.as_str(),
+            ])
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Failed to cut sample at {}s: {}",
+                start,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        samples.push(sample);
+    }
+
+    Ok(samples)
+}
+
+// The start and length, in seconds, of each sample of a source lasting
+// `duration` seconds. Samples are centred at even intervals over the source.
+fn sample_segments(duration: f64) -> Vec<(f64, f64)> {
+    if duration <= SAMPLE_SECONDS * SAMPLE_COUNT as f64 {
+        return vec![(0.0, duration)];
+    }
+
+    (1..=SAMPLE_COUNT)
+        .map(|n| {
+            let middle = duration * n as f64 / (SAMPLE_COUNT + 1) as f64;
+            (middle - SAMPLE_SECONDS / 2.0, SAMPLE_SECONDS)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mean VMAF of a source at each candidate CRF, falling as CRF rises
+    fn vmaf_at(crf: u32) -> f64 {
+        match crf {
+            18 => 97.0,
+            24 => 95.0,
+            30 => 93.0,
+            36 => 90.0,
+            42 => 86.0,
+            _ => 80.0,
+        }
+    }
+
+    // Bisects CANDIDATE_CRFS with `vmaf_at`, returning the choice and the CRFs scored
+    async fn bisect(target_vmaf: f64) -> (CrfChoice, Vec<u32>) {
+        let mut scored = Vec::new();
+        let choice = bisect_crf(&CANDIDATE_CRFS, target_vmaf, |crf| {
+            scored.push(crf);
+            async move { Ok(vmaf_at(crf)) }
+        })
+        .await
+        .unwrap();
+
+        (choice, scored)
+    }
+
+    #[tokio::test]
+    async fn bisection_picks_the_highest_crf_reaching_the_target() {
+        for (target_vmaf, crf) in [
+            (92.0, 30),
+            (93.0, 30),
+            (94.0, 24),
+            (89.0, 36),
+            (86.0, 42),
+            (50.0, 48),
+        ] {
+            let (choice, scored) = bisect(target_vmaf).await;
+            assert_eq!(choice.crf, crf, "target {}", target_vmaf);
+            assert_eq!(choice.vmaf, vmaf_at(crf));
+            assert!(scored.len() <= 3, "scored {:?}", scored);
+        }
+    }
+
+    #[tokio::test]
+    async fn bisection_falls_back_to_the_lowest_crf() {
+        let (choice, scored) = bisect(99.0).await;
+        assert_eq!(choice.crf, 18);
+        assert_eq!(choice.vmaf, 97.0);
+        assert_eq!(scored, [36, 24, 18]);
+    }
+
+    #[tokio::test]
+    async fn bisection_stops_at_the_first_scoring_error() {
+        let mut scored = 0;
+        let result = bisect_crf(&CANDIDATE_CRFS, 90.0, |_| {
+            scored += 1;
+            async { Err(anyhow::anyhow!("probe encode failed")) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(scored, 1);
+        assert!(bisect_crf(&[], 90.0, |_| async { Ok(100.0) })
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn samples_are_spread_over_the_source() {
+        assert_eq!(
+            sample_segments(100.0),
+            [(22.5, 5.0), (47.5, 5.0), (72.5, 5.0)]
+        );
+    }
+
+    #[test]
+    fn short_sources_are_sampled_whole() {
+        assert_eq!(sample_segments(15.0), [(0.0, 15.0)]);
+        assert_eq!(sample_segments(4.0), [(0.0, 4.0)]);
+    }
+}
@@ -0,0 +1,600 @@
+/*
+ * portals.rs
+ *
+ * Replicates stored blobs across several S5 portals. Each blob is uploaded
+ * to a quorum of portals in parallel, failing over to the next portal when
+ * one errors, and portals that failed recently are tried last.
+ */
+
+use crate::registry::RegistryEntry;
+use crate::storage::{BlobStat, S5Backend, StorageBackend};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use dotenv::var;
+use futures_util::future::join_all;
+use once_cell::sync::Lazy;
+use reqwest_async::Response;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long a portal that failed is tried after the healthy ones
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(300);
+
+// When each portal URL last failed, shared by the jobs
+static PORTAL_FAILURES: Lazy<Mutex<HashMap<String, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The portals configured in the environment, shared by the jobs so the
+// portals known to hold each blob are remembered between them
+static SHARED: Lazy<Result<Arc<PortalSet>, String>> = Lazy::new(|| {
+    PortalSet::from_env()
+        .map(Arc::new)
+        .map_err(|e| e.to_string())
+});
+
+struct Portal {
+    backend: S5Backend,
+    url: String,
+    weight: u32,
+}
+
+// The S5 portals blobs are replicated to
+pub struct PortalSet {
+    portals: Vec<Portal>,
+    quorum: usize,
+    // Portals holding each blob stored through this set
+    holders: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl PortalSet {
+    // `portals` are (url, token, weight) entries. Each blob is stored on
+    // `quorum` of them.
+    pub fn new(portals: Vec<(String, String, u32)>, quorum: usize) -> Result<Self, anyhow::Error> {
+        if portals.is_empty() {
+            return Err(anyhow!("No S5 portals are configured"));
+        }
+        if quorum == 0 || quorum > portals.len() {
+            return Err(anyhow!(
+                "Quorum of {} is impossible with {} portals",
+                quorum,
+                portals.len()
+            ));
+        }
+
+        let portals = portals
+            .into_iter()
+            .map(|(url, token, weight)| Portal {
+                backend: S5Backend::new(&url, &token),
+                url: url.trim_end_matches('/').to_string(),
+                weight,
+            })
+            .collect();
+
+        Ok(PortalSet {
+            portals,
+            quorum,
+            holders: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Uses the portals listed in PORTALS as comma separated `url|token|weight`
+    // entries (token and weight optional), storing each blob on PORTAL_QUORUM
+    // of them, by default a majority. Without PORTALS, uses the single portal
+    // at PORTAL_URL with TOKEN.
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let portals = match var("PORTALS").ok().filter(|portals| !portals.is_empty()) {
+            Some(portals) => parse_portals(&portals)?,
+            None => {
+                let portal_url = var("PORTAL_URL")
+                    .ok()
+                    .filter(|url| !url.is_empty())
+                    .ok_or_else(|| anyhow!("Neither PORTALS nor PORTAL_URL is set"))?;
+                vec![(portal_url, var("TOKEN").unwrap_or_default(), 1)]
+            }
+        };
+
+        let quorum = match var("PORTAL_QUORUM")
+            .ok()
+            .filter(|quorum| !quorum.is_empty())
+        {
+            Some(quorum) => quorum
+                .parse()
+                .map_err(|e| anyhow!("Invalid PORTAL_QUORUM {}: {}", quorum, e))?,
+            None => portals.len() / 2 + 1,
+        };
+
+        PortalSet::new(portals, quorum)
+    }
+
+    // The set of the portals configured in the environment
+    pub fn shared() -> Result<Arc<PortalSet>, anyhow::Error> {
+        SHARED.clone().map_err(|e| anyhow!(e))
+    }
+
+    // Portals in the order they are tried: healthy before recently failed,
+    // then by descending weight
+    fn ranked(&self) -> Vec<&Portal> {
+        let failures = PORTAL_FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+        let is_unhealthy = |portal: &Portal| {
+            failures
+                .get(&portal.url)
+                .is_some_and(|failed| failed.elapsed() < UNHEALTHY_COOLDOWN)
+        };
+
+        let mut portals: Vec<&Portal> = self.portals.iter().collect();
+        portals.sort_by_key(|portal| (is_unhealthy(portal), std::cmp::Reverse(portal.weight)));
+        portals
+    }
+
+    // The portals to ask for the blob with `cid`: those known to hold it first
+    fn ranked_for(&self, cid: &str) -> Vec<&Portal> {
+        let holders = self.holders(cid);
+        let mut portals = self.ranked();
+        portals.sort_by_key(|portal| !holders.contains(&portal.url));
+        portals
+    }
+
+    // Requests the blob with `cid` from the first portal that serves it
+    pub async fn open(&self, cid: &str) -> Result<Response, anyhow::Error> {
+        let mut errors = Vec::new();
+
+        for portal in self.ranked_for(cid) {
+            match portal.backend.open(cid).await {
+                Ok(response) => {
+                    record_health(portal, true);
+                    return Ok(response);
+                }
+                Err(e) => {
+                    record_health(portal, false);
+                    errors.push(format!("{}: {}", portal.url, e));
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "No portal served blob {} ({})",
+            cid,
+            errors.join("; ")
+        ))
+    }
+
+    // The validly signed registry entry under `pk` with the highest revision
+    // any portal holds, or None if none holds one
+    pub async fn registry_entry(&self, pk: &[u8]) -> Result<Option<RegistryEntry>, anyhow::Error> {
+        let results = join_all(
+            self.portals
+                .iter()
+                .map(|portal| portal.backend.registry_entry(pk)),
+        )
+        .await;
+
+        let mut latest: Option<RegistryEntry> = None;
+        let mut errors = Vec::new();
+        for (portal, result) in self.portals.iter().zip(results) {
+            match result {
+                Ok(Some(entry)) if entry.pk == pk && entry.is_valid() => {
+                    if latest
+                        .as_ref()
+                        .is_none_or(|latest| entry.revision > latest.revision)
+                    {
+                        latest = Some(entry);
+                    }
+                }
+                Ok(Some(_)) => eprintln!("{} sent an invalid registry entry", portal.url),
+                Ok(None) => (),
+                Err(e) => errors.push(format!("{}: {}", portal.url, e)),
+            }
+        }
+
+        if errors.len() == self.portals.len() {
+            return Err(anyhow!(
+                "No portal answered for the registry entry ({})",
+                errors.join("; ")
+            ));
+        }
+
+        Ok(latest)
+    }
+
+    // Publishes `entry` to every portal, succeeding once a quorum accepted it
+    pub async fn publish_registry_entry(&self, entry: &RegistryEntry) -> Result<(), anyhow::Error> {
+        let results = join_all(
+            self.portals
+                .iter()
+                .map(|portal| portal.backend.publish_registry_entry(entry)),
+        )
+        .await;
+
+        let mut published = 0;
+        let mut errors = Vec::new();
+        for (portal, result) in self.portals.iter().zip(results) {
+            match result {
+                Ok(()) => published += 1,
+                Err(e) => {
+                    eprintln!("Failed to publish registry entry to {}: {}", portal.url, e);
+                    errors.push(format!("{}: {}", portal.url, e));
+                }
+            }
+        }
+
+        if published < self.quorum {
+            return Err(anyhow!(
+                "Published the registry entry to {} of the {} portals needed ({})",
+                published,
+                self.quorum,
+                errors.join("; ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn holders(&self, cid: &str) -> Vec<String> {
+        self.holders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(cid)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+fn parse_portals(portals: &str) -> Result<Vec<(String, String, u32)>, anyhow::Error> {
+    portals
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut fields = entry.split('|');
+            let url = fields.next().unwrap_or_default().to_string();
+            let token = fields.next().unwrap_or_default().to_string();
+            let weight = match fields.next() {
+                Some(weight) => weight
+                    .parse()
+                    .map_err(|e| anyhow!("Invalid weight of portal {}: {}", url, e))?,
+                None => 1,
+            };
+
+            Ok((url, token, weight))
+        })
+        .collect()
+}
+
+fn record_health(portal: &Portal, healthy: bool) {
+    let mut failures = PORTAL_FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+    if healthy {
+        failures.remove(&portal.url);
+    } else {
+        failures.insert(portal.url.clone(), Instant::now());
+    }
+}
+
+// Uploads the file at `path` to `portal` and checks the portal holds it
+// intact, removing a bad replica so it isn't mistaken for a good one later
+async fn replicate(portal: &Portal, path: &str, index: usize) -> Result<String, anyhow::Error> {
+    let cid = portal.backend.put(path).await?;
+    let scratch_path = format!("{}.{}.verify", path, index);
+    if let Err(e) = portal.backend.verify(&cid, &scratch_path).await {
+        if let Err(delete_error) = portal.backend.delete(&cid).await {
+            eprintln!(
+                "Failed to remove bad replica {} from {}: {}",
+                cid, portal.url, delete_error
+            );
+        }
+        return Err(e);
+    }
+
+    Ok(cid)
+}
+
+#[async_trait]
+impl StorageBackend for PortalSet {
+    fn name(&self) -> &'static str {
+        "s5"
+    }
+
+    async fn put(&self, path: &str) -> Result<String, anyhow::Error> {
+        let mut candidates = self.ranked().into_iter().enumerate();
+        let mut holders = Vec::new();
+        let mut stored_cid = None;
+        let mut errors = Vec::new();
+
+        // Upload to as many portals as the quorum still needs, in parallel,
+        // until it is met or every portal has been tried
+        while holders.len() < self.quorum {
+            let batch: Vec<(usize, &Portal)> = candidates
+                .by_ref()
+                .take(self.quorum - holders.len())
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let results = join_all(
+                batch
+                    .iter()
+                    .map(|(index, portal)| replicate(portal, path, *index)),
+            )
+            .await;
+
+            for ((_, portal), result) in batch.into_iter().zip(results) {
+                record_health(portal, result.is_ok());
+                match result {
+                    Ok(cid) => {
+                        println!("Stored {} on {}", cid, portal.url);
+                        stored_cid = Some(cid);
+                        holders.push(portal.url.clone());
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to store {} on {}: {}", path, portal.url, e);
+                        errors.push(format!("{}: {}", portal.url, e));
+                    }
+                }
+            }
+        }
+
+        let cid = match stored_cid {
+            Some(cid) if holders.len() >= self.quorum => cid,
+            _ => {
+                return Err(anyhow!(
+                    "Stored {} on {} of the {} portals needed ({})",
+                    path,
+                    holders.len(),
+                    self.quorum,
+                    errors.join("; ")
+                ))
+            }
+        };
+
+        self.holders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(cid.clone(), holders);
+
+        Ok(cid)
+    }
+
+    async fn get(&self, cid: &str, path: &str) -> Result<(), anyhow::Error> {
+        let mut errors = Vec::new();
+
+        for portal in self.ranked_for(cid) {
+            match portal.backend.get(cid, path).await {
+                Ok(()) => {
+                    record_health(portal, true);
+                    return Ok(());
+                }
+                Err(e) => {
+                    record_health(portal, false);
+                    errors.push(format!("{}: {}", portal.url, e));
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "No portal served blob {} ({})",
+            cid,
+            errors.join("; ")
+        ))
+    }
+
+    async fn stat(&self, cid: &str) -> Result<Option<BlobStat>, anyhow::Error> {
+        let mut errors = Vec::new();
+
+        for portal in self.ranked_for(cid) {
+            match portal.backend.stat(cid).await {
+                Ok(Some(stat)) => return Ok(Some(stat)),
+                Ok(None) => (),
+                Err(e) => errors.push(format!("{}: {}", portal.url, e)),
+            }
+        }
+
+        if errors.len() == self.portals.len() {
+            return Err(anyhow!(
+                "No portal answered for blob {} ({})",
+                cid,
+                errors.join("; ")
+            ));
+        }
+
+        Ok(None)
+    }
+
+    async fn delete(&self, cid: &str) -> Result<(), anyhow::Error> {
+        let results = join_all(self.portals.iter().map(|portal| portal.backend.delete(cid))).await;
+
+        let errors: Vec<String> = self
+            .portals
+            .iter()
+            .zip(results)
+            .filter_map(|(portal, result)| result.err().map(|e| format!("{}: {}", portal.url, e)))
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Failed to delete blob {} ({})",
+                cid,
+                errors.join("; ")
+            ))
+        }
+    }
+
+    // Each replica was verified as it was stored, so only check that enough
+    // of them were
+    async fn verify(&self, cid: &str, _scratch_path: &str) -> Result<(), anyhow::Error> {
+        let holders = self.holders(cid);
+        if holders.len() < self.quorum {
+            return Err(anyhow!(
+                "Blob {} is held by {} of the {} portals needed",
+                cid,
+                holders.len(),
+                self.quorum
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn locations(&self, cid: &str) -> Vec<String> {
+        self.holders(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::fs;
+
+    // An address nothing listens on
+    fn dead_portal() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    // Serves a stand-in portal storing its blobs under `dir`, returning its URL
+    async fn live_portal(dir: &std::path::Path) -> String {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let portal_url = format!("http://{}", addr);
+        let portal = crate::s5::local_portal(&dir.to_string_lossy(), &portal_url).unwrap();
+        tokio::spawn(Arc::new(portal).serve(addr));
+        while tokio::net::TcpStream::connect(addr).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+
+        portal_url
+    }
+
+    async fn source_file(dir: &std::path::Path) -> String {
+        // Uploads remember their URLs under ./temp, like the server does
+        fs::create_dir_all("./temp").await.unwrap();
+        let path = dir.join("rendition");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).await.unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn urls(portals: Vec<&Portal>) -> Vec<String> {
+        portals
+            .into_iter()
+            .map(|portal| portal.url.clone())
+            .collect()
+    }
+
+    #[test]
+    fn portals_are_parsed_from_the_environment_format() {
+        assert_eq!(
+            parse_portals("https://a.example|ta|3, https://b.example|tb,https://c.example")
+                .unwrap(),
+            vec![
+                ("https://a.example".to_string(), "ta".to_string(), 3),
+                ("https://b.example".to_string(), "tb".to_string(), 1),
+                ("https://c.example".to_string(), String::new(), 1),
+            ]
+        );
+        assert!(parse_portals("https://a.example|ta|heavy").is_err());
+    }
+
+    #[test]
+    fn impossible_quorums_are_refused() {
+        let portals = vec![("https://a.example".to_string(), String::new(), 1)];
+
+        assert!(PortalSet::new(Vec::new(), 1).is_err());
+        assert!(PortalSet::new(portals.clone(), 0).is_err());
+        assert!(PortalSet::new(portals.clone(), 2).is_err());
+        assert!(PortalSet::new(portals, 1).is_ok());
+    }
+
+    #[test]
+    fn portals_are_tried_healthy_first_then_by_weight() {
+        let (light, heavy, medium) = (dead_portal(), dead_portal(), dead_portal());
+        let portals = PortalSet::new(
+            vec![
+                (light.clone(), String::new(), 1),
+                (heavy.clone(), String::new(), 3),
+                (medium.clone(), String::new(), 2),
+            ],
+            2,
+        )
+        .unwrap();
+
+        assert_eq!(
+            urls(portals.ranked()),
+            vec![heavy.clone(), medium.clone(), light.clone()]
+        );
+
+        record_health(portals.ranked()[0], false);
+        assert_eq!(
+            urls(portals.ranked()),
+            vec![medium.clone(), light.clone(), heavy.clone()]
+        );
+
+        record_health(portals.ranked()[2], true);
+        assert_eq!(urls(portals.ranked()), vec![heavy, medium, light]);
+    }
+
+    #[tokio::test]
+    async fn blobs_fail_over_to_the_next_portal_until_a_quorum_holds_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = live_portal(&dir.path().join("first")).await;
+        let second = live_portal(&dir.path().join("second")).await;
+        let dead = dead_portal();
+        // The dead portal weighs the most, so it is tried first
+        let portals = PortalSet::new(
+            vec![
+                (dead.clone(), String::new(), 3),
+                (first.clone(), String::new(), 2),
+                (second.clone(), String::new(), 1),
+            ],
+            2,
+        )
+        .unwrap();
+        let path = source_file(dir.path()).await;
+
+        let cid = portals.put(&path).await.unwrap();
+
+        let mut locations = portals.locations(&cid);
+        locations.sort();
+        let mut expected = vec![first.clone(), second.clone()];
+        expected.sort();
+        assert_eq!(locations, expected);
+        portals
+            .verify(&cid, &format!("{}.verify", path))
+            .await
+            .unwrap();
+        // Having failed, the dead portal is now tried last
+        assert_eq!(urls(portals.ranked()).last(), Some(&dead));
+
+        let fetched = format!("{}.fetched", path);
+        portals.get(&cid, &fetched).await.unwrap();
+        assert_eq!(
+            fs::read(&fetched).await.unwrap(),
+            fs::read(&path).await.unwrap()
+        );
+        assert!(portals.stat(&cid).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn stores_fail_without_a_quorum() {
+        let dir = tempfile::tempdir().unwrap();
+        let live = live_portal(&dir.path().join("live")).await;
+        let portals = PortalSet::new(
+            vec![(live, String::new(), 1), (dead_portal(), String::new(), 1)],
+            2,
+        )
+        .unwrap();
+        let path = source_file(dir.path()).await;
+
+        let error = portals.put(&path).await.unwrap_err();
+
+        assert!(
+            error.to_string().contains("on 1 of the 2 portals needed"),
+            "{}",
+            error
+        );
+    }
+}
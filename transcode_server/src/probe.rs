@@ -0,0 +1,390 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::process::Command;
+
+#[derive(Debug, Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<StreamInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FormatProbeOutput {
+    format: FormatInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct FormatInfo {
+    duration: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutputProbeOutput {
+    #[serde(default)]
+    streams: Vec<StreamInfo>,
+    format: OutputFormatInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutputFormatInfo {
+    #[serde(default)]
+    bit_rate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FrameProbeOutput {
+    #[serde(default)]
+    frames: Vec<FrameInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FrameInfo {
+    #[serde(default)]
+    side_data_list: Vec<HashMap<String, serde_json::Value>>,
+}
+
+// A single stream of the source as reported by `ffprobe -show_streams`
+#[derive(Debug, Clone, Deserialize)]
+pub struct StreamInfo {
+    #[serde(default)]
+    pub codec_name: String,
+    #[serde(default)]
+    pub codec_type: String,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub channels: Option<u32>,
+    #[serde(default)]
+    pub color_transfer: Option<String>,
+    #[serde(default)]
+    pub color_primaries: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferFunction {
+    // SMPTE ST 2084, used by HDR10
+    Pq,
+    // ARIB STD-B67 hybrid log-gamma
+    Hlg,
+}
+
+impl TransferFunction {
+    // The value ffmpeg expects for `-color_trc`
+    pub fn color_trc(&self) -> &'static str {
+        match self {
+            TransferFunction::Pq => "smpte2084",
+            TransferFunction::Hlg => "arib-std-b67",
+        }
+    }
+
+    pub fn dynamic_range(&self) -> &'static str {
+        match self {
+            TransferFunction::Pq => "hdr10",
+            TransferFunction::Hlg => "hlg",
+        }
+    }
+}
+
+// HDR signalling of the source's first video stream
+#[derive(Debug, Clone)]
+pub struct HdrInfo {
+    pub transfer: TransferFunction,
+    pub bt2020: bool,
+    pub mastering_display: Option<MasteringDisplay>,
+    // MaxCLL and MaxFALL as `max_content,max_average`
+    pub content_light_level: Option<String>,
+}
+
+// SMPTE ST 2086 mastering display colour volume: the CIE 1931 xy
+// chromaticities of the primaries and the white point, and the luminance
+// range in cd/m2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MasteringDisplay {
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub red: (f64, f64),
+    pub white_point: (f64, f64),
+    pub max_luminance: f64,
+    pub min_luminance: f64,
+}
+
+impl MasteringDisplay {
+    // The `G(x,y)B(x,y)R(x,y)WP(x,y)L(max,min)` notation of x265, with
+    // chromaticities in units of 0.00002 and luminance in 0.0001 cd/m2
+    pub fn x265(&self) -> String {
+        let chroma = |(x, y): (f64, f64)| {
+            format!(
+                "{},{}",
+                (x * 50000.0).round() as u64,
+                (y * 50000.0).round() as u64
+            )
+        };
+
+        format!(
+            "G({})B({})R({})WP({})L({},{})",
+            chroma(self.green),
+            chroma(self.blue),
+            chroma(self.red),
+            chroma(self.white_point),
+            (self.max_luminance * 10000.0).round() as u64,
+            (self.min_luminance * 10000.0).round() as u64,
+        )
+    }
+
+    // The same notation as SVT-AV1 takes it, in chromaticities and cd/m2
+    pub fn svt_av1(&self) -> String {
+        let chroma = |(x, y): (f64, f64)| format!("{:.5},{:.5}", x, y);
+
+        format!(
+            "G({})B({})R({})WP({})L({:.4},{:.4})",
+            chroma(self.green),
+            chroma(self.blue),
+            chroma(self.red),
+            chroma(self.white_point),
+            self.max_luminance,
+            self.min_luminance,
+        )
+    }
+}
+
+impl StreamInfo {
+    // ISO 639-2 language tag of the stream, "und" if the source has none
+    pub fn language(&self) -> String {
+        self.tags
+            .get("language")
+            .map(sanitize_filename::sanitize)
+            .filter(|language| !language.is_empty())
+            .unwrap_or_else(|| String::from("und"))
+    }
+}
+
+pub async fn probe_streams(file_path: &str) -> Result<Vec<StreamInfo>, anyhow::Error> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_streams", "-of", "json", file_path])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffprobe failed on {}: {}",
+            file_path,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let probe: ProbeOutput = serde_json::from_slice(&output.stdout)?;
+
+    Ok(probe.streams)
+}
+
+// Duration of the source in seconds
+pub async fn probe_duration(file_path: &str) -> Result<f64, anyhow::Error> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_format", "-of", "json", file_path])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffprobe failed on {}: {}",
+            file_path,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let probe: FormatProbeOutput = serde_json::from_slice(&output.stdout)?;
+
+    Ok(probe.format.duration.parse()?)
+}
+
+// The main stream of a transcoded output, as described in the media metadata
+#[derive(Debug, Default)]
+pub struct OutputInfo {
+    pub codec: String,
+    pub width: u32,
+    pub height: u32,
+    // Overall bitrate of the file in bits/s
+    pub bitrate: u64,
+    pub channels: u32,
+}
+
+// Reads the codec and size of the first video stream of an output, or of its
+// first stream if it has no video, and the bitrate of the whole file
+pub async fn probe_output(file_path: &str) -> Result<OutputInfo, anyhow::Error> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_streams",
+            "-show_format",
+            "-of",
+            "json",
+            file_path,
+        ])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffprobe failed on {}: {}",
+            file_path,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let probe: OutputProbeOutput = serde_json::from_slice(&output.stdout)?;
+    let stream = streams_of_type(&probe.streams, "video")
+        .first()
+        .copied()
+        .or_else(|| probe.streams.first())
+        .ok_or_else(|| anyhow::anyhow!("{} has no streams", file_path))?;
+
+    Ok(OutputInfo {
+        codec: stream.codec_name.clone(),
+        width: stream.width.unwrap_or_default(),
+        height: stream.height.unwrap_or_default(),
+        bitrate: probe
+            .format
+            .bit_rate
+            .and_then(|bit_rate| bit_rate.parse().ok())
+            .unwrap_or_default(),
+        channels: stream.channels.unwrap_or_default(),
+    })
+}
+
+// Returns the streams of the given type ("video", "audio" or "subtitle") in the
+// order ffmpeg numbers them for `-map 0:<type>:<n>`
+pub fn streams_of_type<'a>(streams: &'a [StreamInfo], codec_type: &str) -> Vec<&'a StreamInfo> {
+    streams
+        .iter()
+        .filter(|stream| stream.codec_type == codec_type)
+        .collect()
+}
+
+// Detects whether the first video stream is HDR (PQ or HLG transfer) and reads
+// the static HDR metadata carried on its first frame. A source whose frames
+// can't be read is still HDR, only without the static metadata.
+pub async fn probe_hdr(file_path: &str) -> Result<Option<HdrInfo>, anyhow::Error> {
+    let streams = probe_streams(file_path).await?;
+    let video = match streams_of_type(&streams, "video").first() {
+        Some(video) => (*video).clone(),
+        None => return Ok(None),
+    };
+
+    let transfer = match video.color_transfer.as_deref() {
+        Some("smpte2084") => TransferFunction::Pq,
+        Some("arib-std-b67") => TransferFunction::Hlg,
+        _ => return Ok(None),
+    };
+
+    let (mastering_display, content_light_level) = match probe_static_metadata(file_path).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            eprintln!("Error reading the HDR metadata of {}: {}", file_path, e);
+            (None, None)
+        }
+    };
+
+    Ok(Some(HdrInfo {
+        transfer,
+        bt2020: video.color_primaries.as_deref() == Some("bt2020"),
+        mastering_display,
+        content_light_level,
+    }))
+}
+
+// The mastering display and content light level in the side data of the
+// first frame of the first video stream
+async fn probe_static_metadata(
+    file_path: &str,
+) -> Result<(Option<MasteringDisplay>, Option<String>), anyhow::Error> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-read_intervals",
+            "%+#1",
+            "-show_frames",
+            "-show_entries",
+            "frame=side_data_list",
+            "-of",
+            "json",
+            file_path,
+        ])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffprobe failed to read the frames of {}: {}",
+            file_path,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let frames: FrameProbeOutput = serde_json::from_slice(&output.stdout)?;
+    let side_data: Vec<_> = frames
+        .frames
+        .into_iter()
+        .take(1)
+        .flat_map(|frame| frame.side_data_list)
+        .collect();
+
+    let mut mastering_display = None;
+    let mut content_light_level = None;
+    for data in &side_data {
+        let field = |key: &str| {
+            data.get(key)
+                .map(|value| match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                })
+                .unwrap_or_default()
+        };
+
+        match field("side_data_type").as_str() {
+            "Mastering display metadata" => {
+                let chroma = |key: &str| {
+                    (
+                        rational(&field(&format!("{}_x", key))),
+                        rational(&field(&format!("{}_y", key))),
+                    )
+                };
+                mastering_display = Some(MasteringDisplay {
+                    green: chroma("green"),
+                    blue: chroma("blue"),
+                    red: chroma("red"),
+                    white_point: chroma("white_point"),
+                    max_luminance: rational(&field("max_luminance")),
+                    min_luminance: rational(&field("min_luminance")),
+                });
+            }
+            "Content light level metadata" => {
+                content_light_level =
+                    Some(format!("{},{}", field("max_content"), field("max_average")));
+            }
+            _ => (),
+        }
+    }
+
+    Ok((mastering_display, content_light_level))
+}
+
+// Converts an ffprobe rational such as "34000/50000" to a number
+fn rational(rational: &str) -> f64 {
+    let mut parts = rational.splitn(2, '/');
+    let numerator: f64 = parts.next().and_then(|n| n.parse().ok()).unwrap_or(0.0);
+    let denominator: f64 = parts.next().and_then(|d| d.parse().ok()).unwrap_or(1.0);
+
+    if denominator == 0.0 {
+        return 0.0;
+    }
+
+    numerator / denominator
+}
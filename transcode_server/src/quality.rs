@@ -0,0 +1,85 @@
+use crate::probe::{probe_streams, streams_of_type};
+use crate::transcode::QualityScores;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::process::Command;
+
+#[derive(Debug, Deserialize)]
+struct VmafLog {
+    pooled_metrics: HashMap<String, PooledMetric>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PooledMetric {
+    mean: f64,
+}
+
+// Computes VMAF, SSIM and PSNR (luma) of `distorted` against `reference` with
+// ffmpeg's libvmaf filter. The distorted video is upscaled to the reference
+// resolution first, as VMAF models expect.
+pub async fn measure_quality(
+    reference: &str,
+    distorted: &str,
+    log_path: &str,
+) -> Result<QualityScores, anyhow::Error> {
+    let streams = probe_streams(reference).await?;
+    let video = streams_of_type(&streams, "video")
+        .first()
+        .map(|video| (*video).clone())
+        .ok_or_else(|| anyhow::anyhow!("No video stream in {}", reference))?;
+    let (width, height) = match (video.width, video.height) {
+        (Some(width), Some(height)) => (width, height),
+        _ => return Err(anyhow::anyhow!("Unknown resolution of {}", reference)),
+    };
+
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+
+    let filter = format!(
+        "[0:v]scale={w}:{h}:flags=bicubic,format=yuv420p,setpts=PTS-STARTPTS[dist];\
+         [1:v]scale={w}:{h}:flags=bicubic,format=yuv420p,setpts=PTS-STARTPTS[ref];\
+         [dist][ref]libvmaf=feature=name=psnr|name=float_ssim:log_fmt=json:log_path={log}:n_threads={threads}",
+        w = width,
+        h = height,
+        log = log_path,
+        threads = threads,
+    );
+
+    let output = Command::new("ffmpeg")
+        .args([
+            "-i",
+            distorted,
+            "-i",
+            reference,
+            "-lavfi",
+            filter.as_str(),
+            "-f",
+            "null",
+            "-",
+        ])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "libvmaf failed on {}: {}",
+            distorted,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let log: VmafLog = serde_json::from_slice(&tokio::fs::read(log_path).await?)?;
+    let mean = |metric: &str| {
+        log.pooled_metrics
+            .get(metric)
+            .map(|pooled| pooled.mean)
+            .ok_or_else(|| anyhow::anyhow!("libvmaf log is missing {}", metric))
+    };
+
+    Ok(QualityScores {
+        vmaf: mean("vmaf")?,
+        ssim: mean("float_ssim")?,
+        psnr: mean("psnr_y")?,
+    })
+}
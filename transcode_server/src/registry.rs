@@ -0,0 +1,334 @@
+/*
+ * registry.rs
+ *
+ * Publishes the media metadata of a job to an S5 registry entry, so players
+ * can resolve the latest transcode of a video by a public key instead of a
+ * CID that changes every time it is transcoded.
+ *
+ * Each tenant has an Ed25519 seed in REGISTRY_KEYS, as comma separated
+ * `tenant|hex seed` entries, and the key of a video is derived from the seed
+ * and the registry name the request gives the video. Callers authenticate as
+ * a tenant with its token in TENANT_TOKENS, as `tenant|token` entries, and
+ * can only publish under the keys of that tenant. An entry holds at most
+ * 64 bytes, too few for an encrypted CID, so its data is the S5 magic byte
+ * and the plaintext CID of the metadata document, which is stored on the
+ * portals unencrypted. Anyone with the public key can therefore play the
+ * video, so only requests that set publish_plaintext are published.
+ */
+
+use crate::job;
+use crate::portals::PortalSet;
+use crate::source::{fetch_decrypted, plaintext_cid};
+use crate::storage::{cid_to_string, StorageBackend};
+use crate::transcode::TranscodeRequest;
+use anyhow::anyhow;
+use base64::{engine::general_purpose, Engine as _};
+use dotenv::var;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Mutex as AsyncMutex;
+
+const RECORD_TYPE_REGISTRY_ENTRY: u8 = 0x07;
+const MKEY_ED25519: u8 = 0xed;
+const REGISTRY_S5_MAGIC_BYTE: u8 = 0x5a;
+const MAX_DATA_SIZE: usize = 64;
+
+// The last revision published under each public key, in case the portals
+// have not caught up with it. Its lock is held from choosing the next
+// revision until that is published, so concurrent jobs publishing under the
+// same key don't sign the same revision.
+static LAST_REVISIONS: Lazy<Mutex<HashMap<String, LastRevision>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+type LastRevision = Arc<AsyncMutex<Option<u64>>>;
+
+// A signed registry entry. `pk` is the Ed25519 public key prefixed with 0xed.
+#[derive(Debug, Clone)]
+pub struct RegistryEntry {
+    pub pk: Vec<u8>,
+    pub revision: u64,
+    pub data: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+// A registry entry as the portals send and receive it, in base64url
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegistryEntryJson {
+    pk: String,
+    revision: u64,
+    data: String,
+    signature: String,
+}
+
+impl RegistryEntry {
+    fn sign(key: &SigningKey, revision: u64, data: Vec<u8>) -> Self {
+        let signature = key.sign(&signed_message(revision, &data));
+
+        RegistryEntry {
+            pk: public_key(key),
+            revision,
+            data,
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    // Whether the entry is signed by the key it names
+    pub fn is_valid(&self) -> bool {
+        let key = match self.pk.split_first() {
+            Some((&MKEY_ED25519, key)) => key,
+            _ => return false,
+        };
+        let key = match <[u8; 32]>::try_from(key)
+            .ok()
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+        {
+            Some(key) => key,
+            None => return false,
+        };
+
+        match Signature::from_slice(&self.signature) {
+            Ok(signature) => key
+                .verify(&signed_message(self.revision, &self.data), &signature)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn to_json(&self) -> RegistryEntryJson {
+        RegistryEntryJson {
+            pk: encode(&self.pk),
+            revision: self.revision,
+            data: encode(&self.data),
+            signature: encode(&self.signature),
+        }
+    }
+
+    pub fn from_json(json: RegistryEntryJson) -> Result<Self, anyhow::Error> {
+        Ok(RegistryEntry {
+            pk: decode(&json.pk)?,
+            revision: json.revision,
+            data: decode(&json.data)?,
+            signature: decode(&json.signature)?,
+        })
+    }
+}
+
+// What the signature of an entry covers: the record type, the revision
+// (little-endian), then the data with its length
+fn signed_message(revision: u64, data: &[u8]) -> Vec<u8> {
+    let mut message = vec![RECORD_TYPE_REGISTRY_ENTRY];
+    message.extend(revision.to_le_bytes());
+    message.push(data.len() as u8);
+    message.extend(data);
+
+    message
+}
+
+fn public_key(key: &SigningKey) -> Vec<u8> {
+    let mut pk = vec![MKEY_ED25519];
+    pk.extend(key.verifying_key().as_bytes());
+
+    pk
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn decode(text: &str) -> Result<Vec<u8>, anyhow::Error> {
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(text)
+        .map_err(|e| anyhow!("Invalid base64url {}: {}", text, e))
+}
+
+// The tenant whose token in TENANT_TOKENS is `token`, if any
+pub fn authenticated_tenant(token: &str) -> Option<String> {
+    let tokens = var("TENANT_TOKENS").unwrap_or_default();
+    // Compared by hash, which takes the same time however much of a token matches
+    let token = blake3::hash(token.as_bytes());
+
+    tokens
+        .split(',')
+        .map(str::trim)
+        .filter_map(|entry| entry.split_once('|'))
+        .find(|(_, tenant_token)| {
+            !tenant_token.is_empty() && blake3::hash(tenant_token.as_bytes()) == token
+        })
+        .map(|(tenant, _)| tenant.to_string())
+}
+
+// The key the video `name` of `tenant` is published under
+pub fn signing_key(tenant: &str, name: &str) -> Result<SigningKey, anyhow::Error> {
+    let keys = var("REGISTRY_KEYS").unwrap_or_default();
+    let seed = keys
+        .split(',')
+        .map(str::trim)
+        .filter_map(|entry| entry.split_once('|'))
+        .find(|(key_tenant, _)| *key_tenant == tenant)
+        .map(|(_, seed)| seed)
+        .ok_or_else(|| anyhow!("No registry key is configured for tenant {}", tenant))?;
+
+    let seed: [u8; 32] = hex::decode(seed)
+        .ok()
+        .and_then(|seed| seed.try_into().ok())
+        .ok_or_else(|| anyhow!("Registry key of tenant {} is not 32 hex bytes", tenant))?;
+
+    // Every video gets a key of its own, so each has its own entry
+    Ok(SigningKey::from_bytes(
+        blake3::keyed_hash(&seed, name.as_bytes()).as_bytes(),
+    ))
+}
+
+// Publishes the media metadata of `job_id`, whose outputs are in `storage`,
+// under the registry name of `request`, returning the public key (base64url)
+// and the revision published
+pub async fn publish(
+    job_id: &str,
+    request: &TranscodeRequest,
+    storage: &dyn StorageBackend,
+) -> Result<(String, u64), anyhow::Error> {
+    if !request.publish_plaintext {
+        return Err(anyhow!(
+            "Publishing stores the media metadata unencrypted, which the request does not allow"
+        ));
+    }
+
+    let key = signing_key(&request.tenant, &request.registry_name)?;
+    let pk = public_key(&key);
+
+    let metadata_cid = job::get_job(job_id)
+        .await
+        .and_then(|job| {
+            job.artifacts
+                .into_iter()
+                .find(|artifact| artifact.kind == "metadata")
+        })
+        .map(|artifact| artifact.cid)
+        .ok_or_else(|| anyhow!("Job {} has no media metadata to publish", job_id))?;
+
+    let portals = PortalSet::shared()?;
+    let cid = store_plaintext(&portals, storage, &metadata_cid, job_id).await?;
+
+    let mut data = vec![REGISTRY_S5_MAGIC_BYTE];
+    data.extend(cid);
+    if data.len() > MAX_DATA_SIZE {
+        return Err(anyhow!(
+            "Registry entry data of {} bytes is too long",
+            data.len()
+        ));
+    }
+
+    let pk_string = encode(&pk);
+    let last_revision = LAST_REVISIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(pk_string.clone())
+        .or_default()
+        .clone();
+    let mut last_published = last_revision.lock().await;
+
+    let latest = portals
+        .registry_entry(&pk)
+        .await?
+        .map(|entry| entry.revision);
+    let revision = latest
+        .max(*last_published)
+        .map_or(0, |revision| revision + 1);
+
+    let entry = RegistryEntry::sign(&key, revision, data);
+    portals.publish_registry_entry(&entry).await?;
+    *last_published = Some(revision);
+
+    Ok((pk_string, revision))
+}
+
+// Makes sure the portals hold the plaintext of the encrypted blob
+// `metadata_cid`, decrypting a copy from `storage` if not, and returns the
+// plaintext CID
+async fn store_plaintext(
+    portals: &PortalSet,
+    storage: &dyn StorageBackend,
+    metadata_cid: &str,
+    job_id: &str,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let cid = plaintext_cid(metadata_cid)?;
+    let cid_string = cid_to_string(&cid);
+    if portals.stat(&cid_string).await?.is_some() {
+        return Ok(cid);
+    }
+
+    let path = format!("./temp/to/transcode/{}_metadata_published.json", job_id);
+    fetch_decrypted(storage, metadata_cid, &path).await?;
+    let stored = portals.put(&path).await;
+    let _ = std::fs::remove_file(&path);
+
+    let stored = stored?;
+    if stored != cid_string {
+        return Err(anyhow!(
+            "Portals stored the metadata as {} instead of {}",
+            stored,
+            cid_string
+        ));
+    }
+
+    Ok(cid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_entry() -> RegistryEntry {
+        let key = SigningKey::from_bytes(&[9; 32]);
+        let mut data = vec![REGISTRY_S5_MAGIC_BYTE];
+        data.extend([3; 36]);
+
+        RegistryEntry::sign(&key, 5, data)
+    }
+
+    #[test]
+    fn signed_entries_are_valid() {
+        let entry = signed_entry();
+
+        assert_eq!(entry.pk[0], MKEY_ED25519);
+        assert!(entry.is_valid());
+    }
+
+    #[test]
+    fn changed_entries_are_not_valid() {
+        let mut entry = signed_entry();
+        entry.revision += 1;
+        assert!(!entry.is_valid());
+
+        let mut entry = signed_entry();
+        entry.data[1] ^= 1;
+        assert!(!entry.is_valid());
+
+        let mut entry = signed_entry();
+        entry.pk = public_key(&SigningKey::from_bytes(&[8; 32]));
+        assert!(!entry.is_valid());
+
+        let mut entry = signed_entry();
+        entry.pk[0] = 0x00;
+        assert!(!entry.is_valid());
+
+        let mut entry = signed_entry();
+        entry.signature.pop();
+        assert!(!entry.is_valid());
+    }
+
+    #[test]
+    fn entries_stay_valid_through_json() {
+        let entry = signed_entry();
+        let json = serde_json::to_string(&entry.to_json()).unwrap();
+        let parsed = RegistryEntry::from_json(serde_json::from_str(&json).unwrap()).unwrap();
+
+        assert_eq!(parsed.pk, entry.pk);
+        assert_eq!(parsed.revision, entry.revision);
+        assert_eq!(parsed.data, entry.data);
+        assert!(parsed.is_valid());
+    }
+}
@@ -1,38 +1,40 @@
+use crate::hash_bytes_to_cid;
+use crate::storage::{cid_to_string, parse_blob_cid};
+use anyhow::anyhow;
 use base64::{engine::general_purpose, Engine as _};
-use dotenv::var;
+use once_cell::sync::Lazy;
 use std::fs::File;
-use std::io::copy;
 use std::io::{BufReader, Read};
-use std::result::Result::{Err, Ok};
+use std::result::Result::Ok;
 use std::{collections::HashMap, fs, path::Path};
-use tus_client::Client;
+use tus_client::{AsyncClient, FileUploadStore, RetryPolicy, TusServer, UploadOptions};
 
-pub fn download_file(url: &str, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Create a new client with default configuration
-    let client = reqwest::Client::new();
+// Number of partial uploads a file is split into for tus concatenation
+const UPLOAD_PARTS: usize = 4;
 
-    // Send a GET request to the download URL
-    let mut response = client.get(url).send()?;
+// Upload URLs of unfinished uploads, so they are resumed after a restart.
+// Uploads to several portals run at once, so they share the store, whose lock
+// keeps them from overwriting each other's entries.
+const UPLOAD_STORE_PATH: &str = "./temp/tus_uploads";
 
-    // Save the response body to the specified file
-    let mut file = File::create(path)?;
-    copy(&mut response, &mut file)?;
+static UPLOAD_STORE: Lazy<FileUploadStore> = Lazy::new(|| FileUploadStore::new(UPLOAD_STORE_PATH));
 
-    Ok(())
-}
-
-pub fn upload_video(path: &str) -> Result<Vec<u8>, anyhow::Error> {
-    let portal_url = var("PORTAL_URL").unwrap();
-    let token = var("TOKEN").unwrap();
-
-    let client = Client::new(reqwest::Client::new()).with_auth_token(token);
+pub async fn upload_video(
+    portal_url: &str,
+    token: &str,
+    path: &str,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let client = AsyncClient::new(reqwest_async::Client::new())
+        .with_auth_token(token.to_string())
+        .with_upload_store(&*UPLOAD_STORE)
+        .with_retry_policy(RetryPolicy::new());
 
     let path = Path::new(path);
-    let metadata = fs::metadata(path).expect("Failed to read metadata");
+    let metadata = fs::metadata(path)?;
     let file_size = metadata.len();
     println!("file_size = {}", &file_size);
 
-    let hash = hash_blake3_file(String::from(path.to_str().unwrap())).unwrap();
+    let hash = hash_blake3_file(path.to_string_lossy().to_string())?;
 
     let mut metadata = HashMap::new();
 
@@ -48,28 +50,86 @@ pub fn upload_video(path: &str) -> Result<Vec<u8>, anyhow::Error> {
     println!("path = {}", &path.display());
     println!("portal_url = {}", &portal_url);
 
-    let upload_url = match client.create_with_metadata(
-        &format!("{}{}", portal_url, "/s5/upload/tus"),
-        path,
-        metadata,
-    ) {
-        Ok(url) => url,
-        Err(e) => {
-            eprintln!("Failed to create file on server: {}", e);
-            String::new()
-        }
-    };
-
-    println!("upload_url = {}", &upload_url);
+    // Renditions are sent as several partial uploads in parallel when the
+    // portal supports tus concatenation
     let chunk_size: usize = 1024 * 1024 * 5;
-    match client.upload_with_chunk_size(&upload_url, path, chunk_size) {
-        Ok(_) => (),
-        Err(e) => eprintln!("Failed to upload file to server: {}", e),
-    }
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let options = UploadOptions::new()
+        .with_chunk_size(chunk_size)
+        .with_progress(move |progress| {
+            println!(
+                "Uploaded {} of {} bytes of {} ({:.1} MiB/s)",
+                progress.bytes_sent,
+                file_size,
+                file_name,
+                progress.bytes_per_second / (1024.0 * 1024.0)
+            )
+        });
+    let upload_url = client
+        .upload_concurrently(
+            &format!("{}{}", portal_url, "/s5/upload/tus"),
+            path,
+            metadata,
+            UPLOAD_PARTS,
+            &options,
+        )
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "Failed to upload {} to {}: {}",
+                path.display(),
+                portal_url,
+                e
+            )
+        })?;
+    println!("upload_url = {}", &upload_url);
 
     Ok(cid)
 }
 
+// A tus server standing in for an S5 portal at `portal_url`, storing uploads
+// in `dir`. Uploads go to /s5/upload/tus as on a portal. Finished ones are
+// hashed like a portal would and linked into `dir`/blobs by their CID, from
+// where they are served at /s5/blob/{cid}.
+pub fn local_portal(dir: &str, portal_url: &str) -> Result<TusServer, anyhow::Error> {
+    let blob_dir = Path::new(dir).join("blobs");
+    fs::create_dir_all(&blob_dir)?;
+    let link_dir = blob_dir.clone();
+
+    Ok(
+        TusServer::new(dir, format!("{}/s5/upload/tus", portal_url))?
+            .with_on_finish(move |upload| {
+                if let Err(e) = link_blob(&upload.path, &link_dir) {
+                    eprintln!("Failed to store {} as a blob: {}", upload.upload_url, e);
+                }
+                HashMap::new()
+            })
+            .with_files(move |path| {
+                let cid = path.strip_prefix("/s5/blob/")?;
+                parse_blob_cid(cid).ok()?;
+                Some(blob_dir.join(cid)).filter(|blob_path| blob_path.is_file())
+            }),
+    )
+}
+
+fn link_blob(path: &Path, blob_dir: &Path) -> Result<(), anyhow::Error> {
+    let hash = hash_blake3_file(path.to_string_lossy().to_string())?;
+    let cid = cid_to_string(&hash_bytes_to_cid(
+        hash.as_bytes().to_vec(),
+        fs::metadata(path)?.len(),
+    ));
+
+    let blob_path = blob_dir.join(cid);
+    if !blob_path.exists() {
+        fs::hard_link(path, blob_path)?;
+    }
+
+    Ok(())
+}
+
 pub fn hash_blake3_file(path: String) -> Result<blake3::Hash, anyhow::Error> {
     let input = File::open(path)?;
     let reader = BufReader::new(input);
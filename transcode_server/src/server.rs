@@ -11,45 +11,102 @@
  */
 
 mod s5;
-use s5::{download_file, upload_video};
+
+mod cache;
+
+mod chunked;
+use chunked::{encode_rendition_chunked, split_source};
+
+mod encode;
+use encode::{encode_rendition, ColourHandling, RENDITIONS};
 
 mod encrypt_file;
 use encrypt_file::encrypt_file_xchacha20;
 
-use tonic::{transport::Server, Code, Request, Response, Status};
+mod job;
+use job::JobStatus;
+
+mod ingest;
+
+mod media_metadata;
+use media_metadata::media_metadata;
+
+mod probe;
+use probe::{probe_duration, probe_hdr, probe_output, probe_streams};
+
+mod per_title;
+use per_title::select_crf;
+
+mod quality;
+use quality::measure_quality;
+
+mod portals;
+
+mod registry;
+
+mod source;
+use source::{download_source, source_cid_hash, DownloadOptions};
+
+mod storage;
+use storage::StorageBackend;
+
+mod tracks;
+use tracks::{extract_audio_tracks, extract_subtitles, extract_thumbnail, select_audio_streams};
+
+use tokio_stream::{Stream, StreamExt};
+use tonic::{transport::Server, Code, Request, Response, Status, Streaming};
 
 use async_trait::async_trait;
 use once_cell::sync::Lazy;
-use s5::hash_blake3_file;
+use s5::{hash_blake3_file, local_portal};
 use sanitize_filename::sanitize;
-use std::process::Command;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
 use transcode::{
     transcode_service_server::{TranscodeService, TranscodeServiceServer},
-    GetCidRequest, GetCidResponse, TranscodeRequest, TranscodeResponse,
+    upload_chunk, Artifact, GetCidRequest, GetCidResponse, GetJobResultRequest,
+    GetJobResultResponse, QualityScores, TranscodeRequest, TranscodeResponse, UploadChunk,
 };
 mod encrypted_cid;
 use base64::{engine::general_purpose, Engine as _};
 use encrypted_cid::create_encrypted_cid;
 
-use std::path::Path;
-
-use dotenv::dotenv;
+use dotenv::{dotenv, var};
 
 static VIDEO_CID: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::from("")));
 static VIDEO_CID1: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::from("")));
 static VIDEO_CID2: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::from("")));
 static PATH_TO_FILE: &str = "path/to/file/";
 
+const CID_TYPE_ENCRYPTED: u8 = 0xae;
+const ENCRYPTION_ALGORITHM: u8 = 0xa6;
+const CHUNK_SIZE_AS_POWER_OF_2: u8 = 18;
+const PADDING: u32 = 0;
+
+// Message of jobs whose outputs were those of an earlier identical job
+const REUSED_MESSAGE: &str = "Reused the outputs of an earlier transcode of the same source";
+
+// Where the local stand-in portal stores uploads
+const LOCAL_PORTAL_DIR: &str = "./temp/portal";
+
+// A queued transcoding task: the job ID and the request it was created from
+type TranscodeTask = (String, TranscodeRequest);
+
 // The transcoding task receiver, which receives transcoding tasks from the gRPC server
-async fn transcode_task_receiver(receiver: Arc<Mutex<mpsc::Receiver<(String, bool)>>>) {
-    while let Some((file_path, is_gpu)) = receiver.lock().await.recv().await {
-        println!("Transcoding video: {}", &file_path);
-        if let Err(e) = transcode_video(&file_path, is_gpu).await {
-            eprintln!("Failed to transcode {}: {}", &file_path, e);
+async fn transcode_task_receiver(receiver: Arc<Mutex<mpsc::Receiver<TranscodeTask>>>) {
+    while let Some((job_id, request)) = receiver.lock().await.recv().await {
+        println!("Transcoding video: {}", &request.url);
+        job::set_status(&job_id, JobStatus::Running, "").await;
+
+        match transcode_video(&job_id, &request).await {
+            Ok(response) => finish_job(&job_id, &request, &response.get_ref().message).await,
+            Err(e) => {
+                eprintln!("Failed to transcode {}: {}", &request.url, e);
+                job::set_status(&job_id, JobStatus::Failed, e.message()).await;
+            }
         }
+        ingest::remove_upload(&request.url).await;
     }
 }
 
@@ -85,8 +142,139 @@ pub fn hash_bytes_to_cid(hash: Vec<u8>, file_size: u64) -> Vec<u8> {
     bytes
 }
 
-// Transcodes a video file to 2160p and 1080p h264 av1 formats using ffmpeg
-async fn transcode_video(url: &str, is_gpu: bool) -> Result<Response<TranscodeResponse>, Status> {
+// Encrypts `file_path_ue` to `file_path`, stores the encrypted file in
+// `storage` and returns its encrypted CID and where the blob is held
+async fn encrypt_and_upload(
+    storage: &dyn StorageBackend,
+    file_path_ue: &str,
+    file_path: &str,
+) -> Result<(String, Vec<String>), anyhow::Error> {
+    let encryption_key =
+        encrypt_file_xchacha20(file_path_ue.to_string(), file_path.to_string(), 0)?;
+
+    let blob_cid = storage.put(file_path).await?;
+    println!("Stored {} in {} as {}", file_path, storage.name(), blob_cid);
+
+    // Only report the CID once the stored blob is known to be intact
+    storage
+        .verify(&blob_cid, &format!("{}.verify", file_path))
+        .await?;
+    println!("Verified {} in {}", blob_cid, storage.name());
+
+    let hash = hash_blake3_file(file_path_ue.to_string())?;
+    let hash_encrypted = hash_blake3_file(file_path.to_string())?;
+
+    let mut encrypted_blob_hash = vec![0x1f];
+    encrypted_blob_hash.extend(hash_encrypted.as_bytes());
+
+    let file_size = std::fs::metadata(file_path_ue)?.len();
+    let cid_ue = hash_bytes_to_cid(hash.as_bytes().to_vec(), file_size);
+
+    let encrypted_cid_bytes = create_encrypted_cid(
+        CID_TYPE_ENCRYPTED,
+        ENCRYPTION_ALGORITHM,
+        CHUNK_SIZE_AS_POWER_OF_2,
+        encrypted_blob_hash,
+        encryption_key,
+        PADDING,
+        cid_ue,
+    );
+
+    Ok((
+        format!("u{}", bytes_to_base64url(&encrypted_cid_bytes)),
+        storage.locations(&blob_cid),
+    ))
+}
+
+// Extracts the audio and subtitle tracks selected by the request, then encrypts
+// and uploads each one as its own artifact of the job
+async fn transcode_tracks(
+    job_id: &str,
+    request: &TranscodeRequest,
+    storage: &dyn StorageBackend,
+    file_path: &str,
+    file_name: &str,
+) -> Result<(), anyhow::Error> {
+    let streams = probe_streams(file_path).await?;
+
+    let audio_streams =
+        select_audio_streams(&streams, &request.audio_tracks, request.all_audio_tracks);
+    let mut tracks = extract_audio_tracks(file_path, file_name, &audio_streams).await?;
+
+    if request.extract_subtitles {
+        tracks.extend(extract_subtitles(file_path, file_name, &streams).await?);
+    }
+
+    for track in tracks {
+        let (cid, locations) =
+            encrypt_and_upload(storage, &track.file_path_ue, &track.file_path).await?;
+        println!("{} cid: {}", track.name, &cid);
+
+        job::add_artifact(
+            job_id,
+            Artifact {
+                kind: track.kind.to_string(),
+                name: track.name,
+                language: track.language,
+                cid,
+                locations,
+                ..described_output(&track.file_path_ue).await
+            },
+        )
+        .await;
+    }
+
+    Ok(())
+}
+
+// Marks the job done, after publishing its media metadata to the registry if
+// the request asks to
+async fn finish_job(job_id: &str, request: &TranscodeRequest, message: &str) {
+    if !request.registry_name.is_empty() {
+        let published = match storage::backend(&request.storage_backend) {
+            Ok(storage) => registry::publish(job_id, request, storage.as_ref()).await,
+            Err(e) => Err(e),
+        };
+
+        match published {
+            Ok((registry_key, revision)) => {
+                println!(
+                    "Published job {} as revision {} of {}",
+                    job_id, revision, registry_key
+                );
+                job::set_published(job_id, &registry_key, revision).await;
+            }
+            Err(e) => {
+                eprintln!("Failed to publish job {}: {}", job_id, e);
+                job::set_status(
+                    job_id,
+                    JobStatus::Failed,
+                    &format!("Transcoded but failed to publish the media metadata: {}", e),
+                )
+                .await;
+                return;
+            }
+        }
+    }
+
+    job::set_status(job_id, JobStatus::Done, message).await;
+}
+
+// Transcodes a video file to 2160p and 1080p av1 renditions using ffmpeg
+async fn transcode_video(
+    job_id: &str,
+    request: &TranscodeRequest,
+) -> Result<Response<TranscodeResponse>, Status> {
+    let url = request.url.as_str();
+    let is_gpu = request.is_gpu;
+
+    let storage = storage::backend(&request.storage_backend).map_err(|e| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Failed to configure storage: {}", e),
+        )
+    })?;
+
     println!("Downloading video from: {}", url);
 
     let mut video_cid = VIDEO_CID.lock().await;
@@ -95,356 +283,500 @@ async fn transcode_video(url: &str, is_gpu: bool) -> Result<Response<TranscodeRe
     let file_name = sanitize(url);
     let file_path = String::from(PATH_TO_FILE.to_owned() + &file_name);
 
-    match download_file(url, file_path.as_str()) {
-        Ok(()) => println!("File downloaded successfully"),
-        Err(e) => eprintln!("Error downloading file: {}", e),
+    let download_options = DownloadOptions::from_env(&request.source_hash)
+        .map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?;
+
+    // Skip the download too when the source is known by its hash
+    let known_key = known_source_hash(request, None, download_options.expected_hash())
+        .map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?
+        .map(|hash| cache::cache_key(&hash, storage.name(), request));
+    if let Some(key) = &known_key {
+        if reuse_outputs(job_id, key, storage.as_ref()).await {
+            return Ok(reused_response(job_id));
+        }
     }
 
+    if let Err(e) = download_source(url, &file_path, &download_options).await {
+        eprintln!("Error downloading file: {}", e);
+
+        return Err(Status::new(
+            Code::FailedPrecondition,
+            format!("Failed to download source {}: {}", url, e),
+        ));
+    }
+    println!("File downloaded successfully");
+
+    let cache_key = match known_key {
+        Some(key) => key,
+        None => {
+            let hash = hash_blake3_file(file_path.clone()).map_err(|e| {
+                Status::new(Code::Internal, format!("Failed to hash source: {}", e))
+            })?;
+            let key = cache::cache_key(&hash, storage.name(), request);
+            if reuse_outputs(job_id, &key, storage.as_ref()).await {
+                return Ok(reused_response(job_id));
+            }
+            key
+        }
+    };
+
     println!("Transcoding video: {}", &file_path);
     println!("is_gpu = {}", &is_gpu);
 
-    let mut encryption_key1: Vec<u8> = Vec::new();
-    let mut encryption_key2: Vec<u8> = Vec::new();
-
-    if is_gpu {
-        println!("GPU transcoding");
-        let mut cmd = Command::new("ffmpeg");
-        cmd.args([
-            "-i",
-            file_path.as_str(),
-            "-c:v",
-            "av1_nvenc",
-            "-b:v",
-            "15M",
-            "-c:a",
-            "libopus",
-            "-b:a",
-            "192k",
-            "-ac",
-            "2",
-            "-vf",
-            "scale=3840:2160",
-            "-y",
-            format!("./temp/to/transcode/{}_2160p_ue.mp4", &file_name).as_str(),
-        ]);
-        let output = cmd.output().expect("Failed to execute command");
-        println!("{:?}", output);
-
-        match encrypt_file_xchacha20(
-            format!("./temp/to/transcode/{}_2160p_ue.mp4", file_name),
-            format!("./temp/to/transcode/{}_2160p.mp4", file_name),
-            0,
-        ) {
-            Ok(bytes) => {
-                // Encryption succeeded, and `bytes` contains the encrypted data
-                // Add your success handling code here
-                encryption_key1 = bytes;
-                println!("Encryption succeeded");
-            }
-            Err(error) => {
-                // Encryption failed
-                // Handle the error here
-                eprintln!("Encryption error: {:?}", error);
-                // Optionally, you can return an error or perform error-specific handling
-            }
+    let hdr = match probe_hdr(&file_path).await {
+        Ok(hdr) => hdr,
+        Err(e) => {
+            eprintln!("Error probing source colour metadata: {}", e);
+            None
         }
+    };
 
-        println!("Before2: let cmd = format!(");
-
-        let mut cmd2 = Command::new("ffmpeg");
-        cmd2.args([
-            "-i",
-            file_path.as_str(),
-            "-c:v",
-            "av1_nvenc",
-            "-b:v",
-            "5M",
-            "-c:a",
-            "libopus",
-            "-b:a",
-            "96k",
-            "-ac",
-            "2",
-            "-vf",
-            "scale=1920:1080",
-            "-y",
-            format!("./temp/to/transcode/{}_1080p_ue.mp4", &file_name).as_str(),
-        ]);
-
-        let output2 = cmd2.output().expect("Failed to execute command");
-        println!("{:?}", output2);
-
-        match encrypt_file_xchacha20(
-            format!("./temp/to/transcode/{}_1080p_ue.mp4", file_name),
-            format!("./temp/to/transcode/{}_1080p.mp4", file_name),
-            0,
-        ) {
-            Ok(bytes) => {
-                // Encryption succeeded, and `bytes` contains the encrypted data
-                // Add your success handling code here
-                encryption_key2 = bytes;
-                println!("Encryption succeeded");
-            }
-            Err(error) => {
-                // Encryption failed
-                // Handle the error here
-                eprintln!("Encryption error: {:?}", error);
-                // Optionally, you can return an error or perform error-specific handling
+    // Every rendition encodes the same chunks, so the source is split once
+    let chunks = if request.chunked && !is_gpu {
+        let work_prefix = format!("./temp/to/transcode/{}", job_id);
+        match split_source(&file_path, &work_prefix).await {
+            Ok(chunks) => Some(chunks),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("Failed to split source into chunks: {}", e),
+                ));
             }
         }
     } else {
-        println!("CPU transcoding");
-
-        let mut cmd = Command::new("ffmpeg");
-        cmd.args([
-            "-i",
-            file_path.as_str(),
-            "-c:v",
-            "libaom-av1", // use libaom-av1 encoder for AV1
-            "-cpu-used",
-            "4", // set encoding speed to 4 (range 0-8, lower is slower)
-            "-b:v",
-            "0", // use constant quality mode
-            "-crf",
-            "30", // set quality level to 30 (range 0-63, lower is better)
-            "-c:a",
-            "libopus", // use libopus encoder for audio
-            "-b:a",
-            "128k",
-            "-ac",
-            "2",
-            "-s",
-            "hd1080",
-            "-y",
-            format!("./temp/to/transcode/{}_2160p.mp4", &file_name).as_str(), // change output extension to .av1
-        ]);
-        let output = cmd.output().expect("Failed to execute command");
-        println!("{:?}", output);
-
-        println!("Before2: let cmd = format!(");
-
-        let mut cmd2 = Command::new("ffmpeg");
-        cmd2.args([
-            "-i",
-            file_path.as_str(),
-            "-c:v",
-            "libaom-av1", // use libaom-av1 encoder for AV1
-            "-cpu-used",
-            "4", // set encoding speed to 4 (range 0-8, lower is slower)
-            "-b:v",
-            "0", // use constant quality mode
-            "-crf",
-            "30", // set quality level to 30 (range 0-63, lower is better)
-            "-c:a",
-            "libopus", // use libopus encoder for audio
-            "-b:a",
-            "128k",
-            "-ac",
-            "2",
-            "-s",
-            "hd720",
-            "-y",
-            format!("./temp/to/transcode/{}_1080p.mp4", &file_name).as_str(), // change output extension to .av1
-        ]);
-
-        let output2 = cmd2.output().expect("Failed to execute command");
-        println!("{:?}", output2);
-    }
-
-    let file_path = format!("./temp/to/transcode/{}_2160p_ue.mp4", file_name);
-    let file_path_encrypted = format!("./temp/to/transcode/{}_2160p.mp4", file_name);
-
-    let hash_result = hash_blake3_file(file_path.clone());
-    let hash_result_encrypted = hash_blake3_file(file_path_encrypted);
+        if request.chunked {
+            println!("Chunked encoding only applies to CPU transcoding");
+        }
+        None
+    };
 
-    let cid_type_encrypted: u8 = 0xae; // replace with your actual cid type encrypted
-    let encryption_algorithm: u8 = 0xa6; // replace with your actual encryption algorithm
-    let chunk_size_as_power_of_2: u8 = 18; // replace with your actual chunk size as power of 2
-    let padding: u32 = 0; // replace with your actual padding
+    for rendition in RENDITIONS.iter() {
+        let colour = ColourHandling::for_rendition(request, rendition, hdr.as_ref());
+        println!(
+            "Transcoding {} rendition ({})",
+            rendition.resolution,
+            colour.dynamic_range()
+        );
 
-    // Upload the transcoded videos to storage
-    let mut response: TranscodeResponse;
-    match upload_video(format!("./temp/to/transcode/{}_2160p.mp4", file_name).as_str()) {
-        Ok(cid) => {
-            println!(
-                "******************************************2160p cid: {:?}",
-                &cid
-            );
+        let file_path_ue = format!(
+            "./temp/to/transcode/{}_{}_ue.mp4",
+            file_name, rendition.resolution
+        );
+        let file_path_encrypted = format!(
+            "./temp/to/transcode/{}_{}.mp4",
+            file_name, rendition.resolution
+        );
 
-            let mut hash = Vec::new();
-            match hash_result {
-                Ok(hash1) => {
-                    hash = hash1.as_bytes().to_vec();
-                    // Now you can use bytes as needed.
+        // Prefix for the intermediate files of per-title probing and chunked encoding
+        let work_prefix = format!("./temp/to/transcode/{}_{}", job_id, rendition.resolution);
+
+        let crf_choice = if request.target_vmaf > 0.0 {
+            match select_crf(
+                &file_path,
+                &work_prefix,
+                rendition,
+                is_gpu,
+                colour,
+                request.target_vmaf,
+            )
+            .await
+            {
+                Ok(choice) => {
+                    println!(
+                        "{} crf: {} (probe vmaf {:.2})",
+                        rendition.resolution, choice.crf, choice.vmaf
+                    );
+                    Some(choice)
                 }
-                Err(err) => {
-                    eprintln!("Error computing blake3 hash: {}", err);
+                Err(e) => {
+                    eprintln!("Error: {}", e);
 
                     return Err(Status::new(
                         Code::Internal,
-                        format!("Error computing blake3 hash: {}", err),
+                        format!(
+                            "Failed to select crf for {} rendition: {}",
+                            rendition.resolution, e
+                        ),
                     ));
                 }
             }
+        } else {
+            None
+        };
+        let crf = crf_choice.map(|choice| choice.crf);
+
+        let encoded = match &chunks {
+            Some(chunks) => {
+                encode_rendition_chunked(
+                    &file_path,
+                    &file_path_ue,
+                    &work_prefix,
+                    rendition,
+                    colour,
+                    crf,
+                    chunks,
+                )
+                .await
+            }
+            None => {
+                encode_rendition(&file_path, &file_path_ue, rendition, is_gpu, colour, crf).await
+            }
+        };
 
-            let mut hash_encrypted = Vec::new();
-            match hash_result_encrypted {
-                Ok(hash1) => {
-                    hash_encrypted = hash1.as_bytes().to_vec();
-                    // Now you can use bytes as needed.
+        if let Err(e) = encoded {
+            eprintln!("Error: {}", e);
+
+            return Err(Status::new(
+                Code::Internal,
+                format!("Transcoding task failed with error {}", e),
+            ));
+        }
+
+        // Quality scores are informative only, so a failed measurement does not fail the job
+        let quality = if request.measure_quality {
+            let log_path = format!(
+                "./temp/to/transcode/{}_{}_vmaf.json",
+                job_id, rendition.resolution
+            );
+            match measure_quality(&file_path, &file_path_ue, &log_path).await {
+                Ok(scores) => {
+                    println!("{} quality: {:?}", rendition.resolution, &scores);
+                    Some(scores)
+                }
+                Err(e) => {
+                    eprintln!("Error measuring {} quality: {}", rendition.resolution, e);
+                    None
                 }
-                Err(err) => {
-                    eprintln!("Error computing blake3 hash: {}", err);
+            }
+        } else {
+            None
+        };
+
+        let (encrypted_cid, locations) =
+            match encrypt_and_upload(storage.as_ref(), &file_path_ue, &file_path_encrypted).await {
+                Ok(stored) => stored,
+                Err(e) => {
+                    eprintln!(
+                        "Failed to store the {} rendition of job {}: {}",
+                        rendition.resolution, job_id, e
+                    );
 
                     return Err(Status::new(
                         Code::Internal,
-                        format!("Error computing blake3 hash: {}", err),
+                        format!("Transcoding task failed with error {}", e),
                     ));
                 }
-            }
+            };
 
-            let mut encrypted_blob_hash = vec![0x1f];
-            encrypted_blob_hash.extend(hash_encrypted);
+        println!("Encrypted CID: {:?}", encrypted_cid);
+        job::add_artifact(
+            job_id,
+            video_artifact(
+                rendition.resolution,
+                &file_path_ue,
+                &encrypted_cid,
+                locations,
+                colour,
+                quality,
+                crf,
+            )
+            .await,
+        )
+        .await;
+
+        match rendition.resolution {
+            "2160p" => *VIDEO_CID1.lock().await = encrypted_cid,
+            "1080p" => *VIDEO_CID2.lock().await = encrypted_cid,
+            _ => (),
+        }
+    }
 
-            let cloned_hash = encrypted_blob_hash.clone();
+    if request.all_audio_tracks || !request.audio_tracks.is_empty() || request.extract_subtitles {
+        if let Err(e) =
+            transcode_tracks(job_id, request, storage.as_ref(), &file_path, &file_name).await
+        {
+            eprintln!("Failed to extract audio and subtitle tracks: {}", e);
 
-            let file_path_path = Path::new(&file_path);
-            let metadata = std::fs::metadata(file_path_path).expect("Failed to read metadata");
-            let file_size = metadata.len();
+            return Err(Status::new(
+                Code::Internal,
+                format!("Failed to extract audio and subtitle tracks: {}", e),
+            ));
+        }
+    }
 
-            let cid_ue = hash_bytes_to_cid(hash, file_size);
+    if let Err(e) = store_media_metadata(
+        job_id,
+        request,
+        storage.as_ref(),
+        &file_path,
+        &file_name,
+        hdr.is_some(),
+    )
+    .await
+    {
+        eprintln!("Failed to store media metadata: {}", e);
+
+        return Err(Status::new(
+            Code::Internal,
+            format!("Failed to store media metadata: {}", e),
+        ));
+    }
 
-            println!("encryption_key1: {:?}", encryption_key1);
-            println!("cid: {:?}", cid);
-            println!("cid_ue: {:?}", cid_ue);
-            let encrypted_cid_bytes = create_encrypted_cid(
-                cid_type_encrypted,
-                encryption_algorithm,
-                chunk_size_as_power_of_2,
-                encrypted_blob_hash,
-                encryption_key1,
-                padding,
-                cid_ue,
-            );
+    if let Some(job) = job::get_job(job_id).await {
+        cache::insert(&cache_key, job.artifacts).await;
+    }
 
-            let encrypted_cid = format!("u{}", bytes_to_base64url(&encrypted_cid_bytes));
+    println!("Transcoding task finished");
 
-            // Now you have your encrypted_blob_hash and encrypted_cid
-            println!("Encrypted Blob Hash: {:02x?}", cloned_hash);
-            println!("Encrypted CID: {:?}", encrypted_cid);
-            let mut video_cid1 = VIDEO_CID1.lock().await;
-            *video_cid1 = encrypted_cid;
+    Ok(Response::new(TranscodeResponse {
+        status_code: 200,
+        message: "Transcoding task finished".to_string(),
+        job_id: job_id.to_string(),
+        ..Default::default()
+    }))
+}
 
-            println!("Transcoding task finished");
-        }
+// Stores a thumbnail of the source, then the media metadata document listing
+// every output of the job, as artifacts of the job
+async fn store_media_metadata(
+    job_id: &str,
+    request: &TranscodeRequest,
+    storage: &dyn StorageBackend,
+    file_path: &str,
+    file_name: &str,
+    hdr: bool,
+) -> Result<(), anyhow::Error> {
+    let duration = match probe_duration(file_path).await {
+        Ok(duration) => Some(duration),
         Err(e) => {
-            println!("!!!!!!!!!!!!!!!!!!!!!2160p no cid");
-            println!("Error: {}", e); // This line is added to print out the error message
-
-            return Err(Status::new(
-                Code::Internal,
-                format!("Transcoding task failed with error {}", e),
-            ));
+            eprintln!("Error probing source duration: {}", e);
+            None
         }
     };
 
-    match upload_video(format!("./temp/to/transcode/{}_1080p.mp4", file_name).as_str()) {
-        Ok(cid) => {
-            response = TranscodeResponse {
-                status_code: 200,
-                message: "Transcoding task finished".to_string(),
-            };
+    // A video without a thumbnail is still usable, so a failure here does
+    // not fail the job
+    match extract_thumbnail(file_path, file_name, duration.unwrap_or_default(), hdr).await {
+        Ok(thumbnail) => {
+            let (cid, locations) =
+                encrypt_and_upload(storage, &thumbnail.file_path_ue, &thumbnail.file_path).await?;
+            println!("thumbnail cid: {}", &cid);
+
+            job::add_artifact(
+                job_id,
+                Artifact {
+                    kind: thumbnail.kind.to_string(),
+                    name: thumbnail.name,
+                    cid,
+                    locations,
+                    ..described_output(&thumbnail.file_path_ue).await
+                },
+            )
+            .await;
+        }
+        Err(e) => eprintln!("Error extracting thumbnail: {}", e),
+    }
 
-            // Instantiate an original CID and a Multihash
-            let file_path = format!("./temp/to/transcode/{}_1080p_ue.mp4", file_name);
-            let file_path_encrypted = format!("./temp/to/transcode/{}_1080p.mp4", file_name);
+    let title = video_title(request);
+    let artifacts = job::get_job(job_id)
+        .await
+        .map(|job| job.artifacts)
+        .unwrap_or_default();
+    let document = media_metadata(&title, duration, &artifacts);
+
+    let file_path_ue = format!("./temp/to/transcode/{}_metadata_ue.json", file_name);
+    let file_path_encrypted = format!("./temp/to/transcode/{}_metadata.json", file_name);
+    std::fs::write(&file_path_ue, serde_json::to_vec(&document)?)?;
+
+    let (cid, locations) = encrypt_and_upload(storage, &file_path_ue, &file_path_encrypted).await?;
+    println!("Media metadata cid: {}", &cid);
+
+    job::add_artifact(
+        job_id,
+        Artifact {
+            kind: "metadata".to_string(),
+            name: "metadata".to_string(),
+            cid,
+            locations,
+            ..Default::default()
+        },
+    )
+    .await;
 
-            let hash_result = hash_blake3_file(file_path.clone());
-            let hash_result_encrypted = hash_blake3_file(file_path_encrypted);
+    Ok(())
+}
 
-            let mut hash = Vec::new();
-            match hash_result {
-                Ok(hash1) => {
-                    hash = hash1.as_bytes().to_vec();
-                }
-                Err(err) => {
-                    eprintln!("Error computing blake3 hash: {}", err);
+// The title of the video in its metadata document
+fn video_title(request: &TranscodeRequest) -> String {
+    if request.title.is_empty() {
+        default_title(&request.url)
+    } else {
+        request.title.clone()
+    }
+}
 
-                    response = TranscodeResponse {
-                        status_code: 500,
-                        message: format!("Error computing blake3 hash: {}", err),
-                    };
-                }
-            }
+// The file name of the source without its extension, the title of videos
+// whose request has none
+fn default_title(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let name = path
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+
+    match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem.to_string(),
+        _ => name.to_string(),
+    }
+}
 
-            let mut hash_encrypted = Vec::new();
-            match hash_result_encrypted {
-                Ok(hash1) => {
-                    hash_encrypted = hash1.as_bytes().to_vec();
-                }
-                Err(err) => {
-                    eprintln!("Error computing blake3 hash: {}", err);
+// An artifact with only the codec, frame size and bitrate of the output at
+// `file_path` set, for the other fields to be filled in
+async fn described_output(file_path: &str) -> Artifact {
+    match probe_output(file_path).await {
+        Ok(output) => Artifact {
+            codec: output.codec,
+            width: output.width,
+            height: output.height,
+            bitrate: output.bitrate,
+            channels: output.channels,
+            ..Default::default()
+        },
+        Err(e) => {
+            eprintln!("Error probing output {}: {}", file_path, e);
+            Artifact::default()
+        }
+    }
+}
 
-                    response = TranscodeResponse {
-                        status_code: 500,
-                        message: format!("Error computing blake3 hash: {}", err),
-                    };
-                }
-            }
+// The hash of the request's source when it is known before downloading it:
+// `source_hash` if the server has hashed the source already, or the one in
+// its CID. The hash the client expects is only checked against it, as a
+// client could claim the hash of someone else's source to get its outputs.
+fn known_source_hash(
+    request: &TranscodeRequest,
+    source_hash: Option<blake3::Hash>,
+    expected_hash: Option<blake3::Hash>,
+) -> Result<Option<blake3::Hash>, anyhow::Error> {
+    let hash = source_hash.or_else(|| source_cid_hash(&request.url));
+    if let (Some(hash), Some(expected_hash)) = (hash, expected_hash) {
+        if hash != expected_hash {
+            return Err(anyhow::anyhow!(
+                "Source hash {} does not match the expected {}",
+                hash.to_hex(),
+                expected_hash.to_hex()
+            ));
+        }
+    }
 
-            let mut encrypted_blob_hash = vec![0x1f];
-            encrypted_blob_hash.extend(hash_encrypted);
+    Ok(hash)
+}
 
-            let cloned_hash = encrypted_blob_hash.clone();
+// Adds the outputs an earlier job stored under `key` to `job_id`, returning
+// whether there were any
+async fn reuse_outputs(job_id: &str, key: &str, storage: &dyn StorageBackend) -> bool {
+    let artifacts = match cache::lookup(key, storage).await {
+        Some(artifacts) => artifacts,
+        None => return false,
+    };
+    println!("Reusing the outputs of an earlier job for job {}", job_id);
+
+    for artifact in artifacts {
+        if artifact.kind == "video" {
+            match artifact.name.as_str() {
+                "2160p" => *VIDEO_CID1.lock().await = artifact.cid.clone(),
+                "1080p" => *VIDEO_CID2.lock().await = artifact.cid.clone(),
+                _ => (),
+            }
+        }
+        job::add_artifact(job_id, artifact).await;
+    }
 
-            let file_path_path_ue = Path::new(&file_path);
-            let metadata = std::fs::metadata(file_path_path_ue).expect("Failed to read metadata");
-            let file_size = metadata.len();
+    true
+}
 
-            let cid_ue = hash_bytes_to_cid(hash, file_size);
+fn reused_response(job_id: &str) -> Response<TranscodeResponse> {
+    Response::new(TranscodeResponse {
+        status_code: 200,
+        message: REUSED_MESSAGE.to_string(),
+        job_id: job_id.to_string(),
+        ..Default::default()
+    })
+}
 
-            println!("encryption_key2: {:?}", encryption_key2);
-            println!("cid: {:?}", cid);
-            println!("cid_ue: {:?}", cid_ue);
-            let encrypted_cid_bytes = create_encrypted_cid(
-                cid_type_encrypted,
-                encryption_algorithm,
-                chunk_size_as_power_of_2,
-                encrypted_blob_hash,
-                encryption_key2,
-                padding,
-                cid_ue,
-            );
+async fn video_artifact(
+    resolution: &str,
+    file_path_ue: &str,
+    cid: &str,
+    locations: Vec<String>,
+    colour: ColourHandling<'_>,
+    quality: Option<QualityScores>,
+    crf: Option<u32>,
+) -> Artifact {
+    Artifact {
+        kind: "video".to_string(),
+        name: resolution.to_string(),
+        language: String::new(),
+        cid: cid.to_string(),
+        dynamic_range: colour.dynamic_range().to_string(),
+        mastering_display: colour
+            .hdr()
+            .and_then(|hdr| hdr.mastering_display.as_ref())
+            .map(|mastering_display| mastering_display.x265())
+            .unwrap_or_default(),
+        content_light_level: colour
+            .hdr()
+            .and_then(|hdr| hdr.content_light_level.clone())
+            .unwrap_or_default(),
+        quality,
+        crf: crf.unwrap_or_default(),
+        locations,
+        ..described_output(file_path_ue).await
+    }
+}
 
-            let encrypted_cid = format!("u{}", bytes_to_base64url(&encrypted_cid_bytes));
+// The tenant the caller of `request` authenticates as with its bearer token
+fn caller_tenant<T>(request: &Request<T>) -> Option<String> {
+    let token = request
+        .metadata()
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
 
-            // Now you have your encrypted_blob_hash and encrypted_cid
-            println!("Encrypted Blob Hash: {:02x?}", cloned_hash);
-            println!("Encrypted CID: {:?}", encrypted_cid);
+    registry::authenticated_tenant(token.trim())
+}
 
-            let mut video_cid2 = VIDEO_CID2.lock().await;
-            println!("after video_cid2");
-            *video_cid2 = encrypted_cid;
-            println!("after *video_cid2 = cid");
-        }
-        Err(e) => {
-            println!("!!!!!!!!!!!!!!!!!!!!!1080p no cid");
-            println!("Error: {}", e); // This line is added to print out the error message
+// Makes the caller's `tenant` the one whose key signs the registry entry of
+// `request`, so callers can only publish under their own keys. Publishing
+// needs an authenticated caller.
+fn bind_tenant(request: &mut TranscodeRequest, tenant: Option<String>) -> Result<(), Box<Status>> {
+    if request.registry_name.is_empty() {
+        return Ok(());
+    }
 
-            return Err(Status::new(
-                Code::Internal,
-                format!("Transcoding task failed with error {}", e),
-            ));
+    match tenant {
+        Some(tenant) if request.tenant.is_empty() || request.tenant == tenant => {
+            request.tenant = tenant;
+            Ok(())
         }
-    };
-
-    Ok(Response::new(response))
+        Some(tenant) => Err(Box::new(Status::permission_denied(format!(
+            "Authenticated as tenant {}, not {}",
+            tenant, request.tenant
+        )))),
+        None => Err(Box::new(Status::unauthenticated(
+            "Publishing to the registry needs a tenant token",
+        ))),
+    }
 }
 
 // The gRPC service implementation
 #[derive(Debug, Clone)]
 struct TranscodeServiceHandler {
-    transcode_task_sender: Option<Arc<Mutex<mpsc::Sender<(String, bool)>>>>,
+    transcode_task_sender: Option<Arc<Mutex<mpsc::Sender<TranscodeTask>>>>,
 }
 
 #[async_trait]
@@ -463,25 +795,33 @@ impl TranscodeService for TranscodeServiceHandler {
             "transcode_task_sender is None: {}",
             self.transcode_task_sender.is_none()
         );
-        // Send the transcoding task to the transcoding task receiver
-        if let Some(ref sender) = self.transcode_task_sender {
-            let sender = sender.lock().await.clone();
-            if let Err(e) = sender.send((url, is_gpu)).await {
-                return Err(Status::internal(format!(
-                    "Failed to send transcoding task: {}",
-                    e
-                )));
-            }
-        }
+
+        let tenant = caller_tenant(&request);
+        let job_id = job::create_job().await;
+
+        let message = self
+            .queue(&job_id, request.into_inner(), None, tenant)
+            .await?;
 
         let response = TranscodeResponse {
             status_code: 200,
-            message: "Transcoding task queued".to_string(),
+            message: message.to_string(),
+            job_id,
+            ..Default::default()
         };
 
         Ok(Response::new(response))
     }
 
+    async fn upload_and_transcode(
+        &self,
+        request: Request<Streaming<UploadChunk>>,
+    ) -> Result<Response<TranscodeResponse>, Status> {
+        let tenant = caller_tenant(&request);
+
+        self.receive_and_queue(request.into_inner(), tenant).await
+    }
+
     async fn get_cid(
         &self,
         request: Request<GetCidRequest>,
@@ -509,6 +849,154 @@ impl TranscodeService for TranscodeServiceHandler {
 
         Ok(Response::new(response))
     }
+
+    async fn get_job_result(
+        &self,
+        request: Request<GetJobResultRequest>,
+    ) -> Result<Response<GetJobResultResponse>, Status> {
+        let job_id = request.get_ref().job_id.as_str();
+
+        let response = match job::get_job(job_id).await {
+            Some(job) => GetJobResultResponse::from(job),
+            None => GetJobResultResponse {
+                status_code: 404,
+                message: format!("Unknown job {}", job_id),
+                ..Default::default()
+            },
+        };
+
+        Ok(Response::new(response))
+    }
+}
+
+impl TranscodeServiceHandler {
+    // Receives the header and source of an UploadAndTranscode call from
+    // `stream` and queues the job, for a caller authenticated as `tenant`
+    async fn receive_and_queue(
+        &self,
+        mut stream: impl Stream<Item = Result<UploadChunk, Status>> + Unpin,
+        tenant: Option<String>,
+    ) -> Result<Response<TranscodeResponse>, Status> {
+        let mut header = match stream.next().await.transpose()? {
+            Some(UploadChunk {
+                payload: Some(upload_chunk::Payload::Header(header)),
+            }) => header,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "The first message must be the header",
+                ))
+            }
+        };
+        let options = DownloadOptions::from_env(&header.source_hash)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        // Refuse to publish before receiving the source rather than after
+        bind_tenant(&mut header, tenant.clone()).map_err(|e| *e)?;
+
+        let job_id = job::create_job().await;
+        println!("Receiving source of job {}", job_id);
+
+        let (path, hash) = match ingest::receive_source(&mut stream, &job_id, &options).await {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Failed to receive source of job {}: {}", job_id, e);
+                job::set_status(&job_id, JobStatus::Failed, &e.to_string()).await;
+
+                return Err(Status::invalid_argument(format!(
+                    "Failed to receive source: {}",
+                    e
+                )));
+            }
+        };
+        println!("Received source of job {} ({})", job_id, hash.to_hex());
+
+        header.url = format!("upload://{}", job_id);
+        header.source_hash = hash.to_hex().to_string();
+        ingest::add_upload(&header.url, path);
+        let message = self.queue(&job_id, header, Some(hash), tenant).await?;
+
+        let response = TranscodeResponse {
+            status_code: 200,
+            message: message.to_string(),
+            job_id,
+            source_hash: hash.to_hex().to_string(),
+        };
+
+        Ok(Response::new(response))
+    }
+
+    // Sends the transcoding task to the transcoding task receiver, unless an
+    // earlier job already transcoded the same source the same way, and returns
+    // the message to respond with. `source_hash` is the hash of the source
+    // when the server has hashed it already, and `tenant` the tenant the
+    // caller authenticated as. Jobs to publish are always queued, so
+    // publishing doesn't hold up the response. A job that can't be queued is
+    // marked failed, as nothing else would finish it, and an uploaded source
+    // is deleted unless the job is queued.
+    async fn queue(
+        &self,
+        job_id: &str,
+        request: TranscodeRequest,
+        source_hash: Option<blake3::Hash>,
+        tenant: Option<String>,
+    ) -> Result<&'static str, Status> {
+        let source = request.url.clone();
+        let queued = self.try_queue(job_id, request, source_hash, tenant).await;
+        match &queued {
+            Ok(message) if *message == REUSED_MESSAGE => ingest::remove_upload(&source).await,
+            Ok(_) => (),
+            Err(e) => {
+                job::set_status(job_id, JobStatus::Failed, e.message()).await;
+                ingest::remove_upload(&source).await;
+            }
+        }
+
+        queued
+    }
+
+    async fn try_queue(
+        &self,
+        job_id: &str,
+        mut request: TranscodeRequest,
+        source_hash: Option<blake3::Hash>,
+        tenant: Option<String>,
+    ) -> Result<&'static str, Status> {
+        bind_tenant(&mut request, tenant).map_err(|e| *e)?;
+
+        let options = DownloadOptions::from_env(&request.source_hash)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let source_hash = known_source_hash(&request, source_hash, options.expected_hash())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        if !request.registry_name.is_empty() {
+            if !request.publish_plaintext {
+                return Err(Status::invalid_argument(
+                    "Publishing to the registry stores the media metadata unencrypted; set publish_plaintext to allow it",
+                ));
+            }
+            registry::signing_key(&request.tenant, &request.registry_name)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        } else if let (Some(hash), Ok(storage)) =
+            (source_hash, storage::backend(&request.storage_backend))
+        {
+            let key = cache::cache_key(&hash, storage.name(), &request);
+            if reuse_outputs(job_id, &key, storage.as_ref()).await {
+                job::set_status(job_id, JobStatus::Done, REUSED_MESSAGE).await;
+                return Ok(REUSED_MESSAGE);
+            }
+        }
+
+        if let Some(ref sender) = self.transcode_task_sender {
+            let sender = sender.lock().await.clone();
+            if let Err(e) = sender.send((job_id.to_string(), request)).await {
+                return Err(Status::internal(format!(
+                    "Failed to send transcoding task: {}",
+                    e
+                )));
+            }
+        }
+
+        Ok("Transcoding task queued")
+    }
 }
 
 impl Drop for TranscodeServiceHandler {
@@ -526,13 +1014,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
     // Create a channel for transcoding tasks
-    let (task_sender, task_receiver) = mpsc::channel::<(String, bool)>(100);
+    let (task_sender, task_receiver) = mpsc::channel::<TranscodeTask>(100);
     let task_receiver = Arc::new(Mutex::new(task_receiver));
 
     // Start the transcoding task receiver
     let receiver_clone = Arc::clone(&task_receiver);
     tokio::spawn(transcode_task_receiver(receiver_clone));
 
+    // Stand in for the S5 portal with a local tus server when LOCAL_PORTAL_ADDR
+    // is set. Point PORTAL_URL at the same address.
+    if let Some(portal_addr) = var("LOCAL_PORTAL_ADDR")
+        .ok()
+        .filter(|addr| !addr.is_empty())
+    {
+        let portal = local_portal(LOCAL_PORTAL_DIR, &format!("http://{}", portal_addr))?;
+        let portal_addr = portal_addr.parse()?;
+        println!("Local portal listening on {}", portal_addr);
+        tokio::spawn(async move {
+            if let Err(e) = Arc::new(portal).serve(portal_addr).await {
+                eprintln!("Local portal stopped: {}", e);
+            }
+        });
+    }
+
+    // Accept sources uploaded directly over tus when INGEST_ADDR is set.
+    // INGEST_URL is the address clients reach it at, if different.
+    if let Some(ingest_addr) = var("INGEST_ADDR").ok().filter(|addr| !addr.is_empty()) {
+        let base_url = var("INGEST_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .unwrap_or_else(|| format!("http://{}/files", ingest_addr));
+        let ingest_addr = ingest_addr.parse()?;
+        let handler = TranscodeServiceHandler {
+            transcode_task_sender: Some(Arc::new(Mutex::new(task_sender.clone()))),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = ingest::serve(ingest_addr, base_url, handler).await {
+                eprintln!("Ingest endpoint stopped: {}", e);
+            }
+        });
+    }
+
     // Create a gRPC server
     let addr = "0.0.0.0:50051".parse()?;
 
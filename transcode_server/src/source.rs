@@ -0,0 +1,1049 @@
+/*
+ * source.rs
+ *
+ * Fetches source videos, from HTTP(S) URLs, as S5 CIDs through the
+ * configured portals or from the uploads of the ingest endpoint.
+ * All downloads are bounded in size and time, and URL downloads resume
+ * with Range requests after transient failures. Plain CIDs are downloaded as
+ * they are; for encrypted CIDs the encrypted blob is downloaded and
+ * decrypted chunk by chunk as it arrives, and sizes and blake3 hashes are
+ * checked against the CID. A source that fails any check is deleted before the job proceeds.
+ */
+
+use crate::ingest::uploaded_file;
+use crate::portals::PortalSet;
+use crate::s5::hash_blake3_file;
+use crate::storage::{cid_to_string, parse_raw_blob_cid, StorageBackend};
+use crate::{hash_bytes_to_cid, CID_TYPE_ENCRYPTED, ENCRYPTION_ALGORITHM};
+use anyhow::anyhow;
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+use dotenv::var;
+use reqwest_async::{header, Client, Response, StatusCode};
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::time::{sleep, timeout};
+
+// Largest source downloaded unless SOURCE_MAX_SIZE is set: 64 GiB
+const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+// How long a connection may stall unless SOURCE_TIMEOUT_SECS is set
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Times a download resumes after a transient failure, waiting 2, 4, 8, ...
+// seconds in between
+const DOWNLOAD_RETRIES: u32 = 5;
+
+// Limits and checks applied to a source download
+pub struct DownloadOptions {
+    max_size: u64,
+    timeout: Duration,
+    expected_hash: Option<blake3::Hash>,
+}
+
+impl DownloadOptions {
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    pub fn expected_hash(&self) -> Option<blake3::Hash> {
+        self.expected_hash
+    }
+
+    // Reads the limits from SOURCE_MAX_SIZE (bytes) and SOURCE_TIMEOUT_SECS.
+    // `expected_hash` is the hex blake3 hash of the source, or empty.
+    pub fn from_env(expected_hash: &str) -> Result<Self, anyhow::Error> {
+        let max_size = match var("SOURCE_MAX_SIZE").ok().filter(|size| !size.is_empty()) {
+            Some(size) => size
+                .parse()
+                .map_err(|e| anyhow!("Invalid SOURCE_MAX_SIZE {}: {}", size, e))?,
+            None => DEFAULT_MAX_SIZE,
+        };
+
+        let timeout = match var("SOURCE_TIMEOUT_SECS")
+            .ok()
+            .filter(|secs| !secs.is_empty())
+        {
+            Some(secs) => Duration::from_secs(
+                secs.parse()
+                    .map_err(|e| anyhow!("Invalid SOURCE_TIMEOUT_SECS {}: {}", secs, e))?,
+            ),
+            None => DEFAULT_TIMEOUT,
+        };
+
+        let expected_hash = match expected_hash {
+            "" => None,
+            hash => Some(
+                blake3::Hash::from_hex(hash)
+                    .map_err(|e| anyhow!("Invalid source hash {}: {}", hash, e))?,
+            ),
+        };
+
+        Ok(DownloadOptions {
+            max_size,
+            timeout,
+            expected_hash,
+        })
+    }
+}
+
+// Downloads the source at `source`, a URL, an S5 CID or the upload URL of a
+// source uploaded to the ingest endpoint, to `path` and checks
+// it looks like a video matching the expected hash. Nothing is left at
+// `path` if the download fails.
+pub async fn download_source(
+    source: &str,
+    path: &str,
+    options: &DownloadOptions,
+) -> Result<(), anyhow::Error> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let result = if let Some(upload) = uploaded_file(source) {
+        fs::copy(upload, path).await.map(|_| ()).map_err(Into::into)
+    } else if let Some(cid) = source_cid(source) {
+        download_cid(cid, path, options).await
+    } else {
+        download_url(source, path, options).await
+    };
+    let result = match result {
+        Ok(()) => check_source(path, options).await,
+        Err(e) => Err(e),
+    };
+
+    if result.is_err() {
+        let _ = fs::remove_file(path).await;
+    }
+
+    result
+}
+
+async fn check_source(path: &str, options: &DownloadOptions) -> Result<(), anyhow::Error> {
+    let size = fs::metadata(path).await?.len();
+    if size > options.max_size {
+        return Err(anyhow!(
+            "Source is {} bytes, more than the limit of {}",
+            size,
+            options.max_size
+        ));
+    }
+
+    let mut header = [0; TS_PACKET_SIZE * TS_SYNC_PACKETS];
+    let mut file = File::open(path).await?;
+    let mut read = 0;
+    while read < header.len() {
+        match file.read(&mut header[read..]).await? {
+            0 => break,
+            count => read += count,
+        }
+    }
+    if !is_media(&header[..read]) {
+        return Err(anyhow!("Source does not look like a video file"));
+    }
+
+    if let Some(expected_hash) = options.expected_hash {
+        let hash = hash_blake3_file(path.to_string())?;
+        if hash != expected_hash {
+            return Err(anyhow!(
+                "Source hash {} does not match the expected {}",
+                hash.to_hex(),
+                expected_hash.to_hex()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// MPEG-TS has no magic number, only a sync byte at the start of every packet,
+// so a source is taken for TS when that many packets in a row start with it
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_PACKETS: usize = 4;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+// Whether a file starting with `header` is a container ffmpeg can read video
+// from: ISO BMFF (mp4, mov), Matroska/WebM, AVI, MPEG-TS, MPEG-PS, FLV, Ogg
+// or ASF
+fn is_media(header: &[u8]) -> bool {
+    let starts_with = |magic: &[u8]| header.starts_with(magic);
+
+    (header.len() >= 8 && &header[4..8] == b"ftyp")
+        || starts_with(&[0x1a, 0x45, 0xdf, 0xa3])
+        || (starts_with(b"RIFF") && header.len() >= 12 && &header[8..12] == b"AVI ")
+        || is_transport_stream(header)
+        || starts_with(&[0x00, 0x00, 0x01, 0xba])
+        || starts_with(b"FLV")
+        || starts_with(b"OggS")
+        || starts_with(&[0x30, 0x26, 0xb2, 0x75])
+}
+
+fn is_transport_stream(header: &[u8]) -> bool {
+    header.len() >= TS_PACKET_SIZE * TS_SYNC_PACKETS
+        && (0..TS_SYNC_PACKETS).all(|packet| header[packet * TS_PACKET_SIZE] == TS_SYNC_BYTE)
+}
+
+// A failed attempt at a URL download, and whether resuming may succeed
+enum FetchError {
+    Transient(anyhow::Error),
+    Fatal(anyhow::Error),
+}
+
+impl From<reqwest_async::Error> for FetchError {
+    fn from(e: reqwest_async::Error) -> Self {
+        if e.is_timeout() || e.is_connect() || e.is_body() || e.is_request() {
+            FetchError::Transient(e.into())
+        } else {
+            FetchError::Fatal(e.into())
+        }
+    }
+}
+
+impl From<std::io::Error> for FetchError {
+    fn from(e: std::io::Error) -> Self {
+        FetchError::Fatal(e.into())
+    }
+}
+
+// A URL download in progress
+struct Download<'a> {
+    url: &'a str,
+    options: &'a DownloadOptions,
+    client: Client,
+    file: File,
+    received: u64,
+    // ETag or Last-Modified of the first response, so a resumed download
+    // starts over if the source changed
+    validator: Option<String>,
+}
+
+async fn download_url(
+    url: &str,
+    path: &str,
+    options: &DownloadOptions,
+) -> Result<(), anyhow::Error> {
+    let mut download = Download {
+        url,
+        options,
+        client: Client::builder().connect_timeout(options.timeout).build()?,
+        file: File::create(path).await?,
+        received: 0,
+        validator: None,
+    };
+
+    let mut retries = 0;
+    loop {
+        let result = download.fetch().await;
+        download.file.flush().await?;
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(FetchError::Transient(e)) if retries < DOWNLOAD_RETRIES => {
+                retries += 1;
+                eprintln!(
+                    "Download of {} failed after {} bytes, resuming ({}/{}): {}",
+                    url, download.received, retries, DOWNLOAD_RETRIES, e
+                );
+                sleep(Duration::from_secs(1 << retries)).await;
+            }
+            Err(FetchError::Transient(e)) | Err(FetchError::Fatal(e)) => {
+                return Err(anyhow!("Failed to download {}: {}", url, e))
+            }
+        }
+    }
+}
+
+impl Download<'_> {
+    // Requests the rest of the source and appends it to the file
+    async fn fetch(&mut self) -> Result<(), FetchError> {
+        let mut request = self.client.get(self.url);
+        if self.received > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", self.received));
+            if let Some(validator) = &self.validator {
+                request = request.header(header::IF_RANGE, validator);
+            }
+        }
+
+        let mut response = timeout(self.options.timeout, request.send())
+            .await
+            .map_err(|_| FetchError::Transient(anyhow!("Timed out waiting for a response")))??;
+
+        let status = response.status();
+        match status {
+            StatusCode::PARTIAL_CONTENT if self.received > 0 => (),
+            StatusCode::RANGE_NOT_SATISFIABLE if self.received > 0 => {
+                // The previous attempt got everything but noticed too late
+                let total = response
+                    .headers()
+                    .get(header::CONTENT_RANGE)
+                    .and_then(|range| range.to_str().ok())
+                    .and_then(|range| range.strip_prefix("bytes */"))
+                    .and_then(|total| total.parse::<u64>().ok());
+                return match total {
+                    Some(total) if total == self.received => Ok(()),
+                    _ => Err(FetchError::Fatal(anyhow!("Server refused to resume"))),
+                };
+            }
+            status if status.is_success() => {
+                // A full response: the server can't resume or the source changed
+                if self.received > 0 {
+                    self.file.set_len(0).await?;
+                    self.file.seek(SeekFrom::Start(0)).await?;
+                    self.received = 0;
+                }
+                self.validator = response
+                    .headers()
+                    .get(header::ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .filter(|etag| !etag.starts_with("W/"))
+                    .or_else(|| {
+                        response
+                            .headers()
+                            .get(header::LAST_MODIFIED)
+                            .and_then(|modified| modified.to_str().ok())
+                    })
+                    .map(str::to_string);
+            }
+            status
+                if status.is_server_error()
+                    || status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::REQUEST_TIMEOUT =>
+            {
+                return Err(FetchError::Transient(anyhow!("Server returned {}", status)))
+            }
+            status => return Err(FetchError::Fatal(anyhow!("Server returned {}", status))),
+        }
+
+        if let Some(content_type) = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+        {
+            let mime = content_type.split(';').next().unwrap_or_default().trim();
+            if mime.starts_with("text/") || mime.ends_with("json") || mime.ends_with("xml") {
+                return Err(FetchError::Fatal(anyhow!(
+                    "Server sent {} instead of a video",
+                    mime
+                )));
+            }
+        }
+
+        let expected_size = response
+            .content_length()
+            .map(|length| self.received + length);
+        if expected_size.is_some_and(|size| size > self.options.max_size) {
+            return Err(FetchError::Fatal(anyhow!(
+                "Source is larger than the limit of {} bytes",
+                self.options.max_size
+            )));
+        }
+
+        while let Some(chunk) = timeout(self.options.timeout, response.chunk())
+            .await
+            .map_err(|_| FetchError::Transient(anyhow!("Timed out waiting for data")))??
+        {
+            self.received += chunk.len() as u64;
+            if self.received > self.options.max_size {
+                return Err(FetchError::Fatal(anyhow!(
+                    "Source is larger than the limit of {} bytes",
+                    self.options.max_size
+                )));
+            }
+            self.file.write_all(&chunk).await?;
+        }
+
+        match expected_size {
+            Some(size) if size != self.received => Err(FetchError::Transient(anyhow!(
+                "Connection closed after {} of {} bytes",
+                self.received,
+                size
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Poly1305 tag appended to every encrypted chunk
+const TAG_SIZE: usize = 16;
+
+// What an encrypted CID says about its blob and the plaintext in it
+struct EncryptedCid {
+    chunk_size: usize,
+    blob_hash: [u8; 32],
+    key: [u8; 32],
+    padding: u32,
+    hash: [u8; 32],
+    size: u64,
+}
+
+impl EncryptedCid {
+    fn parse(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+        // Type, algorithm, chunk size as a power of 2, 0x1f and the blake3
+        // hash of the encrypted blob, the key, the padding (big-endian), then
+        // the CID of the plaintext
+        if bytes.len() < 72 || bytes[0] != CID_TYPE_ENCRYPTED || bytes[3] != 0x1f {
+            return Err(anyhow!("Malformed encrypted CID"));
+        }
+        if bytes[1] != ENCRYPTION_ALGORITHM {
+            return Err(anyhow!("Unsupported encryption algorithm {:#x}", bytes[1]));
+        }
+        if !(10..=30).contains(&bytes[2]) {
+            return Err(anyhow!("Unsupported chunk size 2^{}", bytes[2]));
+        }
+
+        let (hash, size) = parse_raw_blob_cid(&bytes[72..])
+            .ok_or_else(|| anyhow!("Malformed plaintext CID in encrypted CID"))?;
+
+        let mut blob_hash = [0; 32];
+        blob_hash.copy_from_slice(&bytes[4..36]);
+        let mut key = [0; 32];
+        key.copy_from_slice(&bytes[36..68]);
+        let mut padding = [0; 4];
+        padding.copy_from_slice(&bytes[68..72]);
+
+        Ok(EncryptedCid {
+            chunk_size: 1 << bytes[2],
+            blob_hash,
+            key,
+            padding: u32::from_be_bytes(padding),
+            hash,
+            size,
+        })
+    }
+
+    // Size of the encrypted blob: the plaintext, padding of a final partial
+    // chunk and the tag of every chunk
+    fn encrypted_size(&self) -> u64 {
+        let chunk_size = self.chunk_size as u64;
+        let chunks = self.size.div_ceil(chunk_size);
+        let padding = if !self.size.is_multiple_of(chunk_size) {
+            self.padding as u64
+        } else {
+            0
+        };
+
+        self.size + padding + chunks * TAG_SIZE as u64
+    }
+
+    fn blob_cid(&self) -> String {
+        cid_to_string(&hash_bytes_to_cid(
+            self.blob_hash.to_vec(),
+            self.encrypted_size(),
+        ))
+    }
+}
+
+// The S5 CID a request's source refers to, given either bare or as s5://<cid>,
+// or None if the source is a URL
+fn source_cid(source: &str) -> Option<&str> {
+    match source.strip_prefix("s5://") {
+        Some(cid) => Some(cid),
+        None if source.starts_with('u') && !source.contains(['/', ':']) => Some(source),
+        None => None,
+    }
+}
+
+fn decode_cid(cid: &str) -> Result<Vec<u8>, anyhow::Error> {
+    cid.strip_prefix('u')
+        .and_then(|cid| general_purpose::URL_SAFE_NO_PAD.decode(cid).ok())
+        .ok_or_else(|| anyhow!("{} is not a base64url S5 CID", cid))
+}
+
+// The blake3 hash of the plaintext a CID source refers to, known without
+// downloading it, or None if the source is not a CID
+pub fn source_cid_hash(source: &str) -> Option<blake3::Hash> {
+    let bytes = decode_cid(source_cid(source)?).ok()?;
+
+    let hash = match bytes.first() {
+        Some(&CID_TYPE_ENCRYPTED) => EncryptedCid::parse(&bytes).ok()?.hash,
+        _ => parse_raw_blob_cid(&bytes)?.0,
+    };
+
+    Some(blake3::Hash::from(hash))
+}
+
+// The CID of the stored blob behind an encrypted CID
+pub fn encrypted_blob_cid(cid: &str) -> Result<String, anyhow::Error> {
+    Ok(EncryptedCid::parse(&decode_cid(cid)?)?.blob_cid())
+}
+
+// The plaintext CID inside an encrypted CID
+pub fn plaintext_cid(cid: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let source = EncryptedCid::parse(&decode_cid(cid)?)?;
+
+    Ok(hash_bytes_to_cid(source.hash.to_vec(), source.size))
+}
+
+// Fetches the encrypted blob behind `cid` from `storage` and decrypts it to
+// `path`
+pub async fn fetch_decrypted(
+    storage: &dyn StorageBackend,
+    cid: &str,
+    path: &str,
+) -> Result<(), anyhow::Error> {
+    let source = EncryptedCid::parse(&decode_cid(cid)?)?;
+    let encrypted_path = format!("{}.encrypted", path);
+
+    let result = match storage.get(&source.blob_cid(), &encrypted_path).await {
+        Ok(()) => decrypt_file(&source, &encrypted_path, path).await,
+        Err(e) => Err(e),
+    };
+
+    let _ = fs::remove_file(&encrypted_path).await;
+    if result.is_err() {
+        let _ = fs::remove_file(path).await;
+    }
+
+    result
+}
+
+async fn decrypt_file(
+    source: &EncryptedCid,
+    encrypted_path: &str,
+    path: &str,
+) -> Result<(), anyhow::Error> {
+    if hash_blake3_file(encrypted_path.to_string())?.as_bytes() != &source.blob_hash {
+        return Err(anyhow!(
+            "Blob {} does not match its hash",
+            source.blob_cid()
+        ));
+    }
+
+    let mut decryptor = Decryptor::new(source, File::create(path).await?)?;
+    let mut file = File::open(encrypted_path).await?;
+    let mut buffer = vec![0; source.chunk_size + TAG_SIZE];
+
+    loop {
+        // Fill the buffer with a whole chunk, or what is left of the file
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match file.read(&mut buffer[filled..]).await? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+
+        decryptor.decrypt(&buffer[..filled]).await?;
+    }
+
+    decryptor.finish(source).await
+}
+
+// Downloads the source with `cid` to `path`, decrypting it if the CID is
+// encrypted. The CID gives the size of the source, so one over the limit is
+// refused before anything is fetched.
+async fn download_cid(
+    cid: &str,
+    path: &str,
+    options: &DownloadOptions,
+) -> Result<(), anyhow::Error> {
+    let bytes = decode_cid(cid)?;
+
+    let size = match bytes.first() {
+        Some(&CID_TYPE_ENCRYPTED) => EncryptedCid::parse(&bytes)?.size,
+        _ => {
+            parse_raw_blob_cid(&bytes)
+                .ok_or_else(|| anyhow!("Unsupported CID {}", cid))?
+                .1
+        }
+    };
+    if size > options.max_size {
+        return Err(anyhow!(
+            "Source is {} bytes, more than the limit of {}",
+            size,
+            options.max_size
+        ));
+    }
+
+    let portals = PortalSet::shared()?;
+
+    let result = match bytes.first() {
+        Some(&CID_TYPE_ENCRYPTED) => {
+            download_encrypted(&portals, &EncryptedCid::parse(&bytes)?, path, options).await
+        }
+        _ => {
+            let (hash, size) =
+                parse_raw_blob_cid(&bytes).ok_or_else(|| anyhow!("Unsupported CID {}", cid))?;
+            download_plain(&portals, cid, &hash, size, path, options).await
+        }
+    };
+
+    if result.is_err() {
+        let _ = fs::remove_file(path).await;
+    }
+
+    result
+}
+
+async fn download_plain(
+    portals: &PortalSet,
+    cid: &str,
+    hash: &[u8; 32],
+    size: u64,
+    path: &str,
+    options: &DownloadOptions,
+) -> Result<(), anyhow::Error> {
+    let mut response = open_blob(portals, cid, options).await?;
+    let mut file = File::create(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut received: u64 = 0;
+
+    while let Some(bytes) = next_chunk(&mut response, options).await? {
+        received += bytes.len() as u64;
+        if received > size {
+            return Err(anyhow!("Blob {} is larger than its CID says", cid));
+        }
+
+        hasher.update(&bytes);
+        file.write_all(&bytes).await?;
+    }
+    file.flush().await?;
+
+    if received != size {
+        return Err(anyhow!(
+            "Blob {} ended after {} of {} bytes",
+            cid,
+            received,
+            size
+        ));
+    }
+    if hasher.finalize().as_bytes() != hash {
+        return Err(anyhow!("Blob {} does not match its hash", cid));
+    }
+
+    Ok(())
+}
+
+async fn download_encrypted(
+    portals: &PortalSet,
+    source: &EncryptedCid,
+    path: &str,
+    options: &DownloadOptions,
+) -> Result<(), anyhow::Error> {
+    let blob_cid = source.blob_cid();
+    let encrypted_size = source.encrypted_size();
+    let encrypted_chunk_size = source.chunk_size + TAG_SIZE;
+
+    let mut decryptor = Decryptor::new(source, File::create(path).await?)?;
+
+    let mut response = open_blob(portals, &blob_cid, options).await?;
+    let mut blob_hasher = blake3::Hasher::new();
+    let mut received: u64 = 0;
+    let mut buffer = Vec::with_capacity(encrypted_chunk_size);
+
+    while let Some(bytes) = next_chunk(&mut response, options).await? {
+        received += bytes.len() as u64;
+        if received > encrypted_size {
+            return Err(anyhow!("Blob {} is larger than its CID says", blob_cid));
+        }
+        blob_hasher.update(&bytes);
+
+        let mut bytes = &bytes[..];
+        while !bytes.is_empty() {
+            let take = (encrypted_chunk_size - buffer.len()).min(bytes.len());
+            buffer.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+
+            if buffer.len() == encrypted_chunk_size {
+                decryptor.decrypt(&buffer).await?;
+                buffer.clear();
+            }
+        }
+    }
+    if !buffer.is_empty() {
+        decryptor.decrypt(&buffer).await?;
+    }
+
+    if received != encrypted_size {
+        return Err(anyhow!(
+            "Blob {} ended after {} of {} bytes",
+            blob_cid,
+            received,
+            encrypted_size
+        ));
+    }
+    if blob_hasher.finalize().as_bytes() != &source.blob_hash {
+        return Err(anyhow!("Blob {} does not match its hash", blob_cid));
+    }
+
+    decryptor.finish(source).await
+}
+
+// Requests the blob with `cid` from the portals, giving up if none answers
+// within the timeout
+async fn open_blob(
+    portals: &PortalSet,
+    cid: &str,
+    options: &DownloadOptions,
+) -> Result<Response, anyhow::Error> {
+    timeout(options.timeout, portals.open(cid))
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for blob {}", cid))?
+}
+
+// The next chunk of a blob download, or an error if the connection stalls
+// for longer than the timeout
+async fn next_chunk(
+    response: &mut Response,
+    options: &DownloadOptions,
+) -> Result<Option<Bytes>, anyhow::Error> {
+    timeout(options.timeout, response.chunk())
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for data"))?
+        .map_err(Into::into)
+}
+
+// Decrypts the chunks of an encrypted blob in order, writing the plaintext
+// without its padding
+struct Decryptor {
+    cipher: XChaCha20Poly1305,
+    file: File,
+    hasher: blake3::Hasher,
+    chunk_index: u32,
+    // Plaintext bytes still to be written
+    remaining: u64,
+}
+
+impl Decryptor {
+    fn new(source: &EncryptedCid, file: File) -> Result<Self, anyhow::Error> {
+        let cipher = XChaCha20Poly1305::new_from_slice(&source.key)
+            .map_err(|_| anyhow!("Invalid encryption key"))?;
+
+        Ok(Decryptor {
+            cipher,
+            file,
+            hasher: blake3::Hasher::new(),
+            chunk_index: 0,
+            remaining: source.size,
+        })
+    }
+
+    // Checks that the whole plaintext was written and matches its CID
+    async fn finish(mut self, source: &EncryptedCid) -> Result<(), anyhow::Error> {
+        self.file.flush().await?;
+
+        if self.remaining != 0 || self.hasher.finalize().as_bytes() != &source.hash {
+            return Err(anyhow!("Decrypted source does not match its CID"));
+        }
+
+        Ok(())
+    }
+
+    async fn decrypt(&mut self, chunk: &[u8]) -> Result<(), anyhow::Error> {
+        // The nonce of each chunk is its index, little-endian
+        let mut nonce = XNonce::default();
+        nonce[..4].copy_from_slice(&self.chunk_index.to_le_bytes());
+
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce, chunk)
+            .map_err(|_| anyhow!("Failed to decrypt chunk {}", self.chunk_index))?;
+
+        let length = (plaintext.len() as u64).min(self.remaining) as usize;
+        self.hasher.update(&plaintext[..length]);
+        self.file.write_all(&plaintext[..length]).await?;
+
+        self.remaining -= length as u64;
+        self.chunk_index += 1;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const HASH: [u8; 32] = [7; 32];
+
+    // An encrypted CID of a 100000 byte plaintext in 64 KiB chunks
+    fn encrypted_cid() -> String {
+        let mut bytes = vec![CID_TYPE_ENCRYPTED, ENCRYPTION_ALGORITHM, 16, 0x1f];
+        bytes.extend([1; 32]);
+        bytes.extend([2; 32]);
+        bytes.extend(12u32.to_be_bytes());
+        bytes.extend(hash_bytes_to_cid(HASH.to_vec(), 100_000));
+
+        cid_to_string(&bytes)
+    }
+
+    #[test]
+    fn source_cid_hash_reads_blob_cids() {
+        let cid = cid_to_string(&hash_bytes_to_cid(HASH.to_vec(), 1234));
+
+        assert_eq!(source_cid_hash(&cid), Some(blake3::Hash::from(HASH)));
+        assert_eq!(
+            source_cid_hash(&format!("s5://{}", cid)),
+            Some(blake3::Hash::from(HASH))
+        );
+    }
+
+    #[test]
+    fn source_cid_hash_reads_the_plaintext_hash_of_encrypted_cids() {
+        assert_eq!(
+            source_cid_hash(&encrypted_cid()),
+            Some(blake3::Hash::from(HASH))
+        );
+    }
+
+    #[test]
+    fn source_cid_hash_ignores_urls_and_malformed_cids() {
+        assert_eq!(source_cid_hash("https://example.com/video.mp4"), None);
+        assert_eq!(source_cid_hash("uploads/video.mp4"), None);
+        assert_eq!(source_cid_hash("uAAAA"), None);
+        assert_eq!(source_cid_hash("s5://not base64"), None);
+    }
+
+    #[test]
+    fn plaintext_cid_is_the_blob_cid_inside_an_encrypted_cid() {
+        assert_eq!(
+            plaintext_cid(&encrypted_cid()).unwrap(),
+            hash_bytes_to_cid(HASH.to_vec(), 100_000)
+        );
+
+        let blob_cid = cid_to_string(&hash_bytes_to_cid(HASH.to_vec(), 1234));
+        assert!(plaintext_cid(&blob_cid).is_err());
+    }
+
+    #[test]
+    fn encrypted_blob_cid_accounts_for_padding_and_tags() {
+        // Two chunks, the second padded by 12 bytes, with a tag each
+        assert_eq!(
+            encrypted_blob_cid(&encrypted_cid()).unwrap(),
+            cid_to_string(&hash_bytes_to_cid(vec![1; 32], 100_000 + 12 + 2 * 16))
+        );
+    }
+
+    // A video as far as `is_media` can tell: an mp4 `ftyp` box, then filler
+    fn video(len: usize, seed: u8) -> Vec<u8> {
+        let mut data = (0..len)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect::<Vec<_>>();
+        data[..8].copy_from_slice(b"\0\0\0\x20ftyp");
+        data
+    }
+
+    fn options(max_size: u64) -> DownloadOptions {
+        DownloadOptions {
+            max_size,
+            timeout: Duration::from_secs(5),
+            expected_hash: None,
+        }
+    }
+
+    // An HTTP server answering one connection after another with `responses`,
+    // each a head and the body sent after it, and recording the lowercased
+    // request heads
+    fn serve(responses: Vec<(String, Vec<u8>)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/video.mp4", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+
+        thread::spawn(move || {
+            for (head, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                    request.push(byte[0]);
+                }
+                received
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&request).to_lowercase());
+
+                let _ = stream.write_all(format!("{}\r\n\r\n", head).as_bytes());
+                let _ = stream.write_all(&body);
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn target() -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("source").to_str().unwrap().to_string();
+        (dir, path)
+    }
+
+    #[test]
+    fn is_media_recognises_video_containers() {
+        assert!(is_media(&video(64, 0)));
+        assert!(is_media(&[0x1a, 0x45, 0xdf, 0xa3, 0x01]));
+        assert!(is_media(b"RIFF\x10\0\0\0AVI LIST"));
+        assert!(is_media(b"FLV\x01\x05"));
+        assert!(is_media(b"OggS\0\x02"));
+
+        let mut ts = vec![0; TS_PACKET_SIZE * TS_SYNC_PACKETS];
+        for packet in 0..TS_SYNC_PACKETS {
+            ts[packet * TS_PACKET_SIZE] = TS_SYNC_BYTE;
+        }
+        assert!(is_transport_stream(&ts));
+        assert!(is_media(&ts));
+        // One sync byte is not enough to take a file for TS
+        assert!(!is_transport_stream(&ts[..TS_PACKET_SIZE * 2]));
+
+        assert!(!is_media(b"<!DOCTYPE html><html>"));
+        assert!(!is_media(b"RIFF\x10\0\0\0WAVEfmt "));
+        assert!(!is_media(b""));
+    }
+
+    #[tokio::test]
+    async fn interrupted_downloads_resume_with_a_range_request() {
+        let data = video(1000, 1);
+        let (url, requests) = serve(vec![
+            (
+                "HTTP/1.1 200 OK\r\nContent-Length: 1000\r\nETag: \"v1\"".to_string(),
+                data[..400].to_vec(),
+            ),
+            (
+                "HTTP/1.1 206 Partial Content\r\nContent-Length: 600\r\nContent-Range: bytes 400-999/1000".to_string(),
+                data[400..].to_vec(),
+            ),
+        ]);
+        let (_dir, path) = target();
+
+        download_source(&url, &path, &options(10_000))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].contains("range:"), "{}", requests[0]);
+        assert!(requests[1].contains("range: bytes=400-"), "{}", requests[1]);
+        assert!(requests[1].contains("if-range: \"v1\""), "{}", requests[1]);
+    }
+
+    #[tokio::test]
+    async fn downloads_start_over_when_the_source_changed() {
+        let old = video(1000, 1);
+        let new = video(800, 2);
+        let (url, requests) = serve(vec![
+            (
+                "HTTP/1.1 200 OK\r\nContent-Length: 1000\r\nLast-Modified: Mon, 05 Oct 2026 10:00:00 GMT".to_string(),
+                old[..400].to_vec(),
+            ),
+            // The If-Range validator no longer matches, so the whole new source comes back
+            ("HTTP/1.1 200 OK\r\nContent-Length: 800".to_string(), new.clone()),
+        ]);
+        let (_dir, path) = target();
+
+        download_source(&url, &path, &options(10_000))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), new);
+        let requests = requests.lock().unwrap();
+        assert!(
+            requests[1].contains("if-range: mon, 05 oct 2026 10:00:00 gmt"),
+            "{}",
+            requests[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let data = video(500, 3);
+        let (url, requests) = serve(vec![
+            (
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0".to_string(),
+                Vec::new(),
+            ),
+            (
+                "HTTP/1.1 200 OK\r\nContent-Length: 500".to_string(),
+                data.clone(),
+            ),
+        ]);
+        let (_dir, path) = target();
+
+        download_source(&url, &path, &options(10_000))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_fail_without_retrying() {
+        let (url, requests) = serve(vec![(
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0".to_string(),
+            Vec::new(),
+        )]);
+        let (_dir, path) = target();
+
+        let error = download_source(&url, &path, &options(10_000))
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("404"), "{}", error);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn pages_and_oversized_sources_are_refused() {
+        let page = b"<html>Not found</html>".to_vec();
+        let (url, _) = serve(vec![(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}",
+                page.len()
+            ),
+            page,
+        )]);
+        let (_dir, path) = target();
+        let error = download_source(&url, &path, &options(10_000))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("text/html"), "{}", error);
+
+        let (url, _) = serve(vec![(
+            "HTTP/1.1 200 OK\r\nContent-Length: 1000".to_string(),
+            video(1000, 4),
+        )]);
+        let error = download_source(&url, &path, &options(999))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("limit"), "{}", error);
+
+        // Without a Content-Type, the magic bytes give a non-video away
+        let (url, _) = serve(vec![(
+            "HTTP/1.1 200 OK\r\nContent-Length: 11".to_string(),
+            b"hello world".to_vec(),
+        )]);
+        let error = download_source(&url, &path, &options(10_000))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("video"), "{}", error);
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn cids_over_the_max_size_are_refused_before_fetching() {
+        let (_dir, path) = target();
+
+        let cid = cid_to_string(&hash_bytes_to_cid(HASH.to_vec(), 1_000_000));
+        let error = download_source(&cid, &path, &options(999_999))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("limit"), "{}", error);
+
+        let error = download_source(&encrypted_cid(), &path, &options(99_999))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("limit"), "{}", error);
+    }
+}
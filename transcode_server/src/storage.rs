@@ -0,0 +1,745 @@
+/*
+ * storage.rs
+ *
+ * Storage backends for the encrypted renditions and tracks of a job: an S5
+ * portal via tus, a local directory and S3-compatible object storage.
+ * Every backend addresses blobs by their S5 blob CID, so the encrypted CIDs
+ * handed to clients locate the blob whichever backend holds it.
+ */
+
+use crate::portals::PortalSet;
+use crate::registry::{encode, RegistryEntry, RegistryEntryJson};
+use crate::s5::{hash_blake3_file, hash_to_cid, upload_video};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use dotenv::var;
+use hmac::{Hmac, Mac};
+use reqwest_async::{header, Body, Client, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::task;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+// Where the local backend stores blobs unless STORAGE_DIR is set
+const DEFAULT_STORAGE_DIR: &str = "./temp/storage";
+
+// Region S3 requests are signed for unless S3_REGION is set
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+// A stored blob, as reported by `StorageBackend::stat`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlobStat {
+    pub size: u64,
+}
+
+// Where transcoded files are stored and fetched back from
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    // Name of the backend, as selected by STORAGE_BACKEND or the request
+    fn name(&self) -> &'static str;
+
+    // Stores the file at `path`, returning its blob CID
+    async fn put(&self, path: &str) -> Result<String, anyhow::Error>;
+
+    // Writes the blob with `cid` to the file at `path`
+    async fn get(&self, cid: &str, path: &str) -> Result<(), anyhow::Error>;
+
+    // The size of the blob with `cid`, or None if the backend doesn't hold it
+    async fn stat(&self, cid: &str) -> Result<Option<BlobStat>, anyhow::Error>;
+
+    // Removes the blob with `cid`
+    async fn delete(&self, cid: &str) -> Result<(), anyhow::Error>;
+
+    // Where the blob with `cid` is held, e.g. the URLs of the portals
+    fn locations(&self, cid: &str) -> Vec<String>;
+
+    // Checks that the backend holds the blob with `cid` intact: at the size
+    // its CID gives, and with its blake3 hash once fetched to `scratch_path`
+    async fn verify(&self, cid: &str, scratch_path: &str) -> Result<(), anyhow::Error> {
+        let (hash, size) = parse_blob_cid(cid)?;
+
+        match self.stat(cid).await? {
+            None => return Err(anyhow!("{} does not hold blob {}", self.name(), cid)),
+            Some(stat) if stat.size != size => {
+                return Err(anyhow!(
+                    "{} holds {} bytes of blob {}, expected {}",
+                    self.name(),
+                    stat.size,
+                    cid,
+                    size
+                ))
+            }
+            Some(_) => (),
+        }
+
+        let fetched = self.get(cid, scratch_path).await;
+        let stored_hash = match fetched {
+            Ok(()) => {
+                let path = scratch_path.to_string();
+                task::spawn_blocking(move || hash_blake3_file(path)).await?
+            }
+            Err(e) => Err(e),
+        };
+        let _ = fs::remove_file(scratch_path).await;
+
+        if stored_hash?.as_bytes() != &hash {
+            return Err(anyhow!(
+                "{} holds a corrupt copy of blob {}",
+                self.name(),
+                cid
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// A backend shared between jobs
+#[async_trait]
+impl<T: StorageBackend + ?Sized> StorageBackend for Arc<T> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    async fn put(&self, path: &str) -> Result<String, anyhow::Error> {
+        (**self).put(path).await
+    }
+
+    async fn get(&self, cid: &str, path: &str) -> Result<(), anyhow::Error> {
+        (**self).get(cid, path).await
+    }
+
+    async fn stat(&self, cid: &str) -> Result<Option<BlobStat>, anyhow::Error> {
+        (**self).stat(cid).await
+    }
+
+    async fn delete(&self, cid: &str) -> Result<(), anyhow::Error> {
+        (**self).delete(cid).await
+    }
+
+    fn locations(&self, cid: &str) -> Vec<String> {
+        (**self).locations(cid)
+    }
+
+    async fn verify(&self, cid: &str, scratch_path: &str) -> Result<(), anyhow::Error> {
+        (**self).verify(cid, scratch_path).await
+    }
+}
+
+// Returns the backend called `name` ("s5", "local" or "s3"), configured from
+// the environment. An empty name selects STORAGE_BACKEND, then S5.
+pub fn backend(name: &str) -> Result<Box<dyn StorageBackend>, anyhow::Error> {
+    let name = match name {
+        "" => var("STORAGE_BACKEND")
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "s5".to_string()),
+        name => name.to_string(),
+    };
+
+    match name.as_str() {
+        "s5" => Ok(Box::new(PortalSet::shared()?)),
+        "local" => Ok(Box::new(LocalBackend::from_env())),
+        "s3" => Ok(Box::new(S3Backend::from_env()?)),
+        name => Err(anyhow!("Unknown storage backend {}", name)),
+    }
+}
+
+// The S5 blob CID of the file at `path`: "u" followed by the base64url raw CID
+pub fn blob_cid(path: &str) -> Result<String, anyhow::Error> {
+    let hash = hash_blake3_file(path.to_string())?;
+    let file_size = std::fs::metadata(path)?.len();
+
+    let hash =
+        general_purpose::URL_SAFE_NO_PAD.encode([&[0x1fu8] as &[_], hash.as_bytes()].concat());
+
+    Ok(cid_to_string(&hash_to_cid(&hash, file_size)))
+}
+
+pub fn cid_to_string(cid: &[u8]) -> String {
+    format!("u{}", general_purpose::URL_SAFE_NO_PAD.encode(cid))
+}
+
+// The blake3 hash and size of the blob with `cid`
+pub fn parse_blob_cid(cid: &str) -> Result<([u8; 32], u64), anyhow::Error> {
+    check_cid(cid)?;
+    let bytes = general_purpose::URL_SAFE_NO_PAD.decode(&cid[1..])?;
+
+    parse_raw_blob_cid(&bytes).ok_or_else(|| anyhow!("{} is not a blake3 blob CID", cid))
+}
+
+// The blake3 hash and size of a blob from its raw CID: 0x26 (raw blob), 0x1f
+// (blake3), the hash, then the size little-endian with trailing zeros trimmed
+pub fn parse_raw_blob_cid(bytes: &[u8]) -> Option<([u8; 32], u64)> {
+    if bytes.len() < 34 || bytes.len() > 42 || bytes[0] != 0x26 || bytes[1] != 0x1f {
+        return None;
+    }
+
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&bytes[2..34]);
+
+    let mut size = [0; 8];
+    size[..bytes.len() - 34].copy_from_slice(&bytes[34..]);
+
+    Some((hash, u64::from_le_bytes(size)))
+}
+
+// Blob CIDs end up in paths and URLs, so anything but base64url is refused
+fn check_cid(cid: &str) -> Result<(), anyhow::Error> {
+    let valid = cid.starts_with('u')
+        && cid.len() > 1
+        && cid
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid blob CID {}", cid))
+    }
+}
+
+fn required_var(key: &str) -> Result<String, anyhow::Error> {
+    var(key)
+        .ok()
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| anyhow!("{} is not set", key))
+}
+
+// Streams the body of a successful response to the file at `path`
+async fn write_response(mut response: Response, path: &str) -> Result<(), anyhow::Error> {
+    let mut file = File::create(path).await?;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    Ok(())
+}
+
+async fn file_body(path: &str) -> Result<(Body, u64), anyhow::Error> {
+    let file = File::open(path).await?;
+    let size = file.metadata().await?.len();
+    let stream = FramedRead::new(file, BytesCodec::new()).map(|chunk| chunk.map(|c| c.freeze()));
+
+    Ok((Body::wrap_stream(stream), size))
+}
+
+// An S5 portal, uploaded to via tus
+pub struct S5Backend {
+    client: Client,
+    portal_url: String,
+    token: String,
+}
+
+impl S5Backend {
+    pub fn new(portal_url: &str, token: &str) -> Self {
+        S5Backend {
+            client: Client::new(),
+            portal_url: portal_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    // Requests the blob with `cid`, so its body can be read as it arrives
+    pub async fn open(&self, cid: &str) -> Result<Response, anyhow::Error> {
+        check_cid(cid)?;
+
+        Ok(self
+            .client
+            .get(format!("{}/s5/blob/{}", self.portal_url, cid))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?)
+    }
+
+    // The registry entry under `pk`, or None if the portal has none
+    pub async fn registry_entry(&self, pk: &[u8]) -> Result<Option<RegistryEntry>, anyhow::Error> {
+        let response = self
+            .client
+            .get(format!("{}/s5/registry", self.portal_url))
+            .query(&[("pk", encode(pk))])
+            .bearer_auth(&self.token)
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let json: RegistryEntryJson = response.error_for_status()?.json().await?;
+
+        Ok(Some(RegistryEntry::from_json(json)?))
+    }
+
+    pub async fn publish_registry_entry(&self, entry: &RegistryEntry) -> Result<(), anyhow::Error> {
+        self.client
+            .post(format!("{}/s5/registry", self.portal_url))
+            .bearer_auth(&self.token)
+            .json(&entry.to_json())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S5Backend {
+    fn name(&self) -> &'static str {
+        "s5"
+    }
+
+    async fn put(&self, path: &str) -> Result<String, anyhow::Error> {
+        let cid = upload_video(&self.portal_url, &self.token, path).await?;
+
+        Ok(cid_to_string(&cid))
+    }
+
+    async fn get(&self, cid: &str, path: &str) -> Result<(), anyhow::Error> {
+        write_response(self.open(cid).await?, path).await
+    }
+
+    async fn stat(&self, cid: &str) -> Result<Option<BlobStat>, anyhow::Error> {
+        check_cid(cid)?;
+
+        // Portals redirect blobs to wherever they are hosted, which may not
+        // answer HEAD, so ask for the first byte and read the size from the
+        // Content-Range
+        let response = self
+            .client
+            .get(format!("{}/s5/blob/{}", self.portal_url, cid))
+            .bearer_auth(&self.token)
+            .header(header::RANGE, "bytes=0-0")
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            StatusCode::PARTIAL_CONTENT => {
+                let size = response
+                    .headers()
+                    .get(header::CONTENT_RANGE)
+                    .and_then(|range| range.to_str().ok())
+                    .and_then(|range| range.rsplit('/').next())
+                    .and_then(|size| size.parse().ok())
+                    .ok_or_else(|| anyhow!("Portal sent no size for blob {}", cid))?;
+
+                Ok(Some(BlobStat { size }))
+            }
+            status if status.is_success() => {
+                let size = response
+                    .content_length()
+                    .ok_or_else(|| anyhow!("Portal sent no size for blob {}", cid))?;
+
+                Ok(Some(BlobStat { size }))
+            }
+            status => Err(anyhow!("Portal returned {} for blob {}", status, cid)),
+        }
+    }
+
+    async fn delete(&self, cid: &str) -> Result<(), anyhow::Error> {
+        check_cid(cid)?;
+
+        self.client
+            .delete(format!("{}/s5/delete/{}", self.portal_url, cid))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    fn locations(&self, _cid: &str) -> Vec<String> {
+        vec![self.portal_url.clone()]
+    }
+}
+
+// A directory on the local filesystem, one file per blob named by its CID
+pub struct LocalBackend {
+    dir: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalBackend { dir: dir.into() }
+    }
+
+    // Uses STORAGE_DIR, or ./temp/storage
+    pub fn from_env() -> Self {
+        LocalBackend::new(
+            var("STORAGE_DIR")
+                .ok()
+                .filter(|dir| !dir.is_empty())
+                .unwrap_or_else(|| DEFAULT_STORAGE_DIR.to_string()),
+        )
+    }
+
+    fn blob_path(&self, cid: &str) -> Result<PathBuf, anyhow::Error> {
+        check_cid(cid)?;
+
+        Ok(self.dir.join(cid))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, path: &str) -> Result<String, anyhow::Error> {
+        let cid = blob_cid(path)?;
+        let blob_path = self.blob_path(&cid)?;
+
+        // Copy next to the blob and rename, so a blob is never seen half written
+        fs::create_dir_all(&self.dir).await?;
+        let temp_path = blob_path.with_extension("tmp");
+        fs::copy(path, &temp_path).await?;
+        fs::rename(&temp_path, &blob_path).await?;
+
+        Ok(cid)
+    }
+
+    async fn get(&self, cid: &str, path: &str) -> Result<(), anyhow::Error> {
+        fs::copy(self.blob_path(cid)?, path).await?;
+
+        Ok(())
+    }
+
+    async fn stat(&self, cid: &str) -> Result<Option<BlobStat>, anyhow::Error> {
+        match fs::metadata(self.blob_path(cid)?).await {
+            Ok(metadata) => Ok(Some(BlobStat {
+                size: metadata.len(),
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, cid: &str) -> Result<(), anyhow::Error> {
+        match fs::remove_file(self.blob_path(cid)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn locations(&self, _cid: &str) -> Vec<String> {
+        vec![self.dir.display().to_string()]
+    }
+}
+
+// An S3-compatible bucket (AWS, MinIO, Garage, ...), with blobs keyed by CID.
+// Requests use path-style URLs and AWS Signature Version 4.
+pub struct S3Backend {
+    client: Client,
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Backend {
+    pub fn new(
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        access_key: &str,
+        secret_key: &str,
+    ) -> Result<Self, anyhow::Error> {
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .and_then(|rest| rest.split('/').next())
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| anyhow!("Invalid S3 endpoint {}", endpoint))?
+            .to_string();
+
+        Ok(S3Backend {
+            client: Client::new(),
+            endpoint,
+            host,
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+        })
+    }
+
+    // Uses S3_ENDPOINT, S3_BUCKET, S3_REGION, S3_ACCESS_KEY and S3_SECRET_KEY
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        S3Backend::new(
+            &required_var("S3_ENDPOINT")?,
+            &required_var("S3_BUCKET")?,
+            &var("S3_REGION")
+                .ok()
+                .filter(|region| !region.is_empty())
+                .unwrap_or_else(|| DEFAULT_S3_REGION.to_string()),
+            &required_var("S3_ACCESS_KEY")?,
+            &required_var("S3_SECRET_KEY")?,
+        )
+    }
+
+    // Builds a request for the object `cid`, signed with the payload left
+    // unsigned so uploads can be streamed
+    fn request(
+        &self,
+        method: reqwest_async::Method,
+        cid: &str,
+    ) -> Result<reqwest_async::RequestBuilder, anyhow::Error> {
+        check_cid(cid)?;
+
+        let path = format!("/{}/{}", self.bucket, cid);
+        let (amz_date, authorization) = self.sign(method.as_str(), &path, SystemTime::now());
+
+        Ok(self
+            .client
+            .request(method, format!("{}{}", self.endpoint, path))
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", "UNSIGNED-PAYLOAD")
+            .header(header::AUTHORIZATION, authorization))
+    }
+
+    // Returns the x-amz-date and Authorization headers of a request
+    fn sign(&self, method: &str, path: &str, time: SystemTime) -> (String, String) {
+        let (date, amz_date) = amz_dates(time);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:UNSIGNED-PAYLOAD\nx-amz-date:{}\n\n{}\nUNSIGNED-PAYLOAD",
+            method, path, self.host, amz_date, signed_headers
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = hmac_sha256(
+            format!("AWS4{}", self.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        let key = hmac_sha256(&key, self.region.as_bytes());
+        let key = hmac_sha256(&key, b"s3");
+        let key = hmac_sha256(&key, b"aws4_request");
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        (amz_date, authorization)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(&self, path: &str) -> Result<String, anyhow::Error> {
+        let cid = blob_cid(path)?;
+        let (body, size) = file_body(path).await?;
+
+        self.request(reqwest_async::Method::PUT, &cid)?
+            .header(header::CONTENT_LENGTH, size)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(cid)
+    }
+
+    async fn get(&self, cid: &str, path: &str) -> Result<(), anyhow::Error> {
+        let response = self
+            .request(reqwest_async::Method::GET, cid)?
+            .send()
+            .await?
+            .error_for_status()?;
+
+        write_response(response, path).await
+    }
+
+    async fn stat(&self, cid: &str) -> Result<Option<BlobStat>, anyhow::Error> {
+        let response = self
+            .request(reqwest_async::Method::HEAD, cid)?
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let response = response.error_for_status()?;
+        let size = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(|| anyhow!("S3 sent no size for object {}", cid))?;
+
+        Ok(Some(BlobStat { size }))
+    }
+
+    async fn delete(&self, cid: &str) -> Result<(), anyhow::Error> {
+        self.request(reqwest_async::Method::DELETE, cid)?
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    fn locations(&self, _cid: &str) -> Vec<String> {
+        vec![format!("{}/{}", self.endpoint, self.bucket)]
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// The date ("20230528") and timestamp ("20230528T120000Z") of a request, in UTC
+fn amz_dates(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let amz_date = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    );
+
+    (date, amz_date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn amz_dates_are_utc_civil_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(951_827_696);
+
+        assert_eq!(
+            amz_dates(time),
+            ("20000229".to_string(), "20000229T123456Z".to_string())
+        );
+    }
+
+    #[test]
+    fn s3_requests_are_signed_with_signature_version_4() {
+        let backend = S3Backend::new(
+            "http://s3.example.com:9000/",
+            "videos",
+            "eu-west-1",
+            "access",
+            "secret",
+        )
+        .unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let (amz_date, authorization) = backend.sign("PUT", "/videos/uJh8", time);
+
+        assert_eq!(amz_date, "20231114T221320Z");
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=access/20231114/eu-west-1/s3/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+             Signature=4ad38f16477e5a513002a707d1c14de358122faa4573b25ca1261db7505b93c9"
+        );
+    }
+
+    #[test]
+    fn s3_endpoints_need_a_host() {
+        assert!(S3Backend::new("s3.example.com", "videos", "us-east-1", "a", "s").is_err());
+        assert!(S3Backend::new("https://", "videos", "us-east-1", "a", "s").is_err());
+    }
+
+    #[tokio::test]
+    async fn blobs_stored_on_the_local_portal_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let portal_dir = dir.path().join("portal");
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let portal_url = format!("http://{}", addr);
+        let portal = crate::s5::local_portal(&portal_dir.to_string_lossy(), &portal_url).unwrap();
+        tokio::spawn(std::sync::Arc::new(portal).serve(addr));
+        while tokio::net::TcpStream::connect(addr).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+
+        // Uploads remember their URLs under ./temp, like the server does
+        fs::create_dir_all("./temp").await.unwrap();
+        let path = dir.path().join("rendition");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).await.unwrap();
+        let path = path.to_string_lossy().to_string();
+        let scratch_path = format!("{}.verify", path);
+
+        let backend = S5Backend::new(&portal_url, "");
+        let cid = backend.put(&path).await.unwrap();
+        assert_eq!(cid, blob_cid(&path).unwrap());
+        assert_eq!(
+            backend.stat(&cid).await.unwrap(),
+            Some(BlobStat {
+                size: data.len() as u64
+            })
+        );
+        backend.verify(&cid, &scratch_path).await.unwrap();
+
+        // A blob the portal doesn't hold, and one it holds a corrupt copy of
+        let missing = cid_to_string(&hash_to_cid(
+            &general_purpose::URL_SAFE_NO_PAD.encode([0x1f; 33]),
+            data.len() as u64,
+        ));
+        assert!(backend.verify(&missing, &scratch_path).await.is_err());
+
+        let blob_path = portal_dir.join("blobs").join(&cid);
+        let mut corrupt = data.clone();
+        corrupt[1000] ^= 1;
+        fs::remove_file(&blob_path).await.unwrap();
+        fs::write(&blob_path, &corrupt).await.unwrap();
+        let error = backend.verify(&cid, &scratch_path).await.unwrap_err();
+        assert!(error.to_string().contains("corrupt"), "{}", error);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::encode::TONE_MAP_FILTER;
+use crate::probe::{streams_of_type, StreamInfo};
+use tokio::process::Command;
+
+// Subtitle codecs that ffmpeg can convert to WebVTT. Bitmap subtitles
+// (PGS, VobSub, DVB) would need OCR and are skipped.
+const TEXT_SUBTITLE_CODECS: [&str; 6] = ["subrip", "ass", "ssa", "mov_text", "webvtt", "text"];
+
+// An audio or subtitle track, or a thumbnail, written out as its own file,
+// ready for encryption and upload
+#[derive(Debug, Clone)]
+pub struct ExtractedTrack {
+    pub kind: &'static str,
+    pub name: String,
+    pub language: String,
+    pub file_path_ue: String,
+    pub file_path: String,
+}
+
+// Returns the audio streams selected by the request. `audio_tracks` holds
+// indices among the source's audio streams; out of range indices are ignored.
+pub fn select_audio_streams<'a>(
+    streams: &'a [StreamInfo],
+    audio_tracks: &[u32],
+    all_audio_tracks: bool,
+) -> Vec<(usize, &'a StreamInfo)> {
+    streams_of_type(streams, "audio")
+        .into_iter()
+        .enumerate()
+        .filter(|(n, _)| all_audio_tracks || audio_tracks.contains(&(*n as u32)))
+        .collect()
+}
+
+// Encodes each selected audio stream to its own Opus file, keeping the source
+// channel layout and tagging it with the stream's language
+pub async fn extract_audio_tracks(
+    file_path: &str,
+    file_name: &str,
+    audio_streams: &[(usize, &StreamInfo)],
+) -> Result<Vec<ExtractedTrack>, anyhow::Error> {
+    let mut tracks = Vec::new();
+
+    for (n, stream) in audio_streams {
+        let language = stream.language();
+        let name = format!("audio_{}_{}", n, language);
+        let output_path = format!("./temp/to/transcode/{}_{}_ue.mp4", file_name, name);
+        let encrypted_path = format!("./temp/to/transcode/{}_{}.mp4", file_name, name);
+        let channels = stream.channels.unwrap_or(2);
+
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args([
+            "-i",
+            file_path,
+            "-map",
+            format!("0:a:{}", n).as_str(),
+            "-vn",
+            "-c:a",
+            "libopus",
+            "-b:a",
+            format!("{}k", 64 * channels.min(8)).as_str(),
+        ]);
+        if channels > 2 {
+            // Surround layouts need the Vorbis channel mapping family
+            cmd.args(["-mapping_family", "1"]);
+        }
+        cmd.args([
+            "-metadata:s:a:0",
+            format!("language={}", language).as_str(),
+            "-y",
+            output_path.as_str(),
+        ]);
+
+        let output = cmd.output().await?;
+        println!("{:?}", output);
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("Failed to extract audio track {}", name));
+        }
+
+        tracks.push(ExtractedTrack {
+            kind: "audio",
+            name,
+            language,
+            file_path_ue: output_path,
+            file_path: encrypted_path,
+        });
+    }
+
+    Ok(tracks)
+}
+
+// Converts every text subtitle stream of the source to WebVTT
+pub async fn extract_subtitles(
+    file_path: &str,
+    file_name: &str,
+    streams: &[StreamInfo],
+) -> Result<Vec<ExtractedTrack>, anyhow::Error> {
+    let mut tracks = Vec::new();
+
+    for (n, stream) in streams_of_type(streams, "subtitle").into_iter().enumerate() {
+        if !TEXT_SUBTITLE_CODECS.contains(&stream.codec_name.as_str()) {
+            println!(
+                "Skipping subtitle stream {} with non-text codec {}",
+                n, stream.codec_name
+            );
+            continue;
+        }
+
+        let language = stream.language();
+        let name = format!("subtitle_{}_{}", n, language);
+        let output_path = format!("./temp/to/transcode/{}_{}_ue.vtt", file_name, name);
+        let encrypted_path = format!("./temp/to/transcode/{}_{}.vtt", file_name, name);
+
+        let output = Command::new("ffmpeg")
+            .args([
+                "-i",
+                file_path,
+                "-map",
+                format!("0:s:{}", n).as_str(),
+                "-c:s",
+                "webvtt",
+                "-y",
+                output_path.as_str(),
+            ])
+            .output()
+            .await?;
+        println!("{:?}", output);
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("Failed to extract subtitle track {}", name));
+        }
+
+        tracks.push(ExtractedTrack {
+            kind: "subtitle",
+            name,
+            language,
+            file_path_ue: output_path,
+            file_path: encrypted_path,
+        });
+    }
+
+    Ok(tracks)
+}
+
+// Grabs the frame a tenth of the way into the source as a JPEG thumbnail 640
+// pixels wide, tone-mapped to SDR if the source is HDR
+pub async fn extract_thumbnail(
+    file_path: &str,
+    file_name: &str,
+    duration: f64,
+    hdr: bool,
+) -> Result<ExtractedTrack, anyhow::Error> {
+    let output_path = format!("./temp/to/transcode/{}_thumbnail_ue.jpg", file_name);
+    let encrypted_path = format!("./temp/to/transcode/{}_thumbnail.jpg", file_name);
+
+    let filter = if hdr {
+        format!("{},scale=640:-2", TONE_MAP_FILTER)
+    } else {
+        String::from("scale=640:-2")
+    };
+
+    let output = Command::new("ffmpeg")
+        .args([
+            "-ss",
+            format!("{:.3}", duration / 10.0).as_str(),
+            "-i",
+            file_path,
+            "-frames:v",
+            "1",
+            "-vf",
+            filter.as_str(),
+            "-q:v",
+            "3",
+            "-y",
+            output_path.as_str(),
+        ])
+        .output()
+        .await?;
+    println!("{:?}", output);
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("Failed to extract thumbnail"));
+    }
+
+    Ok(ExtractedTrack {
+        kind: "thumbnail",
+        name: String::from("thumbnail"),
+        language: String::new(),
+        file_path_ue: output_path,
+        file_path: encrypted_path,
+    })
+}
@@ -0,0 +1,613 @@
+use crate::http::{default_headers, AsyncHttpHandler, Headers, HttpMethod, HttpRequest};
+use crate::retry::{is_transient, sleep};
+use crate::upload_options::ProgressTracker;
+use crate::{
+    create_concat_final_headers, create_deferred_length_headers, create_headers, create_request,
+    create_upload_headers, create_with_upload_headers, fingerprint, headers, parse_create_response,
+    parse_delete_response, parse_server_info, parse_upload_info, parse_upload_response, read_chunk,
+    remember_upload, stream_len, ChecksumAlgorithm, Error, ServerInfo, TusExtension, UploadInfo,
+    UploadStore, DEFAULT_CHUNK_SIZE, MAX_CHECKSUM_ATTEMPTS,
+};
+use crate::{RetryPolicy, UploadOptions};
+use blocking::unblock;
+use futures_util::future::try_join_all;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Deref;
+use std::path::Path;
+
+/// Used to interact with a [tus](https://tus.io) endpoint without blocking. Offers the same operations as `Client`.
+pub struct AsyncClient<'a> {
+    use_method_override: bool,
+    http_handler: Box<dyn AsyncHttpHandler + 'a>,
+    auth_token: Option<String>,
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    upload_store: Option<Box<dyn UploadStore + 'a>>,
+    retry_policy: RetryPolicy,
+}
+
+impl<'a> AsyncClient<'a> {
+    /// Instantiates a new instance of `AsyncClient`. `http_handler` needs to implement the `AsyncHttpHandler` trait.
+    /// A default implementation of this trait for the async `reqwest` client is available by enabling the `async-reqwest` feature.
+    pub fn new(http_handler: impl AsyncHttpHandler + 'a) -> Self {
+        AsyncClient {
+            use_method_override: false,
+            http_handler: Box::new(http_handler),
+            auth_token: None,
+            checksum_algorithm: None,
+            upload_store: None,
+            retry_policy: RetryPolicy::none(),
+        }
+    }
+
+    /// Some environments might not support using the HTTP methods `PATCH` and `DELETE`. Use this method to create an `AsyncClient` which uses the `X-HTTP-METHOD-OVERRIDE` header to specify these methods instead.
+    pub fn with_method_override(http_handler: impl AsyncHttpHandler + 'a) -> Self {
+        AsyncClient {
+            use_method_override: true,
+            http_handler: Box::new(http_handler),
+            auth_token: None,
+            checksum_algorithm: None,
+            upload_store: None,
+            retry_policy: RetryPolicy::none(),
+        }
+    }
+
+    pub fn with_auth_token(mut self, auth_token: impl Into<String>) -> Self {
+        self.auth_token = Some(auth_token.into());
+        self
+    }
+
+    /// Send an `Upload-Checksum` header with every chunk, using the given algorithm. The server must advertise the algorithm in `ServerInfo::checksum_algorithms`.
+    /// Chunks rejected with `460 Checksum Mismatch` are sent again.
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum_algorithm = Some(algorithm);
+        self
+    }
+
+    /// Remember created uploads in `store`. `create`, `create_with_metadata` and the partial uploads of `upload_concurrently` then reuse an unexpired upload of the same file, if the server still has it, instead of creating a new file.
+    /// Uploading resumes such an upload from where it left off, even after the process restarts.
+    pub fn with_upload_store(mut self, store: impl UploadStore + 'a) -> Self {
+        self.upload_store = Some(Box::new(store));
+        self
+    }
+
+    /// Retry failed chunks and limit the duration of requests as set in `retry_policy`.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Get info about a file on the server.
+    pub async fn get_info(&self, url: &str) -> Result<UploadInfo, Error> {
+        let req = self.create_request(HttpMethod::Head, url, None, Some(default_headers()));
+
+        let response = self.http_handler.deref().handle_request(req).await?;
+
+        parse_upload_info(response)
+    }
+
+    /// Upload a file to the specified upload URL.
+    pub async fn upload(&self, url: &str, path: &Path) -> Result<(), Error> {
+        self.upload_with_chunk_size(url, path, DEFAULT_CHUNK_SIZE)
+            .await
+    }
+
+    /// Upload a file to the specified upload URL with the given chunk size.
+    pub async fn upload_with_chunk_size(
+        &self,
+        url: &str,
+        path: &Path,
+        chunk_size: usize,
+    ) -> Result<(), Error> {
+        self.upload_with_options(url, path, &UploadOptions::new().with_chunk_size(chunk_size))
+            .await
+    }
+
+    /// Upload a file to the specified upload URL, reporting progress and checking for cancellation as set in `options`.
+    pub async fn upload_with_options(
+        &self,
+        url: &str,
+        path: &Path,
+        options: &UploadOptions<'_>,
+    ) -> Result<(), Error> {
+        let mut source = FileSource::open(path).await?;
+        let len = source.len;
+        let tracker = ProgressTracker::new(options, Some(len as usize));
+        self.upload_range(url, &mut source, 0, len, &tracker).await
+    }
+
+    /// Upload the data of a reader, from its start, to the specified upload URL.
+    /// Like `upload`, this resumes from the offset reported by the server.
+    pub async fn upload_reader(
+        &self,
+        url: &str,
+        mut reader: impl Read + Seek,
+        options: &UploadOptions<'_>,
+    ) -> Result<(), Error> {
+        let len = stream_len(&mut reader)?;
+        let tracker = ProgressTracker::new(options, Some(len as usize));
+        self.upload_range(url, &mut ReaderSource(reader), 0, len, &tracker)
+            .await
+    }
+
+    /// Create a file on the server with the specified metadata and upload the data of a reader which can't seek, such as a pipe or data produced on the fly, receiving the upload URL of the file.
+    /// Requires the *creation-defer-length* extension, as the size is only known once the reader is exhausted. The upload can't be resumed if it is interrupted.
+    pub async fn upload_stream(
+        &self,
+        url: &str,
+        mut reader: impl Read,
+        metadata: HashMap<String, String>,
+        options: &UploadOptions<'_>,
+    ) -> Result<String, Error> {
+        let upload_url = self.create_with_deferred_length(url, metadata).await?;
+
+        let chunk_size = options.chunk_size();
+        let mut chunk = vec![0; chunk_size];
+        let mut next_chunk = vec![0; chunk_size];
+        let mut chunk_len = read_chunk(&mut reader, &mut chunk)?;
+        let mut progress = 0;
+        let tracker = ProgressTracker::new(options, None);
+
+        loop {
+            options.check_cancelled()?;
+
+            // Read ahead to find out whether this is the last chunk
+            let next_len = if chunk_len == chunk_size {
+                read_chunk(&mut reader, &mut next_chunk)?
+            } else {
+                0
+            };
+
+            let upload_length = match next_len {
+                0 => Some((progress + chunk_len) as u64),
+                _ => None,
+            };
+            let mut retries = 0;
+            let offset = loop {
+                let error = match self
+                    .upload_chunk(&upload_url, progress, &chunk[..chunk_len], upload_length)
+                    .await
+                {
+                    Ok(offset) => break offset,
+                    Err(error) => error,
+                };
+
+                // Only the current chunk is still at hand, so the server must have all data before it
+                let offset = self.resync_offset(&upload_url, error, &mut retries).await?;
+                if offset == progress + chunk_len {
+                    break offset;
+                }
+                if offset != progress {
+                    return Err(Error::WrongUploadOffsetError);
+                }
+            };
+            tracker.acknowledged(offset.saturating_sub(progress));
+            progress = offset;
+
+            if upload_length.is_some() {
+                break;
+            }
+
+            std::mem::swap(&mut chunk, &mut next_chunk);
+            chunk_len = next_len;
+        }
+
+        Ok(upload_url)
+    }
+
+    /// Create a file on the server and upload it as `parts` partial uploads sent concurrently, receiving the upload URL of the final file.
+    /// The partial uploads are joined on the server with the *concatenation* extension. If the server doesn't support it, the file is created and uploaded serially instead.
+    /// `Client` has no counterpart, as it would have to send the partial uploads from several threads, and an `HttpHandler` isn't required to be shared between threads.
+    pub async fn upload_concurrently(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: HashMap<String, String>,
+        parts: usize,
+        options: &UploadOptions<'_>,
+    ) -> Result<String, Error> {
+        let file_len = file_len(path).await?;
+        let server_info = self.get_server_info(url).await?;
+        if parts < 2
+            || file_len == 0
+            || !server_info
+                .extensions
+                .contains(&TusExtension::Concatenation)
+        {
+            let upload_url = self.create_with_metadata(url, path, metadata).await?;
+            self.upload_with_options(&upload_url, path, options).await?;
+            return Ok(upload_url);
+        }
+
+        let part_len = file_len.div_ceil(parts as u64);
+        let ranges: Vec<(u64, u64)> = (0..file_len)
+            .step_by(part_len as usize)
+            .map(|start| (start, part_len.min(file_len - start)))
+            .collect();
+
+        let file_fingerprint = match self.upload_store {
+            Some(_) => Some(file_fingerprint(url, path).await?),
+            None => None,
+        };
+
+        let tracker = ProgressTracker::new(options, Some(file_len as usize));
+
+        let partial_urls = try_join_all(ranges.into_iter().map(|(start, len)| {
+            let file_fingerprint = file_fingerprint.as_deref();
+            let tracker = &tracker;
+            async move {
+                let fingerprint = file_fingerprint
+                    .map(|file_fingerprint| format!("{}:{}-{}", file_fingerprint, start, len));
+                let mut source = FileSource::open(path).await?;
+                if let Some(partial_url) = self.stored_upload(fingerprint.as_deref()).await? {
+                    self.upload_range(&partial_url, &mut source, start, len, tracker)
+                        .await?;
+                    return Ok(partial_url);
+                }
+
+                let mut headers = create_headers(len, &HashMap::new());
+                headers.insert(headers::UPLOAD_CONCAT.to_owned(), "partial".to_owned());
+
+                let req = self.create_request(HttpMethod::Post, url, None, Some(headers));
+                let response = self.http_handler.deref().handle_request(req).await?;
+                let partial_url = remember_upload(
+                    self.upload_store.as_deref(),
+                    fingerprint.as_deref(),
+                    response,
+                )?;
+
+                self.upload_range(&partial_url, &mut source, start, len, tracker)
+                    .await?;
+
+                Ok::<_, Error>(partial_url)
+            }
+        }))
+        .await?;
+
+        let headers = create_concat_final_headers(&partial_urls, &metadata);
+        let req = self.create_request(HttpMethod::Post, url, None, Some(headers));
+        let response = self.http_handler.deref().handle_request(req).await?;
+
+        parse_create_response(response)
+    }
+
+    /// Upload `len` bytes of the source, starting at `start`, to the specified upload URL.
+    async fn upload_range(
+        &self,
+        url: &str,
+        source: &mut impl ChunkSource,
+        start: u64,
+        len: u64,
+        tracker: &ProgressTracker<'_, '_>,
+    ) -> Result<(), Error> {
+        let info = self.get_info(url).await?;
+
+        if let Some(total_size) = info.total_size {
+            if len as usize != total_size {
+                return Err(Error::UnequalSizeError);
+            }
+        }
+
+        let options = tracker.options();
+        let chunk_size = options.chunk_size();
+        let mut progress = info.bytes_uploaded;
+        tracker.resumed(progress);
+
+        if progress >= len as usize {
+            return Ok(());
+        }
+
+        let mut retries = 0;
+        loop {
+            options.check_cancelled()?;
+
+            let remaining = (len as usize - progress).min(chunk_size);
+            let chunk = source.read_at(start + progress as u64, remaining).await?;
+            if chunk.is_empty() {
+                return Err(Error::FileReadError);
+            }
+
+            match self.upload_chunk(url, progress, &chunk, None).await {
+                Ok(offset) => {
+                    tracker.acknowledged(offset.saturating_sub(progress));
+                    progress = offset;
+                    retries = 0;
+                }
+                Err(error) => {
+                    let offset = self.resync_offset(url, error, &mut retries).await?;
+                    tracker.acknowledged(offset.saturating_sub(progress));
+                    progress = offset;
+                }
+            }
+
+            if progress >= len as usize {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Upload a chunk to the specified upload URL at the given offset, receiving the offset after the chunk.
+    /// Pass `upload_length` with the last chunk of a file created with `create_with_deferred_length` to declare the size of the file.
+    pub async fn upload_chunk(
+        &self,
+        url: &str,
+        offset: usize,
+        chunk: &[u8],
+        upload_length: Option<u64>,
+    ) -> Result<usize, Error> {
+        let mut attempts = 0;
+        let response = loop {
+            let mut headers = create_upload_headers(offset, chunk, self.checksum_algorithm);
+            if let Some(upload_length) = upload_length {
+                headers.insert(headers::UPLOAD_LENGTH.to_owned(), upload_length.to_string());
+            }
+
+            let req = self.create_request(HttpMethod::Patch, url, Some(chunk), Some(headers));
+
+            let response = self.http_handler.deref().handle_request(req).await?;
+            attempts += 1;
+
+            // The chunk was corrupted in transit, send it again
+            if response.status_code == 460 && attempts < MAX_CHECKSUM_ATTEMPTS {
+                continue;
+            }
+
+            break response;
+        };
+
+        parse_upload_response(response)
+    }
+
+    /// Get information about the tus server
+    pub async fn get_server_info(&self, url: &str) -> Result<ServerInfo, Error> {
+        let req = self.create_request(HttpMethod::Options, url, None, None);
+
+        let response = self.http_handler.deref().handle_request(req).await?;
+
+        parse_server_info(response)
+    }
+
+    /// Create a file on the server, receiving the upload URL of the file.
+    pub async fn create(&self, url: &str, path: &Path) -> Result<String, Error> {
+        self.create_with_metadata(url, path, HashMap::new()).await
+    }
+
+    /// Create a file on the server including the specified metadata, receiving the upload URL of the file.
+    pub async fn create_with_metadata(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: HashMap<String, String>,
+    ) -> Result<String, Error> {
+        let fingerprint = match self.upload_store {
+            Some(_) => Some(file_fingerprint(url, path).await?),
+            None => None,
+        };
+        if let Some(upload_url) = self.stored_upload(fingerprint.as_deref()).await? {
+            return Ok(upload_url);
+        }
+
+        let headers = create_headers(file_len(path).await?, &metadata);
+
+        let req = self.create_request(HttpMethod::Post, url, None, Some(headers));
+
+        let response = self.http_handler.deref().handle_request(req).await?;
+
+        remember_upload(
+            self.upload_store.as_deref(),
+            fingerprint.as_deref(),
+            response,
+        )
+    }
+
+    /// Create a file on the server for the data of a reader, including the specified metadata, receiving the upload URL of the file. Upload the data with `upload_reader`.
+    pub async fn create_from_reader(
+        &self,
+        url: &str,
+        reader: &mut impl Seek,
+        metadata: HashMap<String, String>,
+    ) -> Result<String, Error> {
+        let headers = create_headers(stream_len(reader)?, &metadata);
+
+        let req = self.create_request(HttpMethod::Post, url, None, Some(headers));
+
+        let response = self.http_handler.deref().handle_request(req).await?;
+
+        parse_create_response(response)
+    }
+
+    /// Create a file on the server including the specified metadata, sending up to `chunk_size` bytes of the file in the same request, receiving the upload URL of the file.
+    /// Requires the *creation-with-upload* extension. `upload` then sends the rest of the file, starting after the bytes accepted by the server.
+    pub async fn create_with_upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: HashMap<String, String>,
+        chunk_size: usize,
+    ) -> Result<String, Error> {
+        let fingerprint = match self.upload_store {
+            Some(_) => Some(file_fingerprint(url, path).await?),
+            None => None,
+        };
+        if let Some(upload_url) = self.stored_upload(fingerprint.as_deref()).await? {
+            return Ok(upload_url);
+        }
+
+        let mut source = FileSource::open(path).await?;
+        let file_len = source.len;
+        let chunk = source.read_at(0, chunk_size).await?;
+
+        let headers =
+            create_with_upload_headers(file_len, &metadata, &chunk, self.checksum_algorithm);
+
+        let req = self.create_request(HttpMethod::Post, url, Some(&chunk), Some(headers));
+
+        let response = self.http_handler.deref().handle_request(req).await?;
+
+        remember_upload(
+            self.upload_store.as_deref(),
+            fingerprint.as_deref(),
+            response,
+        )
+    }
+
+    /// Create a file on the server including the specified metadata without declaring its size, receiving the upload URL of the file.
+    /// Requires the *creation-defer-length* extension. Send the data with `upload_chunk`, declaring the size of the file with the last chunk.
+    pub async fn create_with_deferred_length(
+        &self,
+        url: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<String, Error> {
+        let headers = create_deferred_length_headers(&metadata);
+
+        let req = self.create_request(HttpMethod::Post, url, None, Some(headers));
+
+        let response = self.http_handler.deref().handle_request(req).await?;
+
+        parse_create_response(response)
+    }
+
+    /// Delete a file on the server.
+    pub async fn delete(&self, url: &str) -> Result<(), Error> {
+        let req = self.create_request(HttpMethod::Delete, url, None, Some(default_headers()));
+
+        let response = self.http_handler.deref().handle_request(req).await?;
+
+        parse_delete_response(response)
+    }
+
+    /// Waits out a transient failure and gets the offset of the upload from the server, counting the attempt in `retries`.
+    /// Fails with `error` if it isn't transient or the retry policy is exhausted.
+    async fn resync_offset(
+        &self,
+        url: &str,
+        mut error: Error,
+        retries: &mut usize,
+    ) -> Result<usize, Error> {
+        loop {
+            if !is_transient(&error) || *retries >= self.retry_policy.max_retries() {
+                return Err(error);
+            }
+            *retries += 1;
+            sleep(self.retry_policy.backoff(*retries)).await;
+
+            match self.get_info(url).await {
+                Ok(info) => return Ok(info.bytes_uploaded),
+                Err(e) => error = e,
+            }
+        }
+    }
+
+    /// The upload URL stored for the fingerprint, if the upload hasn't expired and the server still has it.
+    async fn stored_upload(&self, fingerprint: Option<&str>) -> Result<Option<String>, Error> {
+        let (store, fingerprint) = match (&self.upload_store, fingerprint) {
+            (Some(store), Some(fingerprint)) => (store, fingerprint),
+            _ => return Ok(None),
+        };
+
+        match store.get(fingerprint)? {
+            Some(upload)
+                if !upload.is_expired() && self.get_info(&upload.upload_url).await.is_ok() =>
+            {
+                Ok(Some(upload.upload_url))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn create_request<'b>(
+        &self,
+        method: HttpMethod,
+        url: &str,
+        body: Option<&'b [u8]>,
+        headers: Option<Headers>,
+    ) -> HttpRequest<'b> {
+        let mut req = create_request(
+            self.use_method_override,
+            self.auth_token.as_deref(),
+            method,
+            url,
+            body,
+            headers,
+        );
+        req.timeout = self.retry_policy.request_timeout();
+        req
+    }
+}
+
+/// Where `upload_range` reads the data of an upload from.
+trait ChunkSource {
+    /// Reads up to `len` bytes starting at `offset`, fewer only at the end of the data.
+    async fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error>;
+}
+
+/// A reader given by the caller, read on the task of the upload.
+struct ReaderSource<R>(R);
+
+impl<R: Read + Seek> ChunkSource for ReaderSource<R> {
+    async fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        read_at(&mut self.0, offset, len)
+    }
+}
+
+/// A file, read on the thread pool of `blocking` so reads don't block the executor.
+struct FileSource {
+    file: Option<File>,
+    len: u64,
+}
+
+impl FileSource {
+    async fn open(path: &Path) -> Result<Self, Error> {
+        let path = path.to_owned();
+        let (file, len) = unblock(move || {
+            let file = File::open(path)?;
+            let len = file.metadata()?.len();
+            Ok::<_, Error>((file, len))
+        })
+        .await?;
+
+        Ok(FileSource {
+            file: Some(file),
+            len,
+        })
+    }
+}
+
+impl ChunkSource for FileSource {
+    async fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        // The file moves to the blocking thread for the read and comes back with the chunk
+        let mut file = self.file.take().ok_or(Error::FileReadError)?;
+        let (file, chunk) = unblock(move || {
+            let chunk = read_at(&mut file, offset, len);
+            (file, chunk)
+        })
+        .await;
+        self.file = Some(file);
+
+        chunk
+    }
+}
+
+fn read_at(reader: &mut (impl Read + Seek), offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut chunk = vec![0; len];
+    let bytes_read = read_chunk(reader, &mut chunk)?;
+    chunk.truncate(bytes_read);
+
+    Ok(chunk)
+}
+
+/// The size of the file at `path`, read without blocking the executor.
+async fn file_len(path: &Path) -> Result<u64, Error> {
+    let path = path.to_owned();
+    Ok(unblock(move || path.metadata()).await?.len())
+}
+
+/// The fingerprint of the file at `path`, which reads its metadata, taken without blocking the executor.
+async fn file_fingerprint(url: &str, path: &Path) -> Result<String, Error> {
+    let (url, path) = (url.to_owned(), path.to_owned());
+    unblock(move || fingerprint(&url, &path)).await
+}
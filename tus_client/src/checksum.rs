@@ -0,0 +1,130 @@
+use sha1::Digest;
+#[cfg(feature = "server")]
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// Enumerates the checksum algorithms `tus_client` can use with the *checksum* extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumAlgorithm {
+    Sha1,
+    Sha256,
+    Md5,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// Picks the strongest of the algorithms advertised by the server, if `tus_client` supports any of them.
+    pub fn preferred(advertised: &[ChecksumAlgorithm]) -> Option<ChecksumAlgorithm> {
+        [
+            ChecksumAlgorithm::Blake3,
+            ChecksumAlgorithm::Sha256,
+            ChecksumAlgorithm::Sha1,
+            ChecksumAlgorithm::Md5,
+        ]
+        .iter()
+        .find(|algorithm| advertised.contains(algorithm))
+        .copied()
+    }
+
+    /// The name of the algorithm as used in the `Upload-Checksum` and `Tus-Checksum-Algorithm` headers.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha1 => "sha1",
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Md5 => "md5",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Returns the value of the `Upload-Checksum` header for `data`.
+    pub fn header_value(&self, data: &[u8]) -> String {
+        let digest = match self {
+            ChecksumAlgorithm::Sha1 => sha1::Sha1::digest(data).to_vec(),
+            ChecksumAlgorithm::Sha256 => sha2::Sha256::digest(data).to_vec(),
+            ChecksumAlgorithm::Md5 => md5::Md5::digest(data).to_vec(),
+            ChecksumAlgorithm::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+        };
+
+        format!("{} {}", self.name(), base64::encode(&digest))
+    }
+
+    /// Returns the value of the `Upload-Checksum` header for the data read from `reader`.
+    #[cfg(feature = "server")]
+    pub(crate) fn header_value_of_reader(&self, reader: &mut impl Read) -> io::Result<String> {
+        let digest = match self {
+            ChecksumAlgorithm::Sha1 => digest_reader(sha1::Sha1::new(), reader)?,
+            ChecksumAlgorithm::Sha256 => digest_reader(sha2::Sha256::new(), reader)?,
+            ChecksumAlgorithm::Md5 => digest_reader(md5::Md5::new(), reader)?,
+            ChecksumAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                io::copy(reader, &mut hasher)?;
+                hasher.finalize().as_bytes().to_vec()
+            }
+        };
+
+        Ok(format!("{} {}", self.name(), base64::encode(&digest)))
+    }
+}
+
+#[cfg(feature = "server")]
+fn digest_reader(mut digest: impl Digest + Write, reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    io::copy(reader, &mut digest)?;
+    Ok(digest.finalize().to_vec())
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "sha1" => Ok(ChecksumAlgorithm::Sha1),
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "md5" => Ok(ChecksumAlgorithm::Md5),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_values_name_the_algorithm_and_encode_the_digest() {
+        assert_eq!(
+            ChecksumAlgorithm::Sha1.header_value(b"hello"),
+            "sha1 qvTGHdzF6KLavt4PO0gs2a6pQ00="
+        );
+        assert_eq!(
+            ChecksumAlgorithm::Sha256.header_value(b"hello"),
+            "sha256 LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+        assert_eq!(
+            ChecksumAlgorithm::Md5.header_value(b"hello"),
+            "md5 XUFAKrxLKna5cZ2REBfFkg=="
+        );
+        assert_eq!(
+            ChecksumAlgorithm::Blake3.header_value(b"hello"),
+            format!(
+                "blake3 {}",
+                base64::encode(blake3::hash(b"hello").as_bytes())
+            )
+        );
+    }
+
+    #[test]
+    fn algorithms_are_parsed_by_name() {
+        assert_eq!(" SHA256 ".parse(), Ok(ChecksumAlgorithm::Sha256));
+        assert_eq!("crc32".parse::<ChecksumAlgorithm>(), Err(()));
+    }
+
+    #[test]
+    fn the_strongest_advertised_algorithm_is_preferred() {
+        assert_eq!(
+            ChecksumAlgorithm::preferred(&[ChecksumAlgorithm::Md5, ChecksumAlgorithm::Sha256]),
+            Some(ChecksumAlgorithm::Sha256)
+        );
+        assert_eq!(ChecksumAlgorithm::preferred(&[]), None);
+    }
+}
@@ -22,11 +22,23 @@ pub const X_HTTP_METHOD_OVERRIDE: &'static str = "x-http-method-override";
 /// Use this header if its environment does not support the PATCH or DELETE methods.
 pub const CONTENT_TYPE: &'static str = "content-type";
 
-/// Use this header if its environment does not support the PATCH or DELETE methods.
-//pub const UPLOAD_DEFER_LENGTH: &'static str = "upload-defer-length";
+/// Indicates that the size of the upload is not known yet, and will be declared by a later request.
+pub const UPLOAD_DEFER_LENGTH: &str = "upload-defer-length";
 
 /// Use this header if its environment does not support the PATCH or DELETE methods.
 pub const UPLOAD_METADATA: &'static str = "upload-metadata";
 
 /// Use this header if its environment does not support the PATCH or DELETE methods.
 pub const LOCATION: &'static str = "location";
+
+/// The checksum algorithm and the Base64 encoded checksum of the current chunk.
+pub const UPLOAD_CHECKSUM: &str = "upload-checksum";
+
+/// A comma-separated list of the checksum algorithms supported by the server.
+pub const TUS_CHECKSUM_ALGORITHM: &str = "tus-checksum-algorithm";
+
+/// Marks an upload as `partial`, or as the `final` concatenation of the listed partial uploads.
+pub const UPLOAD_CONCAT: &str = "upload-concat";
+
+/// The time, in RFC 7231 format, after which the server discards an unfinished upload.
+pub const UPLOAD_EXPIRES: &str = "upload-expires";
@@ -1,6 +1,8 @@
 use crate::Error;
+use async_trait::async_trait;
 use std::collections::HashMap;
 use std::fmt;
+use std::time::Duration;
 
 /// An alias for `HashMap<String, String>`, which represents a set of HTTP headers and their values.
 pub type Headers = HashMap<String, String>;
@@ -28,6 +30,8 @@ pub struct HttpRequest<'a> {
     pub headers: Headers,
     pub url: String,
     pub body: Option<&'a [u8]>,
+    /// How long the request may take before failing. Handlers which can't limit single requests ignore it.
+    pub timeout: Option<Duration>,
 }
 
 /// Represents an HTTP response from the server.
@@ -42,6 +46,12 @@ pub trait HttpHandler {
     fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error>;
 }
 
+/// The required trait used by `tus_client::AsyncClient` to represent a handler to execute `HttpRequest`s without blocking.
+#[async_trait]
+pub trait AsyncHttpHandler: Send + Sync {
+    async fn handle_request(&self, req: HttpRequest<'_>) -> Result<HttpResponse, Error>;
+}
+
 /// Returns the default headers required to make requests to an tus enabled endpoint.
 pub fn default_headers() -> Headers {
     let mut map = Headers::new();
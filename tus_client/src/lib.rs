@@ -41,8 +41,19 @@
 //! ```
 //!
 //! `upload` (and `upload_with_chunk_size`) will automatically resume the upload from where it left off, if the upload transfer is interrupted.
+//!
+//! ## Async usage
+//!
+//! `AsyncClient` offers the same operations for handlers implementing the `AsyncHttpHandler` trait, so uploads don't block an async runtime. Enable the `async-reqwest` feature to include an implementation of this trait for the async `reqwest::Client` (reqwest 0.11).
+//!
+//! ```toml
+//! # Other parts of Cargo.toml omitted for brevity
+//! [dependencies]
+//! tus_client = {version = "x.x.x", features = ["async-reqwest"]}
+//! ```
 #![doc(html_root_url = "https://docs.rs/tus_client/0.1.1")]
-use crate::http::{default_headers, Headers, HttpHandler, HttpMethod, HttpRequest};
+use crate::http::{default_headers, Headers, HttpHandler, HttpMethod, HttpRequest, HttpResponse};
+use retry::is_transient;
 use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::fmt::{Display, Formatter};
@@ -53,21 +64,47 @@ use std::num::ParseIntError;
 use std::ops::Deref;
 use std::path::Path;
 use std::str::FromStr;
+use std::time::SystemTime;
+use upload_options::ProgressTracker;
 
+mod async_client;
+mod checksum;
 mod headers;
 /// Contains the `HttpHandler` trait and related structs. This module is only relevant when implement `HttpHandler` manually.
 pub mod http;
+mod retry;
+#[cfg(feature = "server")]
+mod server;
+mod upload_options;
+mod upload_store;
 
 #[cfg(feature = "reqwest")]
 mod reqwest;
 
+#[cfg(feature = "async-reqwest")]
+mod reqwest_async;
+
+pub use async_client::AsyncClient;
+pub use checksum::ChecksumAlgorithm;
+pub use retry::RetryPolicy;
+#[cfg(feature = "server")]
+pub use server::{FinishedUpload, TusServer};
+pub use upload_options::{CancellationToken, Progress, UploadOptions};
+pub use upload_store::{fingerprint, FileUploadStore, StoredUpload, UploadStore};
+
 const DEFAULT_CHUNK_SIZE: usize = 5 * 1024 * 1024;
 
+/// How many times a chunk is sent before giving up on repeated `460 Checksum Mismatch` responses.
+const MAX_CHECKSUM_ATTEMPTS: usize = 3;
+
 /// Used to interact with a [tus](https://tus.io) endpoint.
 pub struct Client<'a> {
     use_method_override: bool,
     http_handler: Box<dyn HttpHandler + 'a>,
     auth_token: Option<String>,
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    upload_store: Option<Box<dyn UploadStore + 'a>>,
+    retry_policy: RetryPolicy,
 }
 
 impl<'a> Client<'a> {
@@ -78,6 +115,9 @@ impl<'a> Client<'a> {
             use_method_override: false,
             http_handler: Box::new(http_handler),
             auth_token: None,
+            checksum_algorithm: None,
+            upload_store: None,
+            retry_policy: RetryPolicy::none(),
         }
     }
 
@@ -87,6 +127,9 @@ impl<'a> Client<'a> {
             use_method_override: true,
             http_handler: Box::new(http_handler),
             auth_token: None,
+            checksum_algorithm: None,
+            upload_store: None,
+            retry_policy: RetryPolicy::none(),
         }
     }
 
@@ -95,48 +138,33 @@ impl<'a> Client<'a> {
         self
     }
 
+    /// Send an `Upload-Checksum` header with every chunk, using the given algorithm. The server must advertise the algorithm in `ServerInfo::checksum_algorithms`.
+    /// Chunks rejected with `460 Checksum Mismatch` are sent again.
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum_algorithm = Some(algorithm);
+        self
+    }
+
+    /// Remember created uploads in `store`. `create` and `create_with_metadata` then return the upload URL of an unexpired upload of the same file, if the server still has it, instead of creating a new file.
+    /// `upload` resumes such an upload from where it left off, even after the process restarts.
+    pub fn with_upload_store(mut self, store: impl UploadStore + 'a) -> Self {
+        self.upload_store = Some(Box::new(store));
+        self
+    }
+
+    /// Retry failed chunks and limit the duration of requests as set in `retry_policy`.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
     /// Get info about a file on the server.
     pub fn get_info(&self, url: &str) -> Result<UploadInfo, Error> {
         let req = self.create_request(HttpMethod::Head, url, None, Some(default_headers()));
 
         let response = self.http_handler.deref().handle_request(req)?;
 
-        let bytes_uploaded = response.headers.get_by_key(headers::UPLOAD_OFFSET);
-        let total_size = response
-            .headers
-            .get_by_key(headers::UPLOAD_LENGTH)
-            .and_then(|l| l.parse::<usize>().ok());
-        let metadata = response
-            .headers
-            .get_by_key(headers::UPLOAD_METADATA)
-            .and_then(|data| base64::decode(data).ok())
-            .map(|decoded| {
-                String::from_utf8(decoded).unwrap().split(';').fold(
-                    HashMap::new(),
-                    |mut acc, key_val| {
-                        let mut parts = key_val.splitn(2, ':');
-                        if let Some(key) = parts.next() {
-                            acc.insert(
-                                String::from(key),
-                                String::from(parts.next().unwrap_or_default()),
-                            );
-                        }
-                        acc
-                    },
-                )
-            });
-
-        if response.status_code.to_string().starts_with('4') || bytes_uploaded.is_none() {
-            return Err(Error::NotFoundError);
-        }
-
-        let bytes_uploaded = bytes_uploaded.unwrap().parse()?;
-
-        Ok(UploadInfo {
-            bytes_uploaded,
-            total_size,
-            metadata,
-        })
+        parse_upload_info(response)
     }
 
     /// Upload a file to the specified upload URL.
@@ -151,62 +179,176 @@ impl<'a> Client<'a> {
         path: &Path,
         chunk_size: usize,
     ) -> Result<(), Error> {
-        let info = self.get_info(url)?;
+        self.upload_with_options(url, path, &UploadOptions::new().with_chunk_size(chunk_size))
+    }
+
+    /// Upload a file to the specified upload URL, reporting progress and checking for cancellation as set in `options`.
+    pub fn upload_with_options(
+        &self,
+        url: &str,
+        path: &Path,
+        options: &UploadOptions,
+    ) -> Result<(), Error> {
         let file = File::open(path)?;
-        let file_len = file.metadata()?.len();
+        self.upload_reader(url, BufReader::new(file), options)
+    }
+
+    /// Upload the data of a reader, from its start, to the specified upload URL.
+    /// Like `upload`, this resumes from the offset reported by the server.
+    pub fn upload_reader(
+        &self,
+        url: &str,
+        mut reader: impl Read + Seek,
+        options: &UploadOptions,
+    ) -> Result<(), Error> {
+        let info = self.get_info(url)?;
+        let len = stream_len(&mut reader)?;
 
         if let Some(total_size) = info.total_size {
-            if file_len as usize != total_size {
+            if len as usize != total_size {
                 return Err(Error::UnequalSizeError);
             }
         }
 
-        let mut reader = BufReader::new(&file);
-        let mut buffer = vec![0; chunk_size];
+        let mut buffer = vec![0; options.chunk_size()];
         let mut progress = info.bytes_uploaded;
+        let tracker = ProgressTracker::new(options, Some(len as usize));
+        tracker.resumed(progress);
+
+        if progress >= len as usize {
+            return Ok(());
+        }
 
         reader.seek(SeekFrom::Start(progress as u64))?;
 
+        let mut retries = 0;
         loop {
+            options.check_cancelled()?;
+
             let bytes_read = reader.read(&mut buffer)?;
             if bytes_read == 0 {
                 return Err(Error::FileReadError);
             }
 
-            let req = self.create_request(
-                HttpMethod::Patch,
-                url,
-                Some(&buffer[..bytes_read]),
-                Some(create_upload_headers(progress)),
-            );
-
-            let response = self.http_handler.deref().handle_request(req)?;
-
-            if response.status_code == 409 {
-                return Err(Error::WrongUploadOffsetError);
+            match self.upload_chunk(url, progress, &buffer[..bytes_read], None) {
+                Ok(offset) => {
+                    tracker.acknowledged(offset.saturating_sub(progress));
+                    progress = offset;
+                    retries = 0;
+                }
+                Err(error) => {
+                    let offset = self.resync_offset(url, error, &mut retries)?;
+                    tracker.acknowledged(offset.saturating_sub(progress));
+                    progress = offset;
+                    reader.seek(SeekFrom::Start(progress as u64))?;
+                }
             }
 
-            if response.status_code == 404 {
-                return Err(Error::NotFoundError);
+            if progress >= len as usize {
+                break;
             }
+        }
 
-            if response.status_code != 204 {
-                return Err(Error::UnexpectedStatusCode(response.status_code));
-            }
+        Ok(())
+    }
 
-            let upload_offset = match response.headers.get_by_key(headers::UPLOAD_OFFSET) {
-                Some(offset) => Ok(offset),
-                None => Err(Error::MissingHeader(headers::UPLOAD_OFFSET.to_owned())),
-            }?;
+    /// Create a file on the server with the specified metadata and upload the data of a reader which can't seek, such as a pipe or data produced on the fly, receiving the upload URL of the file.
+    /// Requires the *creation-defer-length* extension, as the size is only known once the reader is exhausted. The upload can't be resumed if it is interrupted.
+    pub fn upload_stream(
+        &self,
+        url: &str,
+        mut reader: impl Read,
+        metadata: HashMap<String, String>,
+        options: &UploadOptions,
+    ) -> Result<String, Error> {
+        let upload_url = self.create_with_deferred_length(url, metadata)?;
+
+        let chunk_size = options.chunk_size();
+        let mut chunk = vec![0; chunk_size];
+        let mut next_chunk = vec![0; chunk_size];
+        let mut chunk_len = read_chunk(&mut reader, &mut chunk)?;
+        let mut progress = 0;
+        let tracker = ProgressTracker::new(options, None);
+
+        loop {
+            options.check_cancelled()?;
+
+            // Read ahead to find out whether this is the last chunk
+            let next_len = if chunk_len == chunk_size {
+                read_chunk(&mut reader, &mut next_chunk)?
+            } else {
+                0
+            };
 
-            progress = upload_offset.parse()?;
+            let upload_length = match next_len {
+                0 => Some((progress + chunk_len) as u64),
+                _ => None,
+            };
+            let mut retries = 0;
+            let offset = loop {
+                let error = match self.upload_chunk(
+                    &upload_url,
+                    progress,
+                    &chunk[..chunk_len],
+                    upload_length,
+                ) {
+                    Ok(offset) => break offset,
+                    Err(error) => error,
+                };
+
+                // Only the current chunk is still at hand, so the server must have all data before it
+                let offset = self.resync_offset(&upload_url, error, &mut retries)?;
+                if offset == progress + chunk_len {
+                    break offset;
+                }
+                if offset != progress {
+                    return Err(Error::WrongUploadOffsetError);
+                }
+            };
+            tracker.acknowledged(offset.saturating_sub(progress));
+            progress = offset;
 
-            if progress >= file_len as usize {
+            if upload_length.is_some() {
                 break;
             }
+
+            std::mem::swap(&mut chunk, &mut next_chunk);
+            chunk_len = next_len;
         }
 
-        Ok(())
+        Ok(upload_url)
+    }
+
+    /// Upload a chunk to the specified upload URL at the given offset, receiving the offset after the chunk.
+    /// Pass `upload_length` with the last chunk of a file created with `create_with_deferred_length` to declare the size of the file.
+    pub fn upload_chunk(
+        &self,
+        url: &str,
+        offset: usize,
+        chunk: &[u8],
+        upload_length: Option<u64>,
+    ) -> Result<usize, Error> {
+        let mut attempts = 0;
+        let response = loop {
+            let mut headers = create_upload_headers(offset, chunk, self.checksum_algorithm);
+            if let Some(upload_length) = upload_length {
+                headers.insert(headers::UPLOAD_LENGTH.to_owned(), upload_length.to_string());
+            }
+
+            let req = self.create_request(HttpMethod::Patch, url, Some(chunk), Some(headers));
+
+            let response = self.http_handler.deref().handle_request(req)?;
+            attempts += 1;
+
+            // The chunk was corrupted in transit, send it again
+            if response.status_code == 460 && attempts < MAX_CHECKSUM_ATTEMPTS {
+                continue;
+            }
+
+            break response;
+        };
+
+        parse_upload_response(response)
     }
 
     /// Get information about the tus server
@@ -215,37 +357,7 @@ impl<'a> Client<'a> {
 
         let response = self.http_handler.deref().handle_request(req)?;
 
-        if ![200_usize, 204].contains(&response.status_code) {
-            return Err(Error::UnexpectedStatusCode(response.status_code));
-        }
-
-        let supported_versions: Vec<String> = response
-            .headers
-            .get_by_key(headers::TUS_VERSION)
-            .unwrap()
-            .split(',')
-            .map(String::from)
-            .collect();
-        let extensions: Vec<TusExtension> =
-            if let Some(ext) = response.headers.get_by_key(headers::TUS_EXTENSION) {
-                ext.split(',')
-                    .map(str::parse)
-                    .filter(Result::is_ok)
-                    .map(Result::unwrap)
-                    .collect()
-            } else {
-                Vec::new()
-            };
-        let max_upload_size = response
-            .headers
-            .get_by_key(headers::TUS_MAX_SIZE)
-            .and_then(|h| h.parse::<usize>().ok());
-
-        Ok(ServerInfo {
-            supported_versions,
-            extensions,
-            max_upload_size,
-        })
+        parse_server_info(response)
     }
 
     /// Create a file on the server, receiving the upload URL of the file.
@@ -260,39 +372,93 @@ impl<'a> Client<'a> {
         path: &Path,
         metadata: HashMap<String, String>,
     ) -> Result<String, Error> {
-        let mut headers = default_headers();
-        headers.insert(
-            headers::UPLOAD_LENGTH.to_owned(),
-            path.metadata()?.len().to_string(),
-        );
-        if !metadata.is_empty() {
-            let data = metadata
-                .iter()
-                .map(|(key, value)| format!("{} {}", key, base64::encode(value)))
-                .collect::<Vec<_>>()
-                .join(",");
-            headers.insert(headers::UPLOAD_METADATA.to_owned(), data);
+        let fingerprint = match self.upload_store {
+            Some(_) => Some(fingerprint(url, path)?),
+            None => None,
+        };
+        if let Some(upload_url) = self.stored_upload(fingerprint.as_deref())? {
+            return Ok(upload_url);
         }
 
+        let headers = create_headers(path.metadata()?.len(), &metadata);
+
         let req = self.create_request(HttpMethod::Post, url, None, Some(headers));
 
         let response = self.http_handler.deref().handle_request(req)?;
 
-        if response.status_code == 413 {
-            return Err(Error::FileTooLarge);
-        }
+        remember_upload(
+            self.upload_store.as_deref(),
+            fingerprint.as_deref(),
+            response,
+        )
+    }
 
-        if response.status_code != 201 {
-            return Err(Error::UnexpectedStatusCode(response.status_code));
-        }
+    /// Create a file on the server for the data of a reader, including the specified metadata, receiving the upload URL of the file. Upload the data with `upload_reader`.
+    pub fn create_from_reader(
+        &self,
+        url: &str,
+        reader: &mut impl Seek,
+        metadata: HashMap<String, String>,
+    ) -> Result<String, Error> {
+        let headers = create_headers(stream_len(reader)?, &metadata);
+
+        let req = self.create_request(HttpMethod::Post, url, None, Some(headers));
 
-        let location = response.headers.get_by_key(headers::LOCATION);
+        let response = self.http_handler.deref().handle_request(req)?;
 
-        if location.is_none() {
-            return Err(Error::MissingHeader(headers::LOCATION.to_owned()));
+        parse_create_response(response)
+    }
+
+    /// Create a file on the server including the specified metadata, sending up to `chunk_size` bytes of the file in the same request, receiving the upload URL of the file.
+    /// Requires the *creation-with-upload* extension. `upload` then sends the rest of the file, starting after the bytes accepted by the server.
+    pub fn create_with_upload(
+        &self,
+        url: &str,
+        path: &Path,
+        metadata: HashMap<String, String>,
+        chunk_size: usize,
+    ) -> Result<String, Error> {
+        let fingerprint = match self.upload_store {
+            Some(_) => Some(fingerprint(url, path)?),
+            None => None,
+        };
+        if let Some(upload_url) = self.stored_upload(fingerprint.as_deref())? {
+            return Ok(upload_url);
         }
 
-        Ok(location.unwrap().to_owned())
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut chunk = Vec::new();
+        file.take(chunk_size as u64).read_to_end(&mut chunk)?;
+
+        let headers =
+            create_with_upload_headers(file_len, &metadata, &chunk, self.checksum_algorithm);
+
+        let req = self.create_request(HttpMethod::Post, url, Some(&chunk), Some(headers));
+
+        let response = self.http_handler.deref().handle_request(req)?;
+
+        remember_upload(
+            self.upload_store.as_deref(),
+            fingerprint.as_deref(),
+            response,
+        )
+    }
+
+    /// Create a file on the server including the specified metadata without declaring its size, receiving the upload URL of the file.
+    /// Requires the *creation-defer-length* extension. Send the data with `upload_chunk`, declaring the size of the file with the last chunk.
+    pub fn create_with_deferred_length(
+        &self,
+        url: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<String, Error> {
+        let headers = create_deferred_length_headers(&metadata);
+
+        let req = self.create_request(HttpMethod::Post, url, None, Some(headers));
+
+        let response = self.http_handler.deref().handle_request(req)?;
+
+        parse_create_response(response)
     }
 
     /// Delete a file on the server.
@@ -301,11 +467,44 @@ impl<'a> Client<'a> {
 
         let response = self.http_handler.deref().handle_request(req)?;
 
-        if response.status_code != 204 {
-            return Err(Error::UnexpectedStatusCode(response.status_code));
+        parse_delete_response(response)
+    }
+
+    /// Waits out a transient failure and gets the offset of the upload from the server, counting the attempt in `retries`.
+    /// Fails with `error` if it isn't transient or the retry policy is exhausted.
+    fn resync_offset(
+        &self,
+        url: &str,
+        mut error: Error,
+        retries: &mut usize,
+    ) -> Result<usize, Error> {
+        loop {
+            if !is_transient(&error) || *retries >= self.retry_policy.max_retries() {
+                return Err(error);
+            }
+            *retries += 1;
+            std::thread::sleep(self.retry_policy.backoff(*retries));
+
+            match self.get_info(url) {
+                Ok(info) => return Ok(info.bytes_uploaded),
+                Err(e) => error = e,
+            }
         }
+    }
 
-        Ok(())
+    /// The upload URL stored for the fingerprint, if the upload hasn't expired and the server still has it.
+    fn stored_upload(&self, fingerprint: Option<&str>) -> Result<Option<String>, Error> {
+        let (store, fingerprint) = match (&self.upload_store, fingerprint) {
+            (Some(store), Some(fingerprint)) => (store, fingerprint),
+            _ => return Ok(None),
+        };
+
+        match store.get(fingerprint)? {
+            Some(upload) if !upload.is_expired() && self.get_info(&upload.upload_url).is_ok() => {
+                Ok(Some(upload.upload_url))
+            }
+            _ => Ok(None),
+        }
     }
 
     fn create_request<'b>(
@@ -315,29 +514,16 @@ impl<'a> Client<'a> {
         body: Option<&'b [u8]>,
         headers: Option<Headers>,
     ) -> HttpRequest<'b> {
-        let mut headers = headers.unwrap_or_default();
-
-        if let Some(auth_token) = &self.auth_token {
-            headers.insert("Authorization".to_owned(), format!("Bearer {}", auth_token));
-            println!("{}", format!("Bearer {}", auth_token));
-        }
-
-        let method = if self.use_method_override {
-            headers.insert(
-                headers::X_HTTP_METHOD_OVERRIDE.to_owned(),
-                method.to_string(),
-            );
-            HttpMethod::Post
-        } else {
-            method
-        };
-
-        HttpRequest {
+        let mut req = create_request(
+            self.use_method_override,
+            self.auth_token.as_deref(),
             method,
-            url: String::from(url),
+            url,
             body,
             headers,
-        }
+        );
+        req.timeout = self.retry_policy.request_timeout();
+        req
     }
 }
 
@@ -350,6 +536,8 @@ pub struct UploadInfo {
     pub total_size: Option<usize>,
     /// Metadata supplied when the file was created.
     pub metadata: Option<HashMap<String, String>>,
+    /// When the server discards the unfinished upload, if the server supports the *expiration* extension.
+    pub expires: Option<SystemTime>,
 }
 
 /// Describes the tus enabled server.
@@ -361,6 +549,8 @@ pub struct ServerInfo {
     pub extensions: Vec<TusExtension>,
     /// The maximum supported total size of a file.
     pub max_upload_size: Option<usize>,
+    /// The checksum algorithms supported by the server, if it supports the *checksum* extension.
+    pub checksum_algorithms: Vec<ChecksumAlgorithm>,
 }
 
 /// Enumerates the extensions to the tus protocol.
@@ -376,6 +566,10 @@ pub enum TusExtension {
     Termination,
     /// The server supports parallel uploads of a single file.
     Concatenation,
+    /// The server accepts the first chunk of a file in the request creating it.
+    CreationWithUpload,
+    /// The server supports creating files without declaring their size.
+    CreationDeferLength,
 }
 
 impl FromStr for TusExtension {
@@ -388,6 +582,8 @@ impl FromStr for TusExtension {
             "checksum" => Ok(TusExtension::Checksum),
             "termination" => Ok(TusExtension::Termination),
             "concatenation" => Ok(TusExtension::Concatenation),
+            "creation-with-upload" => Ok(TusExtension::CreationWithUpload),
+            "creation-defer-length" => Ok(TusExtension::CreationDeferLength),
             _ => Err(()),
         }
     }
@@ -416,6 +612,16 @@ pub enum Error {
     FileTooLarge,
     /// An error occurred in the HTTP handler.
     HttpHandlerError(String),
+    /// The server kept rejecting a chunk because its checksum did not match.
+    ChecksumMismatch,
+    /// The upload was cancelled through its `CancellationToken`.
+    Cancelled,
+    /// A header couldn't be sent because its name or value isn't valid in HTTP.
+    InvalidHeader(String),
+    /// The `Upload-Metadata` header sent by the server couldn't be decoded.
+    MalformedMetadata(String),
+    /// The server didn't send the `Tus-Version` header listing the protocol versions it supports.
+    MissingVersion,
 }
 
 impl Display for Error {
@@ -431,6 +637,11 @@ impl Display for Error {
             Error::WrongUploadOffsetError => "The client tried to upload the file with an incorrect offset".to_string(),
             Error::FileTooLarge => "The specified file is larger that what is supported by the server".to_string(),
             Error::HttpHandlerError(message) => format!("An error occurred in the HTTP handler: {}", message),
+            Error::ChecksumMismatch => "The server kept rejecting a chunk because its checksum did not match".to_string(),
+            Error::Cancelled => "The upload was cancelled".to_string(),
+            Error::InvalidHeader(header_name) => format!("The '{}' header has an invalid name or value", header_name),
+            Error::MalformedMetadata(pair) => format!("Unable to decode the metadata sent by the server: '{}'", pair),
+            Error::MissingVersion => "The server didn't send the versions of the tus protocol it supports".to_string(),
         };
 
         write!(f, "{}", message)?;
@@ -465,12 +676,704 @@ impl HeaderMap for HashMap<String, String> {
     }
 }
 
-fn create_upload_headers(progress: usize) -> Headers {
+fn create_request<'b>(
+    use_method_override: bool,
+    auth_token: Option<&str>,
+    method: HttpMethod,
+    url: &str,
+    body: Option<&'b [u8]>,
+    headers: Option<Headers>,
+) -> HttpRequest<'b> {
+    let mut headers = headers.unwrap_or_default();
+
+    if let Some(auth_token) = auth_token {
+        headers.insert("Authorization".to_owned(), format!("Bearer {}", auth_token));
+    }
+
+    let method = if use_method_override {
+        headers.insert(
+            headers::X_HTTP_METHOD_OVERRIDE.to_owned(),
+            method.to_string(),
+        );
+        HttpMethod::Post
+    } else {
+        method
+    };
+
+    HttpRequest {
+        method,
+        url: String::from(url),
+        body,
+        headers,
+        timeout: None,
+    }
+}
+
+fn parse_upload_info(response: HttpResponse) -> Result<UploadInfo, Error> {
+    let bytes_uploaded = response.headers.get_by_key(headers::UPLOAD_OFFSET);
+    let total_size = response
+        .headers
+        .get_by_key(headers::UPLOAD_LENGTH)
+        .and_then(|l| l.parse::<usize>().ok());
+    let metadata = response
+        .headers
+        .get_by_key(headers::UPLOAD_METADATA)
+        .map(|data| parse_metadata(data))
+        .transpose()?;
+
+    if response.status_code >= 500 {
+        return Err(Error::UnexpectedStatusCode(response.status_code));
+    }
+
+    if response.status_code.to_string().starts_with('4') || bytes_uploaded.is_none() {
+        return Err(Error::NotFoundError);
+    }
+
+    let bytes_uploaded = bytes_uploaded.unwrap().parse()?;
+    let expires = parse_expires(&response);
+
+    Ok(UploadInfo {
+        bytes_uploaded,
+        total_size,
+        metadata,
+        expires,
+    })
+}
+
+/// Decodes an `Upload-Metadata` header: comma-separated pairs of a key and a Base64 encoded value, separated by a space. The value may be left out.
+fn parse_metadata(data: &str) -> Result<HashMap<String, String>, Error> {
+    let mut metadata = HashMap::new();
+
+    for pair in data
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next().unwrap_or_default();
+        let value = match parts.next().map(str::trim) {
+            Some(encoded) if !encoded.is_empty() => base64::decode(encoded)
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or_else(|| Error::MalformedMetadata(pair.to_owned()))?,
+            _ => String::new(),
+        };
+
+        metadata.insert(key.to_owned(), value);
+    }
+
+    Ok(metadata)
+}
+
+fn parse_expires(response: &HttpResponse) -> Option<SystemTime> {
+    response
+        .headers
+        .get_by_key(headers::UPLOAD_EXPIRES)
+        .and_then(|expires| httpdate::parse_http_date(expires).ok())
+}
+
+/// Returns the new upload offset acknowledged by the server.
+fn parse_upload_response(response: HttpResponse) -> Result<usize, Error> {
+    if response.status_code == 409 {
+        return Err(Error::WrongUploadOffsetError);
+    }
+
+    if response.status_code == 404 {
+        return Err(Error::NotFoundError);
+    }
+
+    if response.status_code == 460 {
+        return Err(Error::ChecksumMismatch);
+    }
+
+    if response.status_code != 204 {
+        return Err(Error::UnexpectedStatusCode(response.status_code));
+    }
+
+    let upload_offset = match response.headers.get_by_key(headers::UPLOAD_OFFSET) {
+        Some(offset) => Ok(offset),
+        None => Err(Error::MissingHeader(headers::UPLOAD_OFFSET.to_owned())),
+    }?;
+
+    Ok(upload_offset.parse()?)
+}
+
+fn parse_server_info(response: HttpResponse) -> Result<ServerInfo, Error> {
+    if ![200_usize, 204].contains(&response.status_code) {
+        return Err(Error::UnexpectedStatusCode(response.status_code));
+    }
+
+    let supported_versions: Vec<String> = response
+        .headers
+        .get_by_key(headers::TUS_VERSION)
+        .ok_or(Error::MissingVersion)?
+        .split(',')
+        .map(|version| version.trim().to_owned())
+        .collect();
+    let extensions: Vec<TusExtension> =
+        if let Some(ext) = response.headers.get_by_key(headers::TUS_EXTENSION) {
+            ext.split(',')
+                .map(str::parse)
+                .filter(Result::is_ok)
+                .map(Result::unwrap)
+                .collect()
+        } else {
+            Vec::new()
+        };
+    let max_upload_size = response
+        .headers
+        .get_by_key(headers::TUS_MAX_SIZE)
+        .and_then(|h| h.parse::<usize>().ok());
+    let checksum_algorithms: Vec<ChecksumAlgorithm> = response
+        .headers
+        .get_by_key(headers::TUS_CHECKSUM_ALGORITHM)
+        .map(|algorithms| {
+            algorithms
+                .split(',')
+                .filter_map(|algorithm| algorithm.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(ServerInfo {
+        supported_versions,
+        extensions,
+        max_upload_size,
+        checksum_algorithms,
+    })
+}
+
+fn create_headers(upload_length: u64, metadata: &HashMap<String, String>) -> Headers {
+    let mut headers = default_headers();
+    headers.insert(headers::UPLOAD_LENGTH.to_owned(), upload_length.to_string());
+    insert_metadata_header(&mut headers, metadata);
+    headers
+}
+
+fn create_with_upload_headers(
+    upload_length: u64,
+    metadata: &HashMap<String, String>,
+    chunk: &[u8],
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+) -> Headers {
+    let mut headers = create_headers(upload_length, metadata);
+    headers.insert(
+        headers::CONTENT_TYPE.to_owned(),
+        "application/offset+octet-stream".to_owned(),
+    );
+    if let Some(algorithm) = checksum_algorithm {
+        headers.insert(
+            headers::UPLOAD_CHECKSUM.to_owned(),
+            algorithm.header_value(chunk),
+        );
+    }
+    headers
+}
+
+fn create_deferred_length_headers(metadata: &HashMap<String, String>) -> Headers {
+    let mut headers = default_headers();
+    headers.insert(headers::UPLOAD_DEFER_LENGTH.to_owned(), "1".to_owned());
+    insert_metadata_header(&mut headers, metadata);
+    headers
+}
+
+fn create_concat_final_headers(
+    partial_urls: &[String],
+    metadata: &HashMap<String, String>,
+) -> Headers {
+    let mut headers = default_headers();
+    headers.insert(
+        headers::UPLOAD_CONCAT.to_owned(),
+        format!("final;{}", partial_urls.join(" ")),
+    );
+    insert_metadata_header(&mut headers, metadata);
+    headers
+}
+
+fn insert_metadata_header(headers: &mut Headers, metadata: &HashMap<String, String>) {
+    if !metadata.is_empty() {
+        headers.insert(
+            headers::UPLOAD_METADATA.to_owned(),
+            encode_metadata(metadata),
+        );
+    }
+}
+
+fn encode_metadata(metadata: &HashMap<String, String>) -> String {
+    metadata
+        .iter()
+        .map(|(key, value)| format!("{} {}", key, base64::encode(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_create_response(response: HttpResponse) -> Result<String, Error> {
+    if response.status_code == 413 {
+        return Err(Error::FileTooLarge);
+    }
+
+    if response.status_code != 201 {
+        return Err(Error::UnexpectedStatusCode(response.status_code));
+    }
+
+    let location = response.headers.get_by_key(headers::LOCATION);
+
+    if location.is_none() {
+        return Err(Error::MissingHeader(headers::LOCATION.to_owned()));
+    }
+
+    Ok(location.unwrap().to_owned())
+}
+
+/// Parses the response to a creation request, storing the new upload under the fingerprint.
+fn remember_upload(
+    store: Option<&(dyn UploadStore + '_)>,
+    fingerprint: Option<&str>,
+    response: HttpResponse,
+) -> Result<String, Error> {
+    let expires = parse_expires(&response);
+    let upload_url = parse_create_response(response)?;
+
+    if let (Some(store), Some(fingerprint)) = (store, fingerprint) {
+        store.set(
+            fingerprint,
+            StoredUpload {
+                upload_url: upload_url.clone(),
+                expires,
+            },
+        )?;
+    }
+
+    Ok(upload_url)
+}
+
+fn parse_delete_response(response: HttpResponse) -> Result<(), Error> {
+    if response.status_code != 204 {
+        return Err(Error::UnexpectedStatusCode(response.status_code));
+    }
+
+    Ok(())
+}
+
+/// The length of the data of a reader, leaving its position unchanged.
+fn stream_len(reader: &mut impl Seek) -> Result<u64, Error> {
+    let position = reader.stream_position()?;
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(position))?;
+    Ok(len)
+}
+
+/// Fills `buffer` from the reader, stopping early only at the end of the data. Returns the number of bytes read.
+fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => filled += bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+fn create_upload_headers(
+    progress: usize,
+    chunk: &[u8],
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+) -> Headers {
     let mut headers = default_headers();
     headers.insert(
         headers::CONTENT_TYPE.to_owned(),
         "application/offset+octet-stream".to_owned(),
     );
     headers.insert(headers::UPLOAD_OFFSET.to_owned(), progress.to_string());
+    if let Some(algorithm) = checksum_algorithm {
+        headers.insert(
+            headers::UPLOAD_CHECKSUM.to_owned(),
+            algorithm.header_value(chunk),
+        );
+    }
     headers
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    const BASE_URL: &str = "http://localhost:1080/files";
+
+    const UPLOAD_URL: &str = "http://localhost:1080/files/1";
+
+    /// A *tus* server holding a single upload in memory. It answers the next `PATCH` requests with the status codes in `failures`, without storing their chunks.
+    #[derive(Default)]
+    struct FakeServer {
+        data: Mutex<Vec<u8>>,
+        length: Mutex<Option<usize>>,
+        failures: Mutex<VecDeque<usize>>,
+        expires: Option<SystemTime>,
+        requests: Mutex<Vec<(String, Headers)>>,
+    }
+
+    impl FakeServer {
+        fn failing(failures: &[usize]) -> Self {
+            let server = FakeServer::default();
+            server.failures.lock().unwrap().extend(failures);
+            server
+        }
+
+        /// How many requests were made with `method`.
+        fn count(&self, method: &str) -> usize {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(request_method, _)| request_method == method)
+                .count()
+        }
+
+        fn data(&self) -> Vec<u8> {
+            self.data.lock().unwrap().clone()
+        }
+    }
+
+    impl HttpHandler for &FakeServer {
+        fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((req.method.to_string(), req.headers.clone()));
+
+            let mut data = self.data.lock().unwrap();
+            let mut length = self.length.lock().unwrap();
+            let mut headers = default_headers();
+            let status_code = match req.method {
+                HttpMethod::Post => {
+                    data.clear();
+                    *length = req
+                        .headers
+                        .get_by_key(headers::UPLOAD_LENGTH)
+                        .and_then(|length| length.parse().ok());
+                    headers.insert(headers::LOCATION.to_owned(), UPLOAD_URL.to_owned());
+                    if let Some(expires) = self.expires {
+                        headers.insert(
+                            headers::UPLOAD_EXPIRES.to_owned(),
+                            httpdate::fmt_http_date(expires),
+                        );
+                    }
+                    201
+                }
+                HttpMethod::Head => {
+                    headers.insert(headers::UPLOAD_OFFSET.to_owned(), data.len().to_string());
+                    if let Some(length) = *length {
+                        headers.insert(headers::UPLOAD_LENGTH.to_owned(), length.to_string());
+                    }
+                    200
+                }
+                HttpMethod::Patch => {
+                    let offset = req
+                        .headers
+                        .get_by_key(headers::UPLOAD_OFFSET)
+                        .and_then(|offset| offset.parse::<usize>().ok());
+                    match self.failures.lock().unwrap().pop_front() {
+                        Some(status_code) => status_code,
+                        None if offset != Some(data.len()) => 409,
+                        None => {
+                            data.extend_from_slice(req.body.unwrap_or_default());
+                            headers
+                                .insert(headers::UPLOAD_OFFSET.to_owned(), data.len().to_string());
+                            204
+                        }
+                    }
+                }
+                _ => 405,
+            };
+
+            Ok(HttpResponse {
+                headers,
+                status_code,
+            })
+        }
+    }
+
+    fn source_file(dir: &TempDir, len: usize) -> (PathBuf, Vec<u8>) {
+        let data = (0..len).map(|i| (i * 31 % 251) as u8).collect::<Vec<_>>();
+        let path = dir.path().join("source");
+        std::fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    #[test]
+    fn checksum_mismatches_send_the_chunk_again() {
+        let server = FakeServer::failing(&[460, 460]);
+        let client = Client::new(&server).with_checksum(ChecksumAlgorithm::Sha256);
+
+        assert_eq!(
+            client.upload_chunk(UPLOAD_URL, 0, b"hello", None).unwrap(),
+            5
+        );
+        assert_eq!(server.count("Patch"), 3);
+        assert_eq!(server.data(), b"hello");
+
+        let requests = server.requests.lock().unwrap();
+        assert_eq!(
+            requests[0].1.get_by_key(headers::UPLOAD_CHECKSUM).unwrap(),
+            "sha256 LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+    }
+
+    #[test]
+    fn checksum_mismatches_give_up_after_max_attempts() {
+        let server = FakeServer::failing(&[460; MAX_CHECKSUM_ATTEMPTS + 1]);
+        let client = Client::new(&server).with_checksum(ChecksumAlgorithm::Sha256);
+
+        match client.upload_chunk(UPLOAD_URL, 0, b"hello", None) {
+            Err(Error::ChecksumMismatch) => (),
+            other => panic!("Expected ChecksumMismatch, got {:?}", other),
+        }
+        assert_eq!(server.count("Patch"), MAX_CHECKSUM_ATTEMPTS);
+        assert!(server.data().is_empty());
+    }
+
+    #[test]
+    fn parse_metadata_decodes_values() {
+        let metadata =
+            parse_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==, is_confidential")
+                .unwrap();
+
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata["filename"], "world_domination_plan.pdf");
+        assert_eq!(metadata["is_confidential"], "");
+    }
+
+    #[test]
+    fn parse_metadata_skips_empty_pairs() {
+        assert!(parse_metadata("").unwrap().is_empty());
+        assert_eq!(parse_metadata(",key dmFsdWU=,").unwrap()["key"], "value");
+    }
+
+    #[test]
+    fn parse_metadata_rejects_malformed_values() {
+        match parse_metadata("key not*base64") {
+            Err(Error::MalformedMetadata(pair)) => assert_eq!(pair, "key not*base64"),
+            other => panic!("Expected MalformedMetadata, got {:?}", other),
+        }
+
+        // Valid Base64, but not UTF-8
+        assert!(parse_metadata("key //8=").is_err());
+    }
+
+    #[test]
+    fn parse_metadata_reads_encoded_metadata() {
+        let mut metadata = HashMap::new();
+        metadata.insert("filename".to_owned(), "video.mp4".to_owned());
+        metadata.insert("title".to_owned(), "A title, with a comma".to_owned());
+
+        assert_eq!(
+            parse_metadata(&encode_metadata(&metadata)).unwrap(),
+            metadata
+        );
+    }
+
+    #[test]
+    fn uploads_are_resumed_from_the_stored_upload_url() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 25);
+        let store_path = dir.path().join("uploads");
+        let server = FakeServer::default();
+
+        // The first process sends one chunk before it stops
+        let client = Client::new(&server).with_upload_store(FileUploadStore::new(&store_path));
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        client
+            .upload_chunk(&upload_url, 0, &data[..10], None)
+            .unwrap();
+
+        let client = Client::new(&server).with_upload_store(FileUploadStore::new(&store_path));
+        assert_eq!(client.create(BASE_URL, &path).unwrap(), upload_url);
+        client
+            .upload_with_chunk_size(&upload_url, &path, 10)
+            .unwrap();
+
+        assert_eq!(server.count("Post"), 1);
+        assert_eq!(server.count("Patch"), 3);
+        assert_eq!(server.data(), data);
+    }
+
+    #[test]
+    fn expired_stored_uploads_are_replaced() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = source_file(&dir, 25);
+        let store_path = dir.path().join("uploads");
+        let store = FileUploadStore::new(&store_path);
+        let fingerprint = fingerprint(BASE_URL, &path).unwrap();
+        store
+            .set(
+                &fingerprint,
+                StoredUpload {
+                    upload_url: format!("{}/0", BASE_URL),
+                    expires: Some(SystemTime::now() - Duration::from_secs(60)),
+                },
+            )
+            .unwrap();
+
+        let expires = UNIX_EPOCH + Duration::from_secs(4_000_000_000);
+        let server = FakeServer {
+            expires: Some(expires),
+            ..FakeServer::default()
+        };
+        let client = Client::new(&server).with_upload_store(FileUploadStore::new(&store_path));
+
+        assert_eq!(client.create(BASE_URL, &path).unwrap(), UPLOAD_URL);
+        assert_eq!(server.count("Head"), 0);
+        assert_eq!(
+            store.get(&fingerprint).unwrap(),
+            Some(StoredUpload {
+                upload_url: UPLOAD_URL.to_owned(),
+                expires: Some(expires),
+            })
+        );
+    }
+
+    #[test]
+    fn progress_is_reported_for_every_acknowledged_chunk() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 25);
+        let server = FakeServer::default();
+        let client = Client::new(&server);
+        let reports = Mutex::new(Vec::new());
+        let options = UploadOptions::new()
+            .with_chunk_size(10)
+            .with_progress(|progress| {
+                reports
+                    .lock()
+                    .unwrap()
+                    .push((progress.bytes_sent, progress.total))
+            });
+
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        client
+            .upload_with_options(&upload_url, &path, &options)
+            .unwrap();
+        drop(options);
+
+        assert_eq!(server.data(), data);
+        assert_eq!(
+            reports.into_inner().unwrap(),
+            vec![(10, Some(25)), (20, Some(25)), (25, Some(25))]
+        );
+    }
+
+    #[test]
+    fn cancelled_uploads_stop_before_the_next_chunk() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 25);
+        let server = FakeServer::default();
+        let client = Client::new(&server);
+        let cancellation_token = CancellationToken::new();
+        let token = cancellation_token.clone();
+        let options = UploadOptions::new()
+            .with_chunk_size(10)
+            .with_cancellation_token(cancellation_token)
+            .with_progress(move |progress| {
+                if progress.bytes_sent >= 20 {
+                    token.cancel();
+                }
+            });
+
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        match client.upload_with_options(&upload_url, &path, &options) {
+            Err(Error::Cancelled) => (),
+            other => panic!("Expected Cancelled, got {:?}", other),
+        }
+
+        assert_eq!(server.count("Patch"), 2);
+        assert_eq!(server.data(), &data[..20]);
+    }
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy::new().with_backoff(Duration::from_millis(0), Duration::from_millis(0))
+    }
+
+    #[test]
+    fn transient_failures_are_retried_from_the_server_offset() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 25);
+        let server = FakeServer::failing(&[503, 409]);
+        let client = Client::new(&server).with_retry_policy(retry_policy());
+        let options = UploadOptions::new().with_chunk_size(10);
+
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        client
+            .upload_with_options(&upload_url, &path, &options)
+            .unwrap();
+
+        assert_eq!(server.data(), data);
+        assert_eq!(server.count("Patch"), 5);
+        assert_eq!(server.count("Head"), 3);
+    }
+
+    #[test]
+    fn offset_conflicts_resume_from_the_offset_of_the_server() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 30);
+        let server = FakeServer::default();
+        let client = Client::new(&server).with_retry_policy(retry_policy());
+        // The server got the second chunk through an earlier request, whose response was lost
+        let options = UploadOptions::new()
+            .with_chunk_size(10)
+            .with_progress(|progress| {
+                if progress.bytes_sent == 10 {
+                    server.data.lock().unwrap().extend_from_slice(&data[10..20]);
+                }
+            });
+
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        client
+            .upload_with_options(&upload_url, &path, &options)
+            .unwrap();
+
+        assert_eq!(server.data(), data);
+        assert_eq!(server.count("Patch"), 3);
+        assert_eq!(server.count("Head"), 2);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = source_file(&dir, 25);
+        let server = FakeServer::failing(&[404]);
+        let client = Client::new(&server).with_retry_policy(retry_policy());
+
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        match client.upload(&upload_url, &path) {
+            Err(Error::NotFoundError) => (),
+            other => panic!("Expected NotFoundError, got {:?}", other),
+        }
+
+        assert_eq!(server.count("Patch"), 1);
+        assert_eq!(server.count("Head"), 1);
+    }
+
+    #[test]
+    fn retries_give_up_after_max_retries() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = source_file(&dir, 25);
+        let server = FakeServer::failing(&[503, 502, 500]);
+        let client = Client::new(&server).with_retry_policy(retry_policy().with_max_retries(2));
+
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        match client.upload(&upload_url, &path) {
+            Err(Error::UnexpectedStatusCode(500)) => (),
+            other => panic!("Expected UnexpectedStatusCode(500), got {:?}", other),
+        }
+
+        assert_eq!(server.count("Patch"), 3);
+        assert!(server.data().is_empty());
+    }
+}
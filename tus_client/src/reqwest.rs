@@ -9,7 +9,15 @@ impl HttpHandler for reqwest::Client {
     fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error> {
         let mut headers = HeaderMap::new();
         for (key, value) in req.headers {
-            headers.insert(HeaderName::from_str(&key).unwrap(), value.parse().unwrap());
+            let name = match HeaderName::from_str(&key) {
+                Ok(name) => name,
+                Err(_) => return Err(Error::InvalidHeader(key)),
+            };
+            let value = match value.parse() {
+                Ok(value) => value,
+                Err(_) => return Err(Error::InvalidHeader(key)),
+            };
+            headers.insert(name, value);
         }
 
         let mut builder = match req.method {
@@ -0,0 +1,60 @@
+use crate::http::{AsyncHttpHandler, HttpMethod, HttpRequest, HttpResponse};
+use crate::Error;
+use async_trait::async_trait;
+use reqwest_async::header::{HeaderMap, HeaderName};
+use reqwest_async::Method;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[async_trait]
+impl AsyncHttpHandler for reqwest_async::Client {
+    async fn handle_request(&self, req: HttpRequest<'_>) -> Result<HttpResponse, Error> {
+        let mut headers = HeaderMap::new();
+        for (key, value) in req.headers {
+            let name = match HeaderName::from_str(&key) {
+                Ok(name) => name,
+                Err(_) => return Err(Error::InvalidHeader(key)),
+            };
+            let value = match value.parse() {
+                Ok(value) => value,
+                Err(_) => return Err(Error::InvalidHeader(key)),
+            };
+            headers.insert(name, value);
+        }
+
+        let mut builder = match req.method {
+            HttpMethod::Head => self.head(&req.url),
+            HttpMethod::Patch => self.patch(&req.url),
+            HttpMethod::Options => self.request(Method::OPTIONS, &req.url),
+            HttpMethod::Post => self.post(&req.url),
+            HttpMethod::Delete => self.delete(&req.url),
+        }
+        .headers(headers);
+
+        if let Some(body) = req.body {
+            builder = builder.body(Vec::from(body));
+        }
+
+        if let Some(timeout) = req.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        let response = match builder.send().await {
+            Ok(resp) => resp,
+            Err(err) => return Err(Error::HttpHandlerError(err.to_string())),
+        };
+
+        let mut headers = HashMap::new();
+        for (key, value) in response.headers() {
+            headers.insert(
+                key.to_string(),
+                value.to_str().map(String::from).unwrap_or_default(),
+            );
+        }
+
+        Ok(HttpResponse {
+            status_code: response.status().as_u16() as usize,
+            headers,
+        })
+    }
+}
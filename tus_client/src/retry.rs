@@ -0,0 +1,143 @@
+use crate::Error;
+use futures_timer::Delay;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How uploads recover from transient failures: network errors, `5xx` responses and `409 Conflict` from an offset mismatch.
+/// After a failed chunk the client waits with exponential backoff and jitter, re-syncs the offset with a `HEAD` request and resumes from there.
+/// It gives up after `max_retries` consecutive failures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    max_retries: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    request_timeout: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Retry up to 5 times, waiting 1 second at first and at most 30 seconds, with a 60 second timeout per request.
+    pub fn new() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            request_timeout: Some(Duration::from_secs(60)),
+        }
+    }
+
+    /// Never retry and don't limit the duration of requests. This is what clients use unless given a policy.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            initial_backoff: Duration::from_secs(0),
+            max_backoff: Duration::from_secs(0),
+            request_timeout: None,
+        }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Wait `initial_backoff` before the first retry, doubling the wait for every further retry up to `max_backoff`.
+    pub fn with_backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Fail requests taking longer than `request_timeout`. Handlers which can't limit single requests ignore it.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    pub(crate) fn max_retries(&self) -> usize {
+        self.max_retries
+    }
+
+    pub(crate) fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    /// The time to wait before the given retry, counting from 1. Half of it is random, so clients failing together don't retry together.
+    pub(crate) fn backoff(&self, retry: usize) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31) as u32;
+        let backoff = self
+            .initial_backoff
+            .checked_mul(2u32.pow(exponent))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+
+        let half = backoff / 2;
+        let jitter = RandomState::new().build_hasher().finish() % (half.as_millis() as u64 + 1);
+        half + Duration::from_millis(jitter)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new()
+    }
+}
+
+/// Waits without blocking the executor. The timer runs on a thread shared by all waits, so it works with any async runtime.
+pub(crate) async fn sleep(duration: Duration) {
+    Delay::new(duration).await;
+}
+
+/// Whether the request may succeed if it is made again.
+pub(crate) fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::HttpHandlerError(_) | Error::WrongUploadOffsetError => true,
+        Error::UnexpectedStatusCode(status_code) => *status_code >= 500,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicts_and_server_errors_are_transient() {
+        assert!(is_transient(&Error::HttpHandlerError("reset".to_owned())));
+        assert!(is_transient(&Error::WrongUploadOffsetError));
+        assert!(is_transient(&Error::UnexpectedStatusCode(500)));
+        assert!(is_transient(&Error::UnexpectedStatusCode(503)));
+
+        assert!(!is_transient(&Error::UnexpectedStatusCode(400)));
+        assert!(!is_transient(&Error::UnexpectedStatusCode(404)));
+        assert!(!is_transient(&Error::NotFoundError));
+        assert!(!is_transient(&Error::ChecksumMismatch));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum_with_jitter() {
+        let policy = RetryPolicy::new()
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(1000));
+        let expected = [100, 200, 400, 800, 1000, 1000];
+
+        for (retry, cap) in (1..).zip(expected.iter()) {
+            let cap = Duration::from_millis(*cap);
+            for _ in 0..20 {
+                let backoff = policy.backoff(retry);
+                assert!(backoff >= cap / 2 && backoff <= cap, "{:?}", backoff);
+            }
+        }
+
+        let backoff = policy.backoff(usize::MAX);
+        assert!(backoff >= Duration::from_millis(500) && backoff <= Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn no_policy_never_waits() {
+        let policy = RetryPolicy::none();
+
+        assert_eq!(policy.max_retries(), 0);
+        assert_eq!(policy.request_timeout(), None);
+        assert_eq!(policy.backoff(1), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,1365 @@
+use crate::http::{
+    default_headers, AsyncHttpHandler, Headers, HttpHandler, HttpMethod, HttpRequest, HttpResponse,
+};
+use crate::{encode_metadata, headers, parse_metadata, ChecksumAlgorithm, Error, HeaderMap};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TUS_VERSION: &str = "1.0.0";
+
+const SUPPORTED_EXTENSIONS: &str = "creation,creation-with-upload,creation-defer-length,termination,checksum,concatenation,expiration";
+
+const SUPPORTED_CHECKSUM_ALGORITHMS: &str = "sha1,sha256,md5,blake3";
+
+/// A *tus* 1.0 server storing uploads on the local filesystem, supporting the creation, termination, checksum, concatenation and expiration extensions.
+///
+/// `TusServer` implements `HttpHandler` and `AsyncHttpHandler`, so a `Client` or `AsyncClient` can use it directly, without a network connection. `serve` makes it available over HTTP, where finished uploads can also be downloaded.
+pub struct TusServer {
+    storage_dir: PathBuf,
+    base_url: String,
+    max_size: Option<usize>,
+    expiration: Option<Duration>,
+    on_finish: Option<Box<OnFinish>>,
+    files: Option<Box<ResolveFile>>,
+    authorize: Option<Box<Authorize>>,
+    lock: Mutex<()>,
+    next_id: AtomicU64,
+}
+
+/// Called with each finished upload, returning metadata to add to it.
+type OnFinish = dyn Fn(&FinishedUpload) -> HashMap<String, String> + Send + Sync;
+
+/// Resolves the path of a `GET` request to the file to send.
+type ResolveFile = dyn Fn(&str) -> Option<PathBuf> + Send + Sync;
+
+/// Decides from its headers whether an HTTP request may be handled.
+type Authorize = dyn Fn(&Headers) -> bool + Send + Sync;
+
+/// An upload which has received all of its data, as passed to the callback of `TusServer::with_on_finish`.
+#[derive(Debug, Clone)]
+pub struct FinishedUpload {
+    pub upload_url: String,
+    /// Where the data of the upload is stored.
+    pub path: PathBuf,
+    pub metadata: HashMap<String, String>,
+}
+
+/// The data sent with a request.
+enum Body<'a> {
+    Memory(&'a [u8]),
+    /// Written to a file as it arrived, so large chunks aren't held in memory.
+    Spooled {
+        path: PathBuf,
+        len: usize,
+    },
+}
+
+impl Body<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Body::Memory(data) => data.len(),
+            Body::Spooled { len, .. } => *len,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn checksum(&self, algorithm: ChecksumAlgorithm) -> Result<String, Error> {
+        match self {
+            Body::Memory(data) => Ok(algorithm.header_value(data)),
+            Body::Spooled { path, .. } => {
+                Ok(algorithm.header_value_of_reader(&mut File::open(path)?)?)
+            }
+        }
+    }
+
+    fn write_to(&self, file: &mut File) -> Result<(), Error> {
+        match self {
+            Body::Memory(data) => file.write_all(data)?,
+            Body::Spooled { path, .. } => {
+                io::copy(&mut File::open(path)?, file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the server knows about an upload, besides its data.
+#[derive(Debug, Default)]
+struct UploadRecord {
+    length: Option<usize>,
+    metadata: Option<String>,
+    concat: Option<String>,
+    expires: Option<SystemTime>,
+}
+
+impl TusServer {
+    /// Store uploads in `storage_dir`, accepting new files at `base_url`, e.g. `http://localhost:1080/files`. Upload URLs are `base_url` followed by the ID of the upload.
+    pub fn new(
+        storage_dir: impl Into<PathBuf>,
+        base_url: impl Into<String>,
+    ) -> Result<Self, Error> {
+        let storage_dir = storage_dir.into();
+        fs::create_dir_all(&storage_dir)?;
+
+        Ok(TusServer {
+            storage_dir,
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+            max_size: None,
+            expiration: None,
+            on_finish: None,
+            files: None,
+            authorize: None,
+            lock: Mutex::new(()),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Reject files larger than `max_size` bytes.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Discard unfinished uploads which haven't received data for `expiration`.
+    pub fn with_expiration(mut self, expiration: Duration) -> Self {
+        self.expiration = Some(expiration);
+        self
+    }
+
+    /// Call `on_finish` when an upload receives its last byte, or when a final upload is concatenated from its partial uploads.
+    /// The entries it returns are added to the metadata of the upload, which clients read with a `HEAD` request.
+    pub fn with_on_finish(
+        mut self,
+        on_finish: impl Fn(&FinishedUpload) -> HashMap<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.on_finish = Some(Box::new(on_finish));
+        self
+    }
+
+    /// Serve `GET` requests for paths which aren't upload URLs from the file `resolve` returns for the path, if any.
+    /// The data of finished uploads is always served at their upload URLs.
+    pub fn with_files(
+        mut self,
+        resolve: impl Fn(&str) -> Option<PathBuf> + Send + Sync + 'static,
+    ) -> Self {
+        self.files = Some(Box::new(resolve));
+        self
+    }
+
+    /// Answer HTTP requests other than `OPTIONS` with `401 Unauthorized` unless `authorize` accepts their headers, e.g. a bearer token.
+    /// Requests executed directly through `handle` or the handler traits are always handled.
+    pub fn with_authorization(
+        mut self,
+        authorize: impl Fn(&Headers) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.authorize = Some(Box::new(authorize));
+        self
+    }
+
+    /// The path of the data of a finished upload, given its upload URL.
+    pub fn file_path(&self, upload_url: &str) -> Option<PathBuf> {
+        self.upload_id(upload_url).map(|id| self.data_path(&id))
+    }
+
+    /// Execute a request, as `HttpHandler` and `AsyncHttpHandler` do.
+    pub fn handle(&self, req: HttpRequest) -> HttpResponse {
+        let body = Body::Memory(req.body.unwrap_or_default());
+        self.handle_body(&req, body)
+    }
+
+    /// Execute a request whose data is `body` rather than the body of `req`.
+    fn handle_body(&self, req: &HttpRequest, body: Body) -> HttpResponse {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let method = match req.headers.get_by_key(headers::X_HTTP_METHOD_OVERRIDE) {
+            Some(method) => method.to_lowercase(),
+            None => req.method.to_string().to_lowercase(),
+        };
+
+        if method != "options" {
+            match req.headers.get_by_key(headers::TUS_RESUMABLE) {
+                Some(version) if version == TUS_VERSION => (),
+                _ => {
+                    let mut response = response(412);
+                    response
+                        .headers
+                        .insert(headers::TUS_VERSION.to_owned(), TUS_VERSION.to_owned());
+                    return response;
+                }
+            }
+        }
+
+        let result = match method.as_str() {
+            "options" => Ok(self.options()),
+            "post" => self.create(req, body),
+            "head" => self.head(&req.url),
+            "patch" => self.patch(req, body),
+            "delete" => self.delete(&req.url),
+            _ => Ok(response(405)),
+        };
+
+        result.unwrap_or_else(|_| response(500))
+    }
+
+    fn options(&self) -> HttpResponse {
+        let mut response = response(204);
+        response
+            .headers
+            .insert(headers::TUS_VERSION.to_owned(), TUS_VERSION.to_owned());
+        response.headers.insert(
+            headers::TUS_EXTENSION.to_owned(),
+            SUPPORTED_EXTENSIONS.to_owned(),
+        );
+        response.headers.insert(
+            headers::TUS_CHECKSUM_ALGORITHM.to_owned(),
+            SUPPORTED_CHECKSUM_ALGORITHMS.to_owned(),
+        );
+        if let Some(max_size) = self.max_size {
+            response
+                .headers
+                .insert(headers::TUS_MAX_SIZE.to_owned(), max_size.to_string());
+        }
+        response
+    }
+
+    fn create(&self, req: &HttpRequest, body: Body) -> Result<HttpResponse, Error> {
+        let concat = req.headers.get_by_key(headers::UPLOAD_CONCAT);
+        if let Some(final_concat) = concat.and_then(|concat| concat.strip_prefix("final;")) {
+            return self.create_final(
+                final_concat,
+                req.headers.get_by_key(headers::UPLOAD_METADATA),
+            );
+        }
+
+        let length = match (
+            req.headers.get_by_key(headers::UPLOAD_LENGTH),
+            req.headers.get_by_key(headers::UPLOAD_DEFER_LENGTH),
+        ) {
+            (Some(length), _) => match length.parse::<usize>() {
+                Ok(length) => Some(length),
+                Err(_) => return Ok(response(400)),
+            },
+            (None, Some(defer)) if defer == "1" => None,
+            _ => return Ok(response(400)),
+        };
+
+        if let (Some(length), Some(max_size)) = (length, self.max_size) {
+            if length > max_size {
+                return Ok(response(413));
+            }
+        }
+
+        let id = self.new_id();
+        let record = UploadRecord {
+            length,
+            metadata: req.headers.get_by_key(headers::UPLOAD_METADATA).cloned(),
+            concat: concat
+                .filter(|concat| concat.as_str() == "partial")
+                .cloned(),
+            expires: self
+                .expiration
+                .map(|expiration| SystemTime::now() + expiration),
+        };
+        File::create(self.data_path(&id))?;
+        self.write_record(&id, &record)?;
+
+        let mut response = response(201);
+        response.headers.insert(
+            headers::LOCATION.to_owned(),
+            format!("{}/{}", self.base_url, id),
+        );
+        if let Some(expires) = record.expires {
+            response.headers.insert(
+                headers::UPLOAD_EXPIRES.to_owned(),
+                httpdate::fmt_http_date(expires),
+            );
+        }
+
+        // creation-with-upload
+        if !body.is_empty() {
+            let patch = self.append(&id, record, 0, body, &req.headers)?;
+            if patch.status_code != 204 {
+                self.remove(&id)?;
+                return Ok(patch);
+            }
+            response.headers.extend(patch.headers);
+        }
+
+        Ok(response)
+    }
+
+    fn create_final(
+        &self,
+        partial_urls: &str,
+        metadata: Option<&String>,
+    ) -> Result<HttpResponse, Error> {
+        let mut partial_ids = Vec::new();
+        for partial_url in partial_urls.split_whitespace() {
+            let id = match self.upload_id(partial_url) {
+                Some(id) => id,
+                None => return Ok(response(400)),
+            };
+            let record = match self.read_record(&id)? {
+                Some(record) if record.concat.as_deref() == Some("partial") => record,
+                _ => return Ok(response(400)),
+            };
+            if record.length != Some(self.offset(&id)?) {
+                return Ok(response(400));
+            }
+            partial_ids.push(id);
+        }
+
+        let id = self.new_id();
+        let mut output = File::create(self.data_path(&id))?;
+        for partial_id in &partial_ids {
+            io::copy(&mut File::open(self.data_path(partial_id))?, &mut output)?;
+        }
+        output.flush()?;
+
+        let length = self.offset(&id)?;
+        if self.max_size.is_some_and(|max_size| length > max_size) {
+            self.remove(&id)?;
+            return Ok(response(413));
+        }
+
+        let mut record = UploadRecord {
+            length: Some(length),
+            metadata: metadata.cloned(),
+            concat: Some(format!("final;{}", partial_urls)),
+            expires: None,
+        };
+        self.finish(&id, &mut record);
+        self.write_record(&id, &record)?;
+
+        let mut response = response(201);
+        response.headers.insert(
+            headers::LOCATION.to_owned(),
+            format!("{}/{}", self.base_url, id),
+        );
+        Ok(response)
+    }
+
+    fn head(&self, url: &str) -> Result<HttpResponse, Error> {
+        let id = match self.upload_id(url) {
+            Some(id) => id,
+            None => return Ok(response(404)),
+        };
+        let record = match self.live_record(&id)? {
+            Some(record) => record,
+            None => return Ok(response(404)),
+        };
+
+        let mut response = response(200);
+        response
+            .headers
+            .insert("cache-control".to_owned(), "no-store".to_owned());
+        response.headers.insert(
+            headers::UPLOAD_OFFSET.to_owned(),
+            self.offset(&id)?.to_string(),
+        );
+        match record.length {
+            Some(length) => response
+                .headers
+                .insert(headers::UPLOAD_LENGTH.to_owned(), length.to_string()),
+            None => response
+                .headers
+                .insert(headers::UPLOAD_DEFER_LENGTH.to_owned(), "1".to_owned()),
+        };
+        if let Some(metadata) = record.metadata {
+            response
+                .headers
+                .insert(headers::UPLOAD_METADATA.to_owned(), metadata);
+        }
+        if let Some(concat) = record.concat {
+            response
+                .headers
+                .insert(headers::UPLOAD_CONCAT.to_owned(), concat);
+        }
+        if let Some(expires) = record.expires {
+            response.headers.insert(
+                headers::UPLOAD_EXPIRES.to_owned(),
+                httpdate::fmt_http_date(expires),
+            );
+        }
+        Ok(response)
+    }
+
+    fn patch(&self, req: &HttpRequest, body: Body) -> Result<HttpResponse, Error> {
+        if req
+            .headers
+            .get_by_key(headers::CONTENT_TYPE)
+            .map(String::as_str)
+            != Some("application/offset+octet-stream")
+        {
+            return Ok(response(415));
+        }
+
+        let id = match self.upload_id(&req.url) {
+            Some(id) => id,
+            None => return Ok(response(404)),
+        };
+        let record = match self.live_record(&id)? {
+            Some(record) => record,
+            None => return Ok(response(404)),
+        };
+        let offset = match req
+            .headers
+            .get_by_key(headers::UPLOAD_OFFSET)
+            .map(|offset| offset.parse::<usize>())
+        {
+            Some(Ok(offset)) => offset,
+            _ => return Ok(response(400)),
+        };
+
+        self.append(&id, record, offset, body, &req.headers)
+    }
+
+    /// Appends a chunk at `offset`, checking the offset, the declared length and the checksum of the chunk.
+    fn append(
+        &self,
+        id: &str,
+        mut record: UploadRecord,
+        offset: usize,
+        chunk: Body,
+        request_headers: &Headers,
+    ) -> Result<HttpResponse, Error> {
+        if record
+            .concat
+            .as_deref()
+            .is_some_and(|concat| concat.starts_with("final"))
+        {
+            return Ok(response(403));
+        }
+
+        if offset != self.offset(id)? {
+            return Ok(response(409));
+        }
+
+        if let Some(length) = request_headers.get_by_key(headers::UPLOAD_LENGTH) {
+            let length = match length.parse::<usize>() {
+                Ok(length) => length,
+                Err(_) => return Ok(response(400)),
+            };
+            if record.length.is_some_and(|declared| declared != length) {
+                return Ok(response(400));
+            }
+            if self.max_size.is_some_and(|max_size| length > max_size) {
+                return Ok(response(413));
+            }
+            record.length = Some(length);
+        }
+
+        if record
+            .length
+            .is_some_and(|length| offset + chunk.len() > length)
+        {
+            return Ok(response(400));
+        }
+
+        if let Some(checksum) = request_headers.get_by_key(headers::UPLOAD_CHECKSUM) {
+            let algorithm = match checksum
+                .split_whitespace()
+                .next()
+                .and_then(|name| name.parse::<ChecksumAlgorithm>().ok())
+            {
+                Some(algorithm) => algorithm,
+                None => return Ok(response(400)),
+            };
+            if &chunk.checksum(algorithm)? != checksum {
+                return Ok(response(460));
+            }
+        }
+
+        chunk.write_to(&mut OpenOptions::new().append(true).open(self.data_path(id))?)?;
+
+        let new_offset = offset + chunk.len();
+        let finished = record.length == Some(new_offset);
+        record.expires = match (finished, self.expiration) {
+            (false, Some(expiration)) => Some(SystemTime::now() + expiration),
+            _ => None,
+        };
+        // Partial uploads only finish once they are concatenated
+        if finished && record.concat.as_deref() != Some("partial") {
+            self.finish(id, &mut record);
+        }
+        self.write_record(id, &record)?;
+
+        let mut response = response(204);
+        response
+            .headers
+            .insert(headers::UPLOAD_OFFSET.to_owned(), new_offset.to_string());
+        if let Some(expires) = record.expires {
+            response.headers.insert(
+                headers::UPLOAD_EXPIRES.to_owned(),
+                httpdate::fmt_http_date(expires),
+            );
+        }
+        Ok(response)
+    }
+
+    /// Passes a finished upload to the `on_finish` callback and adds the metadata it returns to the record.
+    fn finish(&self, id: &str, record: &mut UploadRecord) {
+        let on_finish = match &self.on_finish {
+            Some(on_finish) => on_finish,
+            None => return,
+        };
+
+        let metadata = record
+            .metadata
+            .as_deref()
+            .and_then(|metadata| parse_metadata(metadata).ok())
+            .unwrap_or_default();
+        let added = on_finish(&FinishedUpload {
+            upload_url: format!("{}/{}", self.base_url, id),
+            path: self.data_path(id),
+            metadata,
+        });
+
+        if !added.is_empty() {
+            let added = encode_metadata(&added);
+            record.metadata = Some(match record.metadata.take() {
+                Some(metadata) if !metadata.is_empty() => format!("{},{}", metadata, added),
+                _ => added,
+            });
+        }
+    }
+
+    fn delete(&self, url: &str) -> Result<HttpResponse, Error> {
+        match self.upload_id(url) {
+            Some(id) if self.live_record(&id)?.is_some() => {
+                self.remove(&id)?;
+                Ok(response(204))
+            }
+            _ => Ok(response(404)),
+        }
+    }
+
+    /// The record of an upload, removing the upload if it has expired.
+    fn live_record(&self, id: &str) -> Result<Option<UploadRecord>, Error> {
+        match self.read_record(id)? {
+            Some(record)
+                if record
+                    .expires
+                    .is_some_and(|expires| expires <= SystemTime::now()) =>
+            {
+                self.remove(id)?;
+                Ok(None)
+            }
+            record => Ok(record),
+        }
+    }
+
+    fn offset(&self, id: &str) -> Result<usize, Error> {
+        Ok(fs::metadata(self.data_path(id))?.len() as usize)
+    }
+
+    /// The ID of the upload at `url`, which may be a full URL or only its path. IDs are hex digits, so they can't point outside the storage directory.
+    fn upload_id(&self, url: &str) -> Option<String> {
+        let id = url.trim_end_matches('/').rsplit('/').next()?;
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(id.to_owned())
+    }
+
+    fn new_id(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&nanos.to_le_bytes());
+        hasher.update(&self.next_id.fetch_add(1, Ordering::SeqCst).to_le_bytes());
+        hasher.finalize().to_hex()[..32].to_owned()
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.storage_dir.join(id)
+    }
+
+    fn record_path(&self, id: &str) -> PathBuf {
+        self.storage_dir.join(format!("{}.info", id))
+    }
+
+    fn read_record(&self, id: &str) -> Result<Option<UploadRecord>, Error> {
+        let contents = match fs::read_to_string(self.record_path(id)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut record = UploadRecord::default();
+        for line in contents.lines() {
+            if let Some((key, value)) = line.split_once(": ") {
+                match key {
+                    "length" => record.length = value.parse().ok(),
+                    "metadata" => record.metadata = Some(value.to_owned()),
+                    "concat" => record.concat = Some(value.to_owned()),
+                    "expires" => {
+                        record.expires = value
+                            .parse()
+                            .ok()
+                            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(Some(record))
+    }
+
+    fn write_record(&self, id: &str, record: &UploadRecord) -> Result<(), Error> {
+        let mut contents = String::new();
+        if let Some(length) = record.length {
+            contents.push_str(&format!("length: {}\n", length));
+        }
+        if let Some(metadata) = &record.metadata {
+            contents.push_str(&format!("metadata: {}\n", metadata));
+        }
+        if let Some(concat) = &record.concat {
+            contents.push_str(&format!("concat: {}\n", concat));
+        }
+        if let Some(expires) = record
+            .expires
+            .and_then(|expires| expires.duration_since(UNIX_EPOCH).ok())
+        {
+            contents.push_str(&format!("expires: {}\n", expires.as_secs()));
+        }
+        fs::write(self.record_path(id), contents)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), Error> {
+        for path in [self.data_path(id), self.record_path(id)] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve the *tus* endpoint over HTTP on `addr` until the future is dropped. Requests to any path are handled, with upload URLs formed from `base_url`.
+    pub async fn serve(self: Arc<Self>, addr: SocketAddr) -> Result<(), Error> {
+        use hyper::service::{make_service_fn, service_fn};
+
+        let make_service = make_service_fn(move |_| {
+            let server = Arc::clone(&self);
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    let server = Arc::clone(&server);
+                    async move { Ok::<_, hyper::Error>(server.handle_hyper(req).await) }
+                }))
+            }
+        });
+
+        hyper::Server::try_bind(&addr)
+            .map_err(|e| Error::HttpHandlerError(e.to_string()))?
+            .serve(make_service)
+            .await
+            .map_err(|e| Error::HttpHandlerError(e.to_string()))
+    }
+
+    async fn handle_hyper(
+        self: Arc<Self>,
+        req: hyper::Request<hyper::Body>,
+    ) -> hyper::Response<hyper::Body> {
+        let (parts, body) = req.into_parts();
+        let headers: Headers = parts
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((name.as_str().to_owned(), value.to_str().ok()?.to_owned()))
+            })
+            .collect();
+
+        if parts.method != hyper::Method::OPTIONS
+            && !self
+                .authorize
+                .as_ref()
+                .is_none_or(|authorize| authorize(&headers))
+        {
+            return hyper_response(response(401));
+        }
+
+        let method = match parts.method.as_str() {
+            "GET" => {
+                let range = parts
+                    .headers
+                    .get(hyper::header::RANGE)
+                    .and_then(|range| range.to_str().ok())
+                    .map(str::to_owned);
+                return self.download(parts.uri.path().to_owned(), range).await;
+            }
+            "HEAD" => HttpMethod::Head,
+            "PATCH" => HttpMethod::Patch,
+            "OPTIONS" => HttpMethod::Options,
+            "POST" => HttpMethod::Post,
+            "DELETE" => HttpMethod::Delete,
+            _ => return hyper_response(response(405)),
+        };
+        let url = parts.uri.path().to_owned();
+
+        // Bodies go to disk as they arrive, so a large chunk is never held in memory
+        let spool_path = self.storage_dir.join(format!("{}.body", self.new_id()));
+        let len = match self.spool(body, &spool_path).await {
+            Ok(Some(len)) => len,
+            Ok(None) => {
+                let _ = tokio::fs::remove_file(&spool_path).await;
+                return hyper_response(response(413));
+            }
+            Err(_) => {
+                let _ = tokio::fs::remove_file(&spool_path).await;
+                return hyper_response(response(400));
+            }
+        };
+
+        // Uploads are stored on disk, so keep the file IO off the async runtime's threads
+        let response = tokio::task::spawn_blocking(move || {
+            let req = HttpRequest {
+                method,
+                headers,
+                url,
+                body: None,
+                timeout: None,
+            };
+            let response = self.handle_body(
+                &req,
+                Body::Spooled {
+                    path: spool_path.clone(),
+                    len,
+                },
+            );
+            let _ = fs::remove_file(&spool_path);
+            response
+        })
+        .await
+        .unwrap_or_else(|_| response(500));
+
+        hyper_response(response)
+    }
+
+    /// Sends the file a `GET` request for `path` asks for, or the part of it in `range`, the value of a `Range` header.
+    async fn download(
+        self: Arc<Self>,
+        path: String,
+        range: Option<String>,
+    ) -> hyper::Response<hyper::Body> {
+        use hyper::body::Bytes;
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let server = Arc::clone(&self);
+        let file_path = match tokio::task::spawn_blocking(move || server.download_path(&path)).await
+        {
+            Ok(Some(file_path)) => file_path,
+            _ => return hyper_response(response(404)),
+        };
+        let mut file = match tokio::fs::File::open(&file_path).await {
+            Ok(file) => file,
+            Err(_) => return hyper_response(response(404)),
+        };
+        let len = match file.metadata().await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return hyper_response(response(500)),
+        };
+
+        let (status_code, start, end) = match range.map(|range| byte_range(&range, len)) {
+            None | Some(Ok(None)) => (200, 0, len),
+            Some(Ok(Some((start, end)))) => (206, start, end),
+            Some(Err(())) => {
+                let mut response = response(416);
+                response
+                    .headers
+                    .insert("content-range".to_owned(), format!("bytes */{}", len));
+                return hyper_response(response);
+            }
+        };
+        if file.seek(io::SeekFrom::Start(start)).await.is_err() {
+            return hyper_response(response(500));
+        }
+
+        // Send the file in chunks as the connection takes them, so it is never held in memory
+        let (mut sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 64 * 1024];
+            let mut remaining = end - start;
+            while remaining > 0 {
+                let max = buffer.len().min(remaining as usize);
+                let read = match file.read(&mut buffer[..max]).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => read,
+                };
+                if sender
+                    .send_data(Bytes::copy_from_slice(&buffer[..read]))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                remaining -= read as u64;
+            }
+            if remaining > 0 {
+                sender.abort();
+            }
+        });
+
+        let mut builder = hyper::Response::builder()
+            .status(status_code)
+            .header("accept-ranges", "bytes")
+            .header("content-length", end - start);
+        if status_code == 206 {
+            builder = builder.header(
+                "content-range",
+                format!("bytes {}-{}/{}", start, end - 1, len),
+            );
+        }
+        builder
+            .body(body)
+            .unwrap_or_else(|_| hyper::Response::new(hyper::Body::empty()))
+    }
+
+    /// The file to send for a `GET` request for `path`: one from the `with_files` callback, or the data of the finished upload at `path`.
+    fn download_path(&self, path: &str) -> Option<PathBuf> {
+        if let Some(file_path) = self.files.as_ref().and_then(|resolve| resolve(path)) {
+            return Some(file_path);
+        }
+
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let id = self.upload_id(path)?;
+        let record = self.live_record(&id).ok()??;
+        let finished =
+            record.concat.as_deref() != Some("partial") && record.length == self.offset(&id).ok();
+
+        if finished {
+            Some(self.data_path(&id))
+        } else {
+            None
+        }
+    }
+
+    /// Writes a request body to `path` as it arrives, returning its length, or `None` once it is larger than `max_size`.
+    async fn spool(&self, mut body: hyper::Body, path: &Path) -> Result<Option<usize>, Error> {
+        use hyper::body::HttpBody;
+        use tokio::io::AsyncWriteExt;
+
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut len = 0;
+        while let Some(data) = body.data().await {
+            let data = data.map_err(|e| Error::HttpHandlerError(e.to_string()))?;
+            len += data.len();
+            if self.max_size.is_some_and(|max_size| len > max_size) {
+                return Ok(None);
+            }
+            file.write_all(&data).await?;
+        }
+        file.flush().await?;
+
+        Ok(Some(len))
+    }
+}
+
+impl HttpHandler for TusServer {
+    fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error> {
+        Ok(self.handle(req))
+    }
+}
+
+#[async_trait]
+impl AsyncHttpHandler for TusServer {
+    async fn handle_request(&self, req: HttpRequest<'_>) -> Result<HttpResponse, Error> {
+        Ok(self.handle(req))
+    }
+}
+
+impl HttpHandler for Arc<TusServer> {
+    fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error> {
+        Ok(self.handle(req))
+    }
+}
+
+#[async_trait]
+impl AsyncHttpHandler for Arc<TusServer> {
+    async fn handle_request(&self, req: HttpRequest<'_>) -> Result<HttpResponse, Error> {
+        Ok(self.handle(req))
+    }
+}
+
+fn response(status_code: usize) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: default_headers(),
+    }
+}
+
+/// The byte range `[start, end)` of a file of `len` bytes which a `Range` header asks for.
+/// `Ok(None)` means the header should be ignored and the whole file sent, as it isn't a single byte range, and `Err` that no byte of the file is in the range.
+fn byte_range(range: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let (first, last) = match range
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.trim().split_once('-'))
+    {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+
+    let (start, end) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last.saturating_add(1).min(len)),
+        (Ok(first), Err(_)) if last.is_empty() => (first, len),
+        (Err(_), Ok(suffix)) if first.is_empty() => (len.saturating_sub(suffix), len),
+        _ => return Ok(None),
+    };
+
+    if start < end {
+        Ok(Some((start, end)))
+    } else {
+        Err(())
+    }
+}
+
+fn hyper_response(response: HttpResponse) -> hyper::Response<hyper::Body> {
+    let mut builder = hyper::Response::builder().status(response.status_code as u16);
+    for (name, value) in &response.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    builder
+        .body(hyper::Body::empty())
+        .unwrap_or_else(|_| hyper::Response::new(hyper::Body::empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsyncClient, Client, RetryPolicy, UploadOptions};
+    use std::sync::atomic::AtomicUsize;
+    use tempfile::TempDir;
+
+    const BASE_URL: &str = "http://localhost:1080/files";
+
+    fn source_file(dir: &TempDir, len: usize) -> (PathBuf, Vec<u8>) {
+        let data = (0..len).map(|i| (i * 31 % 251) as u8).collect::<Vec<_>>();
+        let path = dir.path().join("source");
+        fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    #[test]
+    fn uploads_round_trip_through_the_client() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 100_000);
+        let finished = Arc::new(AtomicUsize::new(0));
+        let on_finish = Arc::clone(&finished);
+        let server = Arc::new(
+            TusServer::new(dir.path().join("storage"), BASE_URL)
+                .unwrap()
+                .with_on_finish(move |upload| {
+                    on_finish.fetch_add(1, Ordering::SeqCst);
+                    HashMap::from([(
+                        "size".to_owned(),
+                        upload.path.metadata().unwrap().len().to_string(),
+                    )])
+                }),
+        );
+        let client = Client::new(Arc::clone(&server)).with_checksum(ChecksumAlgorithm::Sha256);
+
+        let mut metadata = HashMap::new();
+        metadata.insert("filename".to_owned(), "source".to_owned());
+        let upload_url = client
+            .create_with_metadata(BASE_URL, &path, metadata)
+            .unwrap();
+        assert!(upload_url.starts_with(BASE_URL));
+
+        client
+            .upload_with_chunk_size(&upload_url, &path, 30_000)
+            .unwrap();
+
+        assert_eq!(
+            fs::read(server.file_path(&upload_url).unwrap()).unwrap(),
+            data
+        );
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+
+        let info = client.get_info(&upload_url).unwrap();
+        assert_eq!(info.bytes_uploaded, data.len());
+        assert_eq!(info.total_size, Some(data.len()));
+        let metadata = info.metadata.unwrap();
+        assert_eq!(metadata["filename"], "source");
+        assert_eq!(metadata["size"], "100000");
+    }
+
+    #[test]
+    fn terminated_uploads_are_gone() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = source_file(&dir, 1000);
+        let server = Arc::new(TusServer::new(dir.path().join("storage"), BASE_URL).unwrap());
+        let client = Client::new(Arc::clone(&server));
+
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        client.delete(&upload_url).unwrap();
+
+        match client.get_info(&upload_url) {
+            Err(Error::NotFoundError) => (),
+            other => panic!("Expected NotFoundError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn files_over_the_max_size_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = source_file(&dir, 1000);
+        let server = Arc::new(
+            TusServer::new(dir.path().join("storage"), BASE_URL)
+                .unwrap()
+                .with_max_size(999),
+        );
+
+        match Client::new(server).create(BASE_URL, &path) {
+            Err(Error::FileTooLarge) => (),
+            other => panic!("Expected FileTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn concurrent_uploads_are_concatenated() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 100_000);
+        let server = Arc::new(TusServer::new(dir.path().join("storage"), BASE_URL).unwrap());
+        let client = AsyncClient::new(Arc::clone(&server));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let upload_url = runtime
+            .block_on(client.upload_concurrently(
+                BASE_URL,
+                &path,
+                HashMap::new(),
+                4,
+                &UploadOptions::new().with_chunk_size(10_000),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            fs::read(server.file_path(&upload_url).unwrap()).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(byte_range("bytes=0-0", 10), Ok(Some((0, 1))));
+        assert_eq!(byte_range("bytes=2-5", 10), Ok(Some((2, 6))));
+        assert_eq!(byte_range("bytes=4-", 10), Ok(Some((4, 10))));
+        assert_eq!(byte_range("bytes=-3", 10), Ok(Some((7, 10))));
+        assert_eq!(byte_range("bytes=5-100", 10), Ok(Some((5, 10))));
+
+        assert_eq!(byte_range("bytes=10-", 10), Err(()));
+        assert_eq!(byte_range("bytes=-0", 10), Err(()));
+
+        assert_eq!(byte_range("bytes=0-1,4-5", 10), Ok(None));
+        assert_eq!(byte_range("bytes=5-2", 10), Ok(None));
+        assert_eq!(byte_range("items=0-1", 10), Ok(None));
+    }
+
+    /// Sends a `GET` request for `path` to `addr`, returning the head of the response, lowercased, and its body.
+    async fn get(addr: SocketAddr, path: &str, range: Option<&str>) -> (String, Vec<u8>) {
+        let range = range
+            .map(|range| format!("Range: {}\r\n", range))
+            .unwrap_or_default();
+        send(addr, "GET", path, &range).await
+    }
+
+    /// Sends a request without a body to `addr`, with the header lines in `headers`, returning the head of the response, lowercased, and its body.
+    async fn send(addr: SocketAddr, method: &str, path: &str, headers: &str) -> (String, Vec<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = loop {
+            match tokio::net::TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+            method, path, headers
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+
+        (
+            String::from_utf8_lossy(&response[..split]).to_lowercase(),
+            response[split + 4..].to_vec(),
+        )
+    }
+
+    #[test]
+    fn finished_uploads_are_served_over_http() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 100_000);
+        let blob_path = path.clone();
+        let server = Arc::new(
+            TusServer::new(dir.path().join("storage"), BASE_URL)
+                .unwrap()
+                .with_files(move |path| Some(blob_path.clone()).filter(|_| path == "/blob")),
+        );
+        let client = Client::new(Arc::clone(&server));
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        let upload_path = upload_url
+            .trim_start_matches("http://localhost:1080")
+            .to_owned();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.spawn(Arc::clone(&server).serve(addr));
+
+        // Unfinished uploads aren't served
+        let (head, _) = runtime.block_on(get(addr, &upload_path, None));
+        assert!(head.starts_with("http/1.1 404"), "{}", head);
+
+        client.upload(&upload_url, &path).unwrap();
+
+        let (head, body) = runtime.block_on(get(addr, &upload_path, None));
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains("content-length: 100000"), "{}", head);
+        assert_eq!(body, data);
+
+        let (head, body) = runtime.block_on(get(addr, &upload_path, Some("bytes=10-19")));
+        assert!(head.starts_with("http/1.1 206"), "{}", head);
+        assert!(
+            head.contains("content-range: bytes 10-19/100000"),
+            "{}",
+            head
+        );
+        assert_eq!(body, &data[10..20]);
+
+        let (head, _) = runtime.block_on(get(addr, &upload_path, Some("bytes=100000-")));
+        assert!(head.starts_with("http/1.1 416"), "{}", head);
+        assert!(head.contains("content-range: bytes */100000"), "{}", head);
+
+        let (head, body) = runtime.block_on(get(addr, "/blob", Some("bytes=-5")));
+        assert!(head.starts_with("http/1.1 206"), "{}", head);
+        assert_eq!(body, &data[99_995..]);
+
+        let (head, _) = runtime.block_on(get(addr, "/files/0123abcd", None));
+        assert!(head.starts_with("http/1.1 404"), "{}", head);
+    }
+
+    #[test]
+    fn http_requests_need_authorization() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 1000);
+        let server = Arc::new(
+            TusServer::new(dir.path().join("storage"), BASE_URL)
+                .unwrap()
+                .with_authorization(|headers| {
+                    headers.get("authorization").map(String::as_str) == Some("Bearer secret")
+                }),
+        );
+        // Requests made directly aren't checked
+        let client = Client::new(Arc::clone(&server));
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        client.upload(&upload_url, &path).unwrap();
+        let upload_path = upload_url
+            .trim_start_matches("http://localhost:1080")
+            .to_owned();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.spawn(Arc::clone(&server).serve(addr));
+
+        let (head, _) = runtime.block_on(get(addr, &upload_path, None));
+        assert!(head.starts_with("http/1.1 401"), "{}", head);
+
+        let tus = "Tus-Resumable: 1.0.0\r\nUpload-Length: 10\r\n";
+        let (head, _) = runtime.block_on(send(addr, "POST", "/files", tus));
+        assert!(head.starts_with("http/1.1 401"), "{}", head);
+
+        let wrong_token = format!("{}Authorization: Bearer guess\r\n", tus);
+        let (head, _) = runtime.block_on(send(addr, "POST", "/files", &wrong_token));
+        assert!(head.starts_with("http/1.1 401"), "{}", head);
+
+        let token = format!("{}Authorization: Bearer secret\r\n", tus);
+        let (head, _) = runtime.block_on(send(addr, "POST", "/files", &token));
+        assert!(head.starts_with("http/1.1 201"), "{}", head);
+
+        let (head, body) = runtime.block_on(send(
+            addr,
+            "GET",
+            &upload_path,
+            "Authorization: Bearer secret\r\n",
+        ));
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert_eq!(body, data);
+
+        // Clients discover the server without credentials
+        let (head, _) = runtime.block_on(send(addr, "OPTIONS", "/files", ""));
+        assert!(head.starts_with("http/1.1 204"), "{}", head);
+    }
+
+    enum Fault {
+        /// Answer with the status code, without forwarding the request.
+        Status(usize),
+        /// Forward the request with an `Upload-Offset` past the end of the upload, so the server answers `409 Conflict`.
+        WrongOffset,
+        /// Forward the request, but answer `503 Service Unavailable`, as if the response was lost.
+        LostResponse,
+    }
+
+    /// Wraps a `TusServer`, injecting the next fault in `faults` into every `PATCH` request.
+    struct FlakyServer {
+        server: Arc<TusServer>,
+        faults: Mutex<Vec<Fault>>,
+    }
+
+    impl FlakyServer {
+        fn new(server: &Arc<TusServer>, mut faults: Vec<Fault>) -> Self {
+            faults.reverse();
+            FlakyServer {
+                server: Arc::clone(server),
+                faults: Mutex::new(faults),
+            }
+        }
+
+        fn handle(&self, mut req: HttpRequest) -> HttpResponse {
+            let fault = match req.method {
+                HttpMethod::Patch => self.faults.lock().unwrap().pop(),
+                _ => None,
+            };
+
+            match fault {
+                None => self.server.handle(req),
+                Some(Fault::Status(status_code)) => response(status_code),
+                Some(Fault::WrongOffset) => {
+                    let offset = req
+                        .headers
+                        .get_by_key(headers::UPLOAD_OFFSET)
+                        .and_then(|offset| offset.parse::<usize>().ok())
+                        .unwrap_or_default();
+                    req.headers
+                        .retain(|name, _| !name.eq_ignore_ascii_case(headers::UPLOAD_OFFSET));
+                    req.headers
+                        .insert(headers::UPLOAD_OFFSET.to_owned(), (offset + 1).to_string());
+                    self.server.handle(req)
+                }
+                Some(Fault::LostResponse) => {
+                    self.server.handle(req);
+                    response(503)
+                }
+            }
+        }
+    }
+
+    impl HttpHandler for &FlakyServer {
+        fn handle_request(&self, req: HttpRequest) -> Result<HttpResponse, Error> {
+            Ok(self.handle(req))
+        }
+    }
+
+    #[async_trait]
+    impl AsyncHttpHandler for &FlakyServer {
+        async fn handle_request(&self, req: HttpRequest<'_>) -> Result<HttpResponse, Error> {
+            Ok(self.handle(req))
+        }
+    }
+
+    fn faults() -> Vec<Fault> {
+        vec![
+            Fault::Status(503),
+            Fault::WrongOffset,
+            Fault::LostResponse,
+            Fault::Status(500),
+        ]
+    }
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy::new().with_backoff(Duration::from_millis(0), Duration::from_millis(0))
+    }
+
+    #[test]
+    fn uploads_survive_conflicts_and_server_errors() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 100_000);
+        let server = Arc::new(TusServer::new(dir.path().join("storage"), BASE_URL).unwrap());
+        let flaky_server = FlakyServer::new(&server, faults());
+        let client = Client::new(&flaky_server).with_retry_policy(retry_policy());
+
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        client
+            .upload_with_chunk_size(&upload_url, &path, 10_000)
+            .unwrap();
+
+        assert_eq!(
+            fs::read(server.file_path(&upload_url).unwrap()).unwrap(),
+            data
+        );
+        assert!(flaky_server.faults.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn async_uploads_survive_conflicts_and_server_errors() {
+        let dir = TempDir::new().unwrap();
+        let (path, data) = source_file(&dir, 100_000);
+        let server = Arc::new(TusServer::new(dir.path().join("storage"), BASE_URL).unwrap());
+        let flaky_server = FlakyServer::new(&server, faults());
+        let client = AsyncClient::new(&flaky_server).with_retry_policy(retry_policy());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let upload_url = runtime.block_on(client.create(BASE_URL, &path)).unwrap();
+        runtime
+            .block_on(client.upload_with_chunk_size(&upload_url, &path, 10_000))
+            .unwrap();
+
+        assert_eq!(
+            fs::read(server.file_path(&upload_url).unwrap()).unwrap(),
+            data
+        );
+        assert!(flaky_server.faults.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn uploads_fail_once_the_retries_are_exhausted() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = source_file(&dir, 1000);
+        let server = Arc::new(TusServer::new(dir.path().join("storage"), BASE_URL).unwrap());
+        let flaky_server = FlakyServer::new(&server, vec![Fault::Status(502), Fault::Status(503)]);
+        let client =
+            Client::new(&flaky_server).with_retry_policy(retry_policy().with_max_retries(1));
+
+        let upload_url = client.create(BASE_URL, &path).unwrap();
+        match client.upload(&upload_url, &path) {
+            Err(Error::UnexpectedStatusCode(503)) => (),
+            other => panic!("Expected UnexpectedStatusCode(503), got {:?}", other),
+        }
+        assert_eq!(client.get_info(&upload_url).unwrap().bytes_uploaded, 0);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{Error, DEFAULT_CHUNK_SIZE};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The state of an upload, passed to the progress callback of `UploadOptions`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// How many bytes the server has acknowledged, including bytes uploaded before the upload was resumed.
+    pub bytes_sent: usize,
+    /// The size of the upload, if known.
+    pub total: Option<usize>,
+    /// The average upload rate since the upload started, in bytes per second.
+    pub bytes_per_second: f64,
+}
+
+/// Cancels uploads using `UploadOptions` carrying a clone of this token. Uploads stop before sending their next chunk and return `Error::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancel all uploads using this token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Options for uploading a file.
+pub struct UploadOptions<'a> {
+    chunk_size: usize,
+    on_progress: Option<Box<dyn Fn(Progress) + Send + Sync + 'a>>,
+    cancellation_token: Option<CancellationToken>,
+}
+
+impl<'a> UploadOptions<'a> {
+    /// Upload in 5 MiB chunks, without progress reporting or cancellation.
+    pub fn new() -> Self {
+        UploadOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            on_progress: None,
+            cancellation_token: None,
+        }
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Call `on_progress` every time the server acknowledges a chunk.
+    pub fn with_progress(mut self, on_progress: impl Fn(Progress) + Send + Sync + 'a) -> Self {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// Check `cancellation_token` before sending each chunk.
+    pub fn with_cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = Some(cancellation_token);
+        self
+    }
+
+    pub(crate) fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Fails with `Error::Cancelled` once the cancellation token has been cancelled.
+    pub(crate) fn check_cancelled(&self) -> Result<(), Error> {
+        match &self.cancellation_token {
+            Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for UploadOptions<'_> {
+    fn default() -> Self {
+        UploadOptions::new()
+    }
+}
+
+/// Adds up the bytes acknowledged for an upload, which may be sent as several concurrent partial uploads, and reports them to the progress callback.
+pub(crate) struct ProgressTracker<'o, 'a> {
+    options: &'o UploadOptions<'a>,
+    total: Option<usize>,
+    started: Instant,
+    bytes_sent: AtomicUsize,
+    bytes_resumed: AtomicUsize,
+}
+
+impl<'o, 'a> ProgressTracker<'o, 'a> {
+    pub(crate) fn new(options: &'o UploadOptions<'a>, total: Option<usize>) -> Self {
+        ProgressTracker {
+            options,
+            total,
+            started: Instant::now(),
+            bytes_sent: AtomicUsize::new(0),
+            bytes_resumed: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn options(&self) -> &'o UploadOptions<'a> {
+        self.options
+    }
+
+    /// Count bytes the server already had when the upload was resumed.
+    pub(crate) fn resumed(&self, bytes: usize) {
+        self.bytes_resumed.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Count bytes acknowledged by the server and report the progress.
+    pub(crate) fn acknowledged(&self, bytes: usize) {
+        let bytes_sent = self.bytes_sent.fetch_add(bytes, Ordering::SeqCst) + bytes;
+
+        if let Some(on_progress) = &self.options.on_progress {
+            let elapsed = self.started.elapsed().as_secs_f64();
+            on_progress(Progress {
+                bytes_sent: self.bytes_resumed.load(Ordering::SeqCst) + bytes_sent,
+                total: self.total,
+                bytes_per_second: if elapsed > 0.0 {
+                    bytes_sent as f64 / elapsed
+                } else {
+                    0.0
+                },
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn progress_adds_up_resumed_and_acknowledged_bytes() {
+        let reports = Mutex::new(Vec::new());
+        let options = UploadOptions::new().with_progress(|progress| {
+            reports
+                .lock()
+                .unwrap()
+                .push((progress.bytes_sent, progress.total))
+        });
+
+        let tracker = ProgressTracker::new(&options, Some(100));
+        tracker.resumed(40);
+        tracker.acknowledged(25);
+        tracker.acknowledged(35);
+        drop(options);
+
+        assert_eq!(
+            reports.into_inner().unwrap(),
+            vec![(65, Some(100)), (100, Some(100))]
+        );
+    }
+
+    #[test]
+    fn cancelling_a_token_cancels_its_clones() {
+        let token = CancellationToken::new();
+        let options = UploadOptions::new().with_cancellation_token(token.clone());
+        assert!(options.check_cancelled().is_ok());
+
+        token.clone().cancel();
+
+        assert!(token.is_cancelled());
+        match options.check_cancelled() {
+            Err(Error::Cancelled) => (),
+            other => panic!("Expected Cancelled, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use crate::Error;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An upload created on the server, as remembered by an `UploadStore`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredUpload {
+    /// The upload URL of the file.
+    pub upload_url: String,
+    /// When the server discards the upload, if it sent an `Upload-Expires` header.
+    pub expires: Option<SystemTime>,
+}
+
+impl StoredUpload {
+    /// Whether the server has already discarded the upload.
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= SystemTime::now())
+    }
+}
+
+/// Remembers the uploads created for files, keyed by the fingerprint of the file, so an upload can be resumed after the process restarts.
+/// `FileUploadStore` is the default implementation.
+pub trait UploadStore: Send + Sync {
+    /// Get the upload stored for the fingerprint, if any.
+    fn get(&self, fingerprint: &str) -> Result<Option<StoredUpload>, Error>;
+
+    /// Store the upload created for the fingerprint, replacing any previous one.
+    fn set(&self, fingerprint: &str, upload: StoredUpload) -> Result<(), Error>;
+
+    /// Forget the upload stored for the fingerprint.
+    fn remove(&self, fingerprint: &str) -> Result<(), Error>;
+}
+
+/// Stores uploads in a text file, one line per upload. Expired uploads are dropped whenever the file is written.
+/// Clients used concurrently with the same file must share one instance, passing it by reference, as separate instances don't lock against each other.
+pub struct FileUploadStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileUploadStore {
+    /// Use the file at `path`, which is created when the first upload is stored.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileUploadStore {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read(&self) -> Result<HashMap<String, StoredUpload>, Error> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let uploads = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let fingerprint = fields.next()?;
+                let upload_url = fields.next()?;
+                let expires = fields
+                    .next()?
+                    .parse::<u64>()
+                    .ok()
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+
+                Some((
+                    fingerprint.to_owned(),
+                    StoredUpload {
+                        upload_url: upload_url.to_owned(),
+                        expires,
+                    },
+                ))
+            })
+            .collect();
+
+        Ok(uploads)
+    }
+
+    fn write(&self, uploads: &HashMap<String, StoredUpload>) -> Result<(), Error> {
+        let contents = uploads
+            .iter()
+            .filter(|(_, upload)| !upload.is_expired())
+            .map(|(fingerprint, upload)| {
+                let expires = upload
+                    .expires
+                    .and_then(|expires| expires.duration_since(UNIX_EPOCH).ok())
+                    .map(|expires| expires.as_secs().to_string())
+                    .unwrap_or_else(|| "-".to_owned());
+                format!("{}\t{}\t{}\n", fingerprint, upload.upload_url, expires)
+            })
+            .collect::<String>();
+
+        // Write a copy and swap it in, so an interrupted write doesn't lose the stored uploads
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl Default for FileUploadStore {
+    /// Use `.tus_uploads` in the working directory.
+    fn default() -> Self {
+        FileUploadStore::new(".tus_uploads")
+    }
+}
+
+impl UploadStore for FileUploadStore {
+    fn get(&self, fingerprint: &str) -> Result<Option<StoredUpload>, Error> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(self.read()?.remove(fingerprint))
+    }
+
+    fn set(&self, fingerprint: &str, upload: StoredUpload) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut uploads = self.read()?;
+        uploads.insert(fingerprint.to_owned(), upload);
+        self.write(&uploads)
+    }
+
+    fn remove(&self, fingerprint: &str) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut uploads = self.read()?;
+        if uploads.remove(fingerprint).is_some() {
+            self.write(&uploads)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: UploadStore + ?Sized> UploadStore for &S {
+    fn get(&self, fingerprint: &str) -> Result<Option<StoredUpload>, Error> {
+        (**self).get(fingerprint)
+    }
+
+    fn set(&self, fingerprint: &str, upload: StoredUpload) -> Result<(), Error> {
+        (**self).set(fingerprint, upload)
+    }
+
+    fn remove(&self, fingerprint: &str) -> Result<(), Error> {
+        (**self).remove(fingerprint)
+    }
+}
+
+/// Identifies the upload of a file to a *tus* endpoint, from the endpoint URL and the canonical path, size and modification time of the file.
+/// Changing the file gives it a new fingerprint, so a stale upload is never resumed.
+pub fn fingerprint(url: &str, path: &Path) -> Result<String, Error> {
+    let metadata = path.metadata()?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(url.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(path.canonicalize()?.to_string_lossy().as_bytes());
+    hasher.update(&[0]);
+    hasher.update(&metadata.len().to_le_bytes());
+    hasher.update(&modified.as_nanos().to_le_bytes());
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn upload(upload_url: &str, expires: Option<SystemTime>) -> StoredUpload {
+        StoredUpload {
+            upload_url: upload_url.to_owned(),
+            expires,
+        }
+    }
+
+    #[test]
+    fn stored_uploads_persist_between_instances() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("uploads");
+        let expires = UNIX_EPOCH + Duration::from_secs(4_000_000_000);
+
+        let store = FileUploadStore::new(&path);
+        store
+            .set("a", upload("http://localhost/files/a", Some(expires)))
+            .unwrap();
+        store
+            .set("b", upload("http://localhost/files/b", None))
+            .unwrap();
+
+        let store = FileUploadStore::new(&path);
+        assert_eq!(
+            store.get("a").unwrap(),
+            Some(upload("http://localhost/files/a", Some(expires)))
+        );
+        assert_eq!(
+            store.get("b").unwrap(),
+            Some(upload("http://localhost/files/b", None))
+        );
+
+        store.remove("a").unwrap();
+        assert_eq!(store.get("a").unwrap(), None);
+        assert!(store.get("b").unwrap().is_some());
+    }
+
+    #[test]
+    fn expired_uploads_are_dropped_when_the_store_is_written() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("uploads");
+        let expired = SystemTime::now() - Duration::from_secs(60);
+        assert!(upload("http://localhost/files/a", Some(expired)).is_expired());
+
+        let store = FileUploadStore::new(&path);
+        store
+            .set("a", upload("http://localhost/files/a", Some(expired)))
+            .unwrap();
+        store
+            .set("b", upload("http://localhost/files/b", None))
+            .unwrap();
+
+        assert_eq!(store.get("a").unwrap(), None);
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "b\thttp://localhost/files/b\t-\n");
+    }
+
+    #[test]
+    fn fingerprints_change_with_the_endpoint_and_the_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("video.mp4");
+        fs::write(&path, b"first").unwrap();
+
+        let first = fingerprint("http://localhost/files", &path).unwrap();
+        assert_eq!(first, fingerprint("http://localhost/files", &path).unwrap());
+        assert_ne!(
+            first,
+            fingerprint("http://example.com/files", &path).unwrap()
+        );
+
+        fs::write(&path, b"second").unwrap();
+        assert_ne!(first, fingerprint("http://localhost/files", &path).unwrap());
+    }
+}